@@ -0,0 +1,264 @@
+use std::fmt::Display;
+
+use anyhow::{Result, bail};
+
+use crate::utils::*;
+
+// On disk a POSIX ACL is the same as the `system.posix_acl_*` xattr the kernel hands out
+//
+//  struct posix_acl_xattr_header {
+//      __le32 a_version;
+//  };
+//
+//  struct posix_acl_xattr_entry {
+//      __le16 e_tag;
+//      __le16 e_perm;
+//      __le32 e_id;
+//  };
+const POSIX_ACL_XATTR_VERSION: u32 = 2;
+const POSIX_ACL_HEADER_SIZE: usize = 4;
+const POSIX_ACL_ENTRY_SIZE: usize = 8;
+
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+pub const ACL_READ: u16 = 0x04;
+pub const ACL_WRITE: u16 = 0x02;
+pub const ACL_EXECUTE: u16 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclTag {
+    UserObj,
+    User(u32),
+    GroupObj,
+    Group(u32),
+    Mask,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AclEntry {
+    pub tag: AclTag,
+    pub perm: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct Acl {
+    /// Whether this came from `system.posix_acl_default`
+    pub default: bool,
+    pub entries: Vec<AclEntry>,
+}
+
+fn perm_str(perm: u16) -> String {
+    let mut s = String::with_capacity(3);
+    s.push(if perm & ACL_READ != 0 { 'r' } else { '-' });
+    s.push(if perm & ACL_WRITE != 0 { 'w' } else { '-' });
+    s.push(if perm & ACL_EXECUTE != 0 { 'x' } else { '-' });
+    s
+}
+
+impl Acl {
+    #[fn_error_context::context("Parsing POSIX ACL")]
+    pub fn parse(mut data: &[u8], default: bool) -> Result<Self> {
+        if data.len() < POSIX_ACL_HEADER_SIZE {
+            bail!("ACL of {} bytes is too short for the header", data.len());
+        }
+
+        let version = u32_le(data, "acl_version")?;
+
+        if version != POSIX_ACL_XATTR_VERSION {
+            bail!("Unsupported ACL version {version}");
+        }
+
+        data = &data[POSIX_ACL_HEADER_SIZE..];
+
+        if !data.len().is_multiple_of(POSIX_ACL_ENTRY_SIZE) {
+            bail!(
+                "ACL entries of {} bytes are not a multiple of 8",
+                data.len()
+            );
+        }
+
+        let mut entries = vec![];
+
+        for entry in data.chunks_exact(POSIX_ACL_ENTRY_SIZE) {
+            let tag = u16_le(entry, "acl_tag")?;
+            let perm = u16_le(&entry[2..], "acl_perm")?;
+            let id = u32_le(&entry[4..], "acl_id")?;
+
+            let tag = match tag {
+                ACL_USER_OBJ => AclTag::UserObj,
+                ACL_USER if id != ACL_UNDEFINED_ID => AclTag::User(id),
+                ACL_GROUP_OBJ => AclTag::GroupObj,
+                ACL_GROUP if id != ACL_UNDEFINED_ID => AclTag::Group(id),
+                ACL_MASK => AclTag::Mask,
+                ACL_OTHER => AclTag::Other,
+                _ => bail!("Invalid ACL entry with tag {tag:#x} and id {id}"),
+            };
+
+            entries.push(AclEntry { tag, perm });
+        }
+
+        Ok(Acl { default, entries })
+    }
+
+    pub fn mask(&self) -> Option<u16> {
+        self.entries
+            .iter()
+            .find(|e| e.tag == AclTag::Mask)
+            .map(|e| e.perm)
+    }
+}
+
+impl Display for Acl {
+    /// Same as `getfacl --numeric --omit-header`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let prefix = if self.default { "default:" } else { "" };
+        let mask = self.mask();
+
+        for entry in &self.entries {
+            let line = match entry.tag {
+                AclTag::UserObj => format!("{prefix}user::{}", perm_str(entry.perm)),
+                AclTag::User(id) => format!("{prefix}user:{id}:{}", perm_str(entry.perm)),
+                AclTag::GroupObj => format!("{prefix}group::{}", perm_str(entry.perm)),
+                AclTag::Group(id) => format!("{prefix}group:{id}:{}", perm_str(entry.perm)),
+                AclTag::Mask => format!("{prefix}mask::{}", perm_str(entry.perm)),
+                AclTag::Other => format!("{prefix}other::{}", perm_str(entry.perm)),
+            };
+
+            // The mask only limits named users and all groups
+            let masked = matches!(
+                entry.tag,
+                AclTag::User(..) | AclTag::GroupObj | AclTag::Group(..)
+            );
+
+            match mask {
+                Some(mask) if masked && entry.perm & !mask != 0 => {
+                    // getfacl lines the comment up at the 4th tab stop
+                    let tabs = 4usize.saturating_sub(line.len() / 8).max(1);
+
+                    writeln!(
+                        f,
+                        "{line}{}#effective:{}",
+                        "\t".repeat(tabs),
+                        perm_str(entry.perm & mask)
+                    )?;
+                }
+
+                _ => writeln!(f, "{line}")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The xattr value for `entries` of (tag, perm, id)
+    fn xattr(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut data = POSIX_ACL_XATTR_VERSION.to_le_bytes().to_vec();
+
+        for (tag, perm, id) in entries {
+            data.extend_from_slice(&tag.to_le_bytes());
+            data.extend_from_slice(&perm.to_le_bytes());
+            data.extend_from_slice(&id.to_le_bytes());
+        }
+
+        data
+    }
+
+    // setfacl -m u:1000:rwx,g:100:rw,m::r-x
+    const WITH_MASK: &[(u16, u16, u32)] = &[
+        (ACL_USER_OBJ, 6, ACL_UNDEFINED_ID),
+        (ACL_USER, 7, 1000),
+        (ACL_GROUP_OBJ, 4, ACL_UNDEFINED_ID),
+        (ACL_GROUP, 6, 100),
+        (ACL_MASK, 5, ACL_UNDEFINED_ID),
+        (ACL_OTHER, 4, ACL_UNDEFINED_ID),
+    ];
+
+    #[test]
+    fn access() {
+        let acl = Acl::parse(&xattr(WITH_MASK), false).unwrap();
+
+        assert_eq!(acl.mask(), Some(5));
+        assert_eq!(acl.entries[1].tag, AclTag::User(1000));
+        assert_eq!(acl.entries[3].tag, AclTag::Group(100));
+        assert_eq!(
+            acl.to_string(),
+            "user::rw-\n\
+             user:1000:rwx\t\t\t#effective:r-x\n\
+             group::r--\n\
+             group:100:rw-\t\t\t#effective:r--\n\
+             mask::r-x\n\
+             other::r--\n"
+        );
+    }
+
+    #[test]
+    fn default() {
+        let acl = Acl::parse(&xattr(WITH_MASK), true).unwrap();
+
+        assert_eq!(
+            acl.to_string(),
+            "default:user::rw-\n\
+             default:user:1000:rwx\t\t#effective:r-x\n\
+             default:group::r--\n\
+             default:group:100:rw-\t\t#effective:r--\n\
+             default:mask::r-x\n\
+             default:other::r--\n"
+        );
+    }
+
+    #[test]
+    fn without_mask() {
+        let acl = Acl::parse(
+            &xattr(&[
+                (ACL_USER_OBJ, 7, ACL_UNDEFINED_ID),
+                (ACL_GROUP_OBJ, 5, ACL_UNDEFINED_ID),
+                (ACL_OTHER, 0, ACL_UNDEFINED_ID),
+            ]),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(acl.mask(), None);
+        assert_eq!(acl.to_string(), "user::rwx\ngroup::r-x\nother::---\n");
+    }
+
+    #[test]
+    fn mask_allows_everything() {
+        let mut entries = WITH_MASK.to_vec();
+        entries[4].1 = 7;
+        let acl = Acl::parse(&xattr(&entries), false).unwrap();
+
+        assert!(!acl.to_string().contains("#effective"));
+    }
+
+    #[test]
+    fn invalid() {
+        let valid = xattr(WITH_MASK);
+
+        // Truncated in the header and in the middle of an entry
+        assert!(Acl::parse(&valid[..3], false).is_err());
+        assert!(Acl::parse(&valid[..valid.len() - 1], false).is_err());
+        assert!(Acl::parse(&[], false).is_err());
+
+        let mut version = valid.clone();
+        version[0] = 1;
+        assert!(Acl::parse(&version, false).is_err());
+
+        // Named entries need an id, and tags are one of the known ones
+        assert!(Acl::parse(&xattr(&[(ACL_USER, 7, ACL_UNDEFINED_ID)]), false).is_err());
+        assert!(Acl::parse(&xattr(&[(0x40, 7, 0)]), false).is_err());
+    }
+}
@@ -0,0 +1,1085 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use sha2::{Digest, Sha256};
+
+use crate::composefs::*;
+use crate::image::SUPERBLOCK_OFFSET;
+use crate::inode::*;
+use crate::metadata::{FileType, encode_dev};
+use crate::objects::{INLINE_MAX, ImportMode, ObjectStore};
+use crate::sb::*;
+use crate::utils::xxh32;
+use crate::verity::{self, HashAlgorithm};
+
+const BLOCK_SIZE: usize = 1 << BLOCK_BITS;
+
+// Inodes are addressed in 32 byte slots, the first one that's free comes right after the
+// superblock
+const SLOT_SIZE: usize = 32;
+const FIRST_SLOT: usize = SUPERBLOCK_OFFSET + size_of::<Superblock>();
+
+/// Index of the root directory in an [`ImageBuilder`]
+pub const ROOT: usize = 0;
+
+#[derive(Debug, Clone)]
+pub enum FileData {
+    Bytes(Vec<u8>),
+    /// A file on the host, it's only read while the image is written
+    Host {
+        path: PathBuf,
+        size: u64,
+    },
+    /// Contents live in a composefs object store, the image only points at them. The redirect
+    /// is the object's path in the store, by default it follows from the fs-verity digest
+    External {
+        size: u64,
+        redirect: Option<String>,
+        digest: Option<Vec<u8>>,
+    },
+}
+
+impl FileData {
+    pub fn size(&self) -> u64 {
+        match self {
+            FileData::Bytes(bytes) => bytes.len() as u64,
+            FileData::Host { size, .. } | FileData::External { size, .. } => *size,
+        }
+    }
+
+    /// Writes `len` bytes starting at `offset`
+    fn copy_range(&self, offset: u64, len: u64, writer: &mut dyn Write) -> Result<()> {
+        match self {
+            FileData::Bytes(bytes) => {
+                writer.write_all(&bytes[offset as usize..(offset + len) as usize])?;
+            }
+
+            FileData::Host { path, .. } => {
+                let mut file =
+                    File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+                file.seek(SeekFrom::Start(offset))?;
+
+                let copied = std::io::copy(&mut file.take(len), writer)
+                    .with_context(|| format!("Reading {}", path.display()))?;
+
+                if copied != len {
+                    bail!("{} shrank while building the image", path.display());
+                }
+            }
+
+            // Stored as holes
+            FileData::External { .. } => writer.write_all(&vec![0; len as usize])?,
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Content {
+    /// Entries by name, each the index of another node in the builder
+    Directory(BTreeMap<Vec<u8>, usize>),
+    Regular(FileData),
+    Symlink(Vec<u8>),
+    /// Device numbers are in the same encoding as `st_rdev`
+    CharDevice(u64),
+    BlockDevice(u64),
+    Fifo,
+    Socket,
+}
+
+/// One inode of the image being built
+#[derive(Debug, Clone)]
+pub struct Node {
+    /// Permission bits, the file type follows from `content`
+    pub permissions: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u64,
+    pub mtime_nsec: u32,
+    /// By full name, like `user.foo`
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+    pub content: Content,
+}
+
+impl Node {
+    /// Owned by root, with an mtime of 0 and no xattrs
+    pub fn new(permissions: u16, content: Content) -> Self {
+        Node {
+            permissions,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            mtime_nsec: 0,
+            xattrs: BTreeMap::new(),
+            content,
+        }
+    }
+
+    pub fn file_type(&self) -> FileType {
+        match self.content {
+            Content::Directory(..) => FileType::Directory,
+            Content::Regular(..) => FileType::Regular,
+            Content::Symlink(..) => FileType::Symlink,
+            Content::CharDevice(..) => FileType::CharDevice,
+            Content::BlockDevice(..) => FileType::BlockDevice,
+            Content::Fifo => FileType::Fifo,
+            Content::Socket => FileType::Socket,
+        }
+    }
+
+    pub fn mode(&self) -> u16 {
+        self.file_type().mode_bits() | (self.permissions & !S_IFMT)
+    }
+}
+
+/// Builds an uncompressed EROFS image from a tree of [`Node`]s, either filled in by hand or
+/// copied from a host directory with [`ImageBuilder::from_dir`]
+pub struct ImageBuilder {
+    nodes: Vec<Node>,
+    build_time: Option<(u64, u32)>,
+    uuid: [u8; 16],
+    derive_uuid: bool,
+    volume_name: [u8; 16],
+    composefs: bool,
+}
+
+impl Default for ImageBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Where an inode ends up in the image
+struct Slot<'a> {
+    id: usize,
+    node: &'a Node,
+    nid: u64,
+    nlink: u32,
+    extended: bool,
+    layout: InodeDataLayout,
+    /// By full name, after composefs escaping
+    xattrs: BTreeMap<String, Vec<u8>>,
+    xattr_area: Vec<u8>,
+    size: u64,
+    /// Sorted entries, including "." and "..", for directories
+    dirents: Vec<(&'a [u8], usize)>,
+    /// First block of the data that isn't inline
+    blkaddr: u32,
+}
+
+impl Slot<'_> {
+    fn header_size(&self) -> usize {
+        if self.extended {
+            size_of::<ExtendedInodeHeader>()
+        } else {
+            size_of::<CompactInodeHeader>()
+        }
+    }
+
+    /// Length of the data that goes in whole blocks, the rest is the inline tail
+    fn plain_len(&self) -> u64 {
+        match self.layout {
+            InodeDataLayout::FlatInline => {
+                self.size.div_ceil(BLOCK_SIZE as u64).saturating_sub(1) * BLOCK_SIZE as u64
+            }
+            InodeDataLayout::ChunkBased => 0,
+            _ => self.size,
+        }
+    }
+
+    /// Chunks are as large as needed for a single one to cover the file, as far as the chunk
+    /// format allows
+    fn chunk_format(&self) -> u32 {
+        let bits = (u64::BITS - self.size.saturating_sub(1).leading_zeros()).max(BLOCK_BITS.into());
+        (bits - u32::from(BLOCK_BITS)).min(EROFS_CHUNK_FORMAT_BLKBITS_MASK)
+    }
+
+    fn chunks(&self) -> usize {
+        self.size
+            .div_ceil((BLOCK_SIZE as u64) << self.chunk_format()) as usize
+    }
+
+    fn data_blocks(&self) -> u64 {
+        self.plain_len().div_ceil(BLOCK_SIZE as u64)
+    }
+}
+
+impl ImageBuilder {
+    /// An image with nothing but an empty root directory
+    pub fn new() -> Self {
+        ImageBuilder {
+            nodes: vec![Node::new(0o755, Content::Directory(BTreeMap::new()))],
+            build_time: None,
+            uuid: [0; 16],
+            derive_uuid: false,
+            volume_name: [0; 16],
+            composefs: false,
+        }
+    }
+
+    /// Copies the tree below `path` on the host, the contents of regular files are only read
+    /// when the image is written
+    #[fn_error_context::context("Reading {}", path.display())]
+    pub fn from_dir(path: &Path) -> Result<Self> {
+        let mut builder = Self::new();
+
+        let metadata = std::fs::symlink_metadata(path)?;
+        if !metadata.is_dir() {
+            bail!("{} is not a directory", path.display());
+        }
+
+        builder.nodes[ROOT] = host_node(path, &metadata)?;
+        builder.add_host_dir(ROOT, path, &mut HashMap::new())?;
+
+        Ok(builder)
+    }
+
+    fn add_host_dir(
+        &mut self,
+        dir: usize,
+        path: &Path,
+        hardlinks: &mut HashMap<(u64, u64), usize>,
+    ) -> Result<()> {
+        let entries = std::fs::read_dir(path)
+            .with_context(|| format!("Reading directory {}", path.display()))?;
+
+        for entry in entries {
+            let entry = entry.with_context(|| format!("Reading directory {}", path.display()))?;
+            let path = entry.path();
+            let name = entry.file_name();
+
+            let metadata = std::fs::symlink_metadata(&path)
+                .with_context(|| format!("Reading {}", path.display()))?;
+
+            let key = (metadata.dev(), metadata.ino());
+
+            if !metadata.is_dir()
+                && metadata.nlink() > 1
+                && let Some(first) = hardlinks.get(&key)
+            {
+                self.link(dir, name.as_bytes(), *first)?;
+                continue;
+            }
+
+            let id = self.insert(dir, name.as_bytes(), host_node(&path, &metadata)?)?;
+
+            if metadata.is_dir() {
+                self.add_host_dir(id, &path, hardlinks)?;
+            } else if metadata.nlink() > 1 {
+                hardlinks.insert(key, id);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Makes the host files external for a composefs image, with their contents imported into
+    /// `objects`. Without a store only the digests are computed and the objects have to get
+    /// there some other way. Files up to [`INLINE_MAX`] bytes stay in the image
+    pub fn import_objects(
+        &mut self,
+        objects: Option<&ObjectStore>,
+        mode: ImportMode,
+    ) -> Result<()> {
+        for node in &mut self.nodes {
+            let Content::Regular(FileData::Host { path, size }) = &node.content else {
+                continue;
+            };
+            let size = *size;
+
+            if size <= INLINE_MAX {
+                continue;
+            }
+
+            let digest = match objects {
+                Some(objects) => objects.import(path, mode)?,
+                None => verity::file_digest(path, HashAlgorithm::Sha256, &[])?,
+            };
+
+            node.content = Content::Regular(FileData::External {
+                size,
+                redirect: None,
+                digest: Some(digest),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Timestamp of compact inodes, those with any other mtime get an extended inode. By
+    /// default it's the most common mtime in the tree
+    pub fn build_time(mut self, secs: u64, nsec: u32) -> Self {
+        self.build_time = Some((secs, nsec));
+        self
+    }
+
+    pub fn uuid(mut self, uuid: [u8; 16]) -> Self {
+        self.uuid = uuid;
+        self.derive_uuid = false;
+        self
+    }
+
+    /// Makes the uuid a hash of the rest of the image, so the same tree always gets the same
+    /// one. Host files are read twice for this
+    pub fn derive_uuid(mut self) -> Self {
+        self.derive_uuid = true;
+        self
+    }
+
+    /// At most 16 bytes
+    pub fn volume_name(mut self, name: &str) -> Result<Self> {
+        if name.len() > self.volume_name.len() {
+            bail!("Volume name {name:?} is longer than 16 bytes");
+        }
+
+        self.volume_name = [0; 16];
+        self.volume_name[..name.len()].copy_from_slice(name.as_bytes());
+
+        Ok(self)
+    }
+
+    /// Writes a composefs image: the composefs header goes in front, the files' own
+    /// `trusted.overlay.` xattrs are escaped, external files point at their object with overlay
+    /// xattrs and inodes get an xattr name filter. Compact inodes take the oldest mtime by
+    /// default, like mkcomposefs
+    pub fn composefs(mut self) -> Self {
+        self.composefs = true;
+        self
+    }
+
+    pub fn node(&self, id: usize) -> &Node {
+        &self.nodes[id]
+    }
+
+    pub fn node_mut(&mut self, id: usize) -> &mut Node {
+        &mut self.nodes[id]
+    }
+
+    fn entries_mut(&mut self, dir: usize) -> Result<&mut BTreeMap<Vec<u8>, usize>> {
+        match &mut self.nodes[dir].content {
+            Content::Directory(entries) => Ok(entries),
+            _ => bail!("Node {dir} is not a directory"),
+        }
+    }
+
+    /// Adds `node` as `name` in the directory `dir`, replacing whatever was there. Returns the
+    /// id of the new node
+    pub fn insert(&mut self, dir: usize, name: &[u8], node: Node) -> Result<usize> {
+        check_name(name)?;
+
+        let id = self.nodes.len();
+        self.entries_mut(dir)?.insert(name.to_vec(), id);
+        self.nodes.push(node);
+
+        Ok(id)
+    }
+
+    /// Adds another name for the existing node `target`, which can't be a directory
+    pub fn link(&mut self, dir: usize, name: &[u8], target: usize) -> Result<()> {
+        check_name(name)?;
+
+        if self.nodes[target].file_type() == FileType::Directory {
+            bail!("Can't hardlink directory {target}");
+        }
+
+        self.entries_mut(dir)?.insert(name.to_vec(), target);
+
+        Ok(())
+    }
+
+    /// Finds the node at `path`, relative to the root. Symlinks are not followed
+    pub fn lookup(&self, path: &[u8]) -> Option<usize> {
+        path.split(|c| *c == b'/')
+            .filter(|c| !c.is_empty() && *c != b".")
+            .try_fold(ROOT, |id, name| match &self.nodes[id].content {
+                Content::Directory(entries) => entries.get(name).copied(),
+                _ => None,
+            })
+    }
+
+    /// Writes the image, with the inodes in breadth first order and all file data after them.
+    ///
+    /// Nothing depends on the order nodes were added in: directories are walked by name, inode
+    /// numbers and nids follow that walk and shared xattrs are sorted. With a fixed build time
+    /// and uuid, or a derived one, the same tree gives the same image
+    #[fn_error_context::context("Writing EROFS image")]
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<W> {
+        let mut slots = self.plan()?;
+
+        let shared = SharedXattrs::new(&slots)?;
+        for slot in &mut slots {
+            slot.xattr_area = shared.area(&slot.xattrs, self.composefs)?;
+        }
+
+        let build_time = self.build_time.unwrap_or_else(|| {
+            if self.composefs {
+                oldest_mtime(&slots)
+            } else {
+                common_mtime(&slots)
+            }
+        });
+        self.layout(&mut slots, build_time)?;
+
+        let index: HashMap<usize, usize> =
+            slots.iter().enumerate().map(|(i, s)| (s.id, i)).collect();
+
+        let meta_blocks = slots
+            .iter()
+            .map(|s| s.nid as usize * SLOT_SIZE + self.meta_size(s))
+            .max()
+            .unwrap_or(FIRST_SLOT)
+            .div_ceil(BLOCK_SIZE);
+
+        let xattr_blkaddr = match shared.area.len() {
+            0 => 0,
+            _ => meta_blocks as u32,
+        };
+        let shared_blocks = shared.area.len().div_ceil(BLOCK_SIZE);
+
+        let mut blocks = (meta_blocks + shared_blocks) as u64;
+        for slot in &mut slots {
+            if slot.data_blocks() > 0 {
+                slot.blkaddr = u32::try_from(blocks).context("Image has too many blocks")?;
+                blocks += slot.data_blocks();
+            }
+        }
+        let blocks = u32::try_from(blocks).context("Image has too many blocks")?;
+
+        let data: Vec<Cow<FileData>> = slots
+            .iter()
+            .map(|s| self.data(s, &slots, &index))
+            .collect::<Result<_>>()?;
+
+        let mut meta = vec![0; meta_blocks * BLOCK_SIZE];
+
+        for (ino, (slot, data)) in slots.iter().zip(&data).enumerate() {
+            let mut inode = self.inode_bytes(slot, ino as u32 + 1, build_time);
+            inode.extend_from_slice(&slot.xattr_area);
+
+            match slot.layout {
+                InodeDataLayout::FlatInline => {
+                    let plain_len = slot.plain_len();
+                    data.copy_range(plain_len, slot.size - plain_len, &mut inode)?;
+                }
+                InodeDataLayout::ChunkBased => {
+                    for _ in 0..slot.chunks() {
+                        inode.extend_from_slice(&EROFS_NULL_ADDR.to_le_bytes());
+                    }
+                }
+                _ => {}
+            }
+
+            let offset = slot.nid as usize * SLOT_SIZE;
+            meta[offset..offset + inode.len()].copy_from_slice(&inode);
+        }
+
+        let mut feature_compat = FEATURE_COMPAT_SB_CHKSUM | FEATURE_COMPAT_MTIME;
+        let mut feature_incompat = 0;
+
+        if self.composefs {
+            feature_compat |= FEATURE_COMPAT_XATTR_FILTER;
+
+            let has_acl = slots.iter().any(|s| {
+                s.xattrs
+                    .contains_key(xattr_prefix(EROFS_XATTR_INDEX_POSIX_ACL_ACCESS))
+                    || s.xattrs
+                        .contains_key(xattr_prefix(EROFS_XATTR_INDEX_POSIX_ACL_DEFAULT))
+            });
+            let flags = if has_acl { COMPOSEFS_FLAGS_HAS_ACL } else { 0 };
+
+            let header = ComposefsHeader::new(flags).to_bytes();
+            meta[..header.len()].copy_from_slice(&header);
+        }
+
+        if slots
+            .iter()
+            .any(|s| s.layout == InodeDataLayout::ChunkBased)
+        {
+            feature_incompat |= FEATURE_INCOMPAT_CHUNKED_FILE;
+        }
+
+        let mut superblock = Superblock {
+            magic: MAGIC_V1,
+            checksum: 0,
+            feature_compat,
+            blkszbits: BLOCK_BITS,
+            extslots: 0,
+            root_nid: slots[0].nid as u16,
+            inos: slots.len() as u64,
+            build_time: build_time.0,
+            build_time_nsec: build_time.1,
+            blocks,
+            meta_blkaddr: 0,
+            xattr_blkaddr,
+            uuid: if self.derive_uuid { [0; 16] } else { self.uuid },
+            volume_name: self.volume_name,
+            feature_incompat,
+            available_compr_algs: 0,
+            extra_devices: 0,
+            devt_slotoff: 0,
+            dirblkbits: 0,
+            xattr_prefix_count: 0,
+            xattr_prefix_start: 0,
+            packed_nid: 0,
+            xattr_filter_reserved: 0,
+            reserved2: [0; 23],
+        };
+
+        let sb_range = SUPERBLOCK_OFFSET..SUPERBLOCK_OFFSET + size_of::<Superblock>();
+        meta[sb_range.clone()].copy_from_slice(&superblock.to_bytes());
+
+        let mut shared_area = shared.area;
+        shared_area.resize(shared_blocks * BLOCK_SIZE, 0);
+
+        if self.derive_uuid {
+            let mut hasher = Sha256::new();
+            hasher.update(&meta);
+            hasher.update(&shared_area);
+            write_data(&slots, &data, &mut hasher)?;
+
+            let hash = hasher.finalize();
+            superblock.uuid.copy_from_slice(&hash[..16]);
+
+            // Version 8, variant 1
+            superblock.uuid[6] = (superblock.uuid[6] & 0x0f) | 0x80;
+            superblock.uuid[8] = (superblock.uuid[8] & 0x3f) | 0x80;
+            meta[sb_range.clone()].copy_from_slice(&superblock.to_bytes());
+        }
+
+        superblock.checksum = checksum(&meta[SUPERBLOCK_OFFSET..BLOCK_SIZE]);
+        meta[sb_range].copy_from_slice(&superblock.to_bytes());
+
+        writer.write_all(&meta)?;
+        writer.write_all(&shared_area)?;
+        write_data(&slots, &data, &mut writer)?;
+
+        Ok(writer)
+    }
+
+    /// One slot per reachable node in breadth first order, with everything but the placement
+    fn plan(&self) -> Result<Vec<Slot<'_>>> {
+        let mut order = vec![ROOT];
+        let mut seen = HashSet::from([ROOT]);
+        let mut nlinks = HashMap::from([(ROOT, 2)]);
+        let mut parents = HashMap::from([(ROOT, ROOT)]);
+        let mut queue = VecDeque::from([ROOT]);
+
+        while let Some(dir) = queue.pop_front() {
+            let Content::Directory(entries) = &self.nodes[dir].content else {
+                continue;
+            };
+
+            for &id in entries.values() {
+                if self.nodes[id].file_type() == FileType::Directory {
+                    *nlinks.get_mut(&dir).unwrap() += 1;
+                    nlinks.insert(id, 2);
+                    parents.insert(id, dir);
+                } else {
+                    *nlinks.entry(id).or_insert(0) += 1;
+                }
+
+                if seen.insert(id) {
+                    order.push(id);
+                    queue.push_back(id);
+                }
+            }
+        }
+
+        order
+            .into_iter()
+            .map(|id| {
+                let node = &self.nodes[id];
+
+                let mut dirents = vec![];
+
+                let size = match &node.content {
+                    Content::Directory(entries) => {
+                        dirents = entries
+                            .iter()
+                            .map(|(name, id)| (name.as_slice(), *id))
+                            .chain([(&b"."[..], id), (&b".."[..], parents[&id])])
+                            .collect();
+                        dirents.sort();
+
+                        dir_size(&dirents)
+                    }
+                    Content::Regular(data) => data.size(),
+                    Content::Symlink(target) => target.len() as u64,
+                    _ => 0,
+                };
+
+                let nlink = nlinks[&id];
+
+                Ok(Slot {
+                    id,
+                    node,
+                    nid: 0,
+                    nlink,
+                    extended: node.uid > u16::MAX.into()
+                        || node.gid > u16::MAX.into()
+                        || nlink > u16::MAX.into()
+                        || size > u32::MAX.into(),
+                    layout: InodeDataLayout::FlatPlain,
+                    xattrs: self.xattrs(node)?,
+                    xattr_area: vec![],
+                    size,
+                    dirents,
+                    blkaddr: 0,
+                })
+            })
+            .collect()
+    }
+
+    /// Picks compact or extended inodes and the data layout, and gives every slot its nid
+    fn layout(&self, slots: &mut [Slot], build_time: (u64, u32)) -> Result<()> {
+        let mut offset = FIRST_SLOT;
+
+        for slot in slots.iter_mut() {
+            slot.extended |= (slot.node.mtime, slot.node.mtime_nsec) != build_time;
+
+            // The tail is inline when it fits in a block together with the inode, so it never
+            // crosses a block boundary
+            slot.layout = InodeDataLayout::FlatInline;
+            let size = self.meta_size(slot);
+
+            if slot.size > 0
+                && matches!(
+                    slot.node.content,
+                    Content::Regular(FileData::External { .. })
+                )
+            {
+                slot.layout = InodeDataLayout::ChunkBased;
+            } else if slot.size == 0 || size > BLOCK_SIZE {
+                slot.layout = InodeDataLayout::FlatPlain;
+            } else if offset % BLOCK_SIZE + size > BLOCK_SIZE {
+                offset = offset.next_multiple_of(BLOCK_SIZE);
+            }
+
+            slot.nid = (offset / SLOT_SIZE) as u64;
+            offset = (offset + self.meta_size(slot)).next_multiple_of(SLOT_SIZE);
+        }
+
+        if slots[0].nid > u16::MAX.into() {
+            bail!("Root nid {} doesn't fit in the superblock", slots[0].nid);
+        }
+
+        Ok(())
+    }
+
+    /// Bytes the inode takes up in the metadata area
+    fn meta_size(&self, slot: &Slot) -> usize {
+        let data = match slot.layout {
+            InodeDataLayout::FlatInline => (slot.size - slot.plain_len()) as usize,
+            InodeDataLayout::ChunkBased => slot.chunks() * size_of::<u32>(),
+            _ => 0,
+        };
+
+        slot.header_size() + slot.xattr_area.len() + data
+    }
+
+    /// The xattrs that end up in the image for `node`
+    fn xattrs(&self, node: &Node) -> Result<BTreeMap<String, Vec<u8>>> {
+        let external = match &node.content {
+            Content::Regular(FileData::External {
+                redirect, digest, ..
+            }) => Some((redirect, digest)),
+            _ => None,
+        };
+
+        if !self.composefs {
+            if external.is_some() {
+                bail!("Files with external contents need a composefs image");
+            }
+
+            return Ok(node.xattrs.clone());
+        }
+
+        let mut xattrs: BTreeMap<String, Vec<u8>> = node
+            .xattrs
+            .iter()
+            .map(|(name, value)| (escape_xattr_name(name), value.clone()))
+            .collect();
+
+        if let Some((redirect, digest)) = external {
+            let redirect = match (redirect, digest) {
+                (Some(redirect), _) => redirect.trim_start_matches('/').to_string(),
+                (None, Some(digest)) => object_path(digest),
+                (None, None) => bail!("External file needs a redirect or a digest"),
+            };
+
+            xattrs.insert(OVERLAY_REDIRECT.into(), format!("/{redirect}").into_bytes());
+            xattrs.insert(OVERLAY_METACOPY.into(), metacopy(digest.as_deref())?);
+        }
+
+        Ok(xattrs)
+    }
+
+    /// Contents of the inode, directories are only serialized here once all nids are known
+    fn data<'a>(
+        &'a self,
+        slot: &Slot<'a>,
+        slots: &[Slot],
+        index: &HashMap<usize, usize>,
+    ) -> Result<Cow<'a, FileData>> {
+        Ok(match &slot.node.content {
+            Content::Directory(..) => Cow::Owned(FileData::Bytes(dir_bytes(&slot.dirents, |id| {
+                let target = &slots[index[&id]];
+                (target.nid, target.node.file_type().dirent_type())
+            }))),
+            Content::Regular(data) => Cow::Borrowed(data),
+            Content::Symlink(target) => Cow::Owned(FileData::Bytes(target.clone())),
+            _ => Cow::Owned(FileData::Bytes(vec![])),
+        })
+    }
+
+    fn inode_bytes(&self, slot: &Slot, ino: u32, build_time: (u64, u32)) -> Vec<u8> {
+        let node = slot.node;
+
+        let u = match node.content {
+            Content::CharDevice(rdev) | Content::BlockDevice(rdev) => encode_dev(rdev),
+            _ if slot.layout == InodeDataLayout::ChunkBased => slot.chunk_format(),
+            _ => slot.blkaddr,
+        };
+
+        let layout = (slot.layout as u16) << EROFS_I_DATALAYOUT_BIT;
+        let xattr_icount = match slot.xattr_area.len() {
+            0 => 0,
+            len => ((len - 12) / 4 + 1) as u16,
+        };
+
+        if slot.extended {
+            ExtendedInodeHeader {
+                format: layout | EROFS_I_VERSION_EXTENDED,
+                xattr_icount,
+                mode: node.mode(),
+                reserved: 0,
+                size: slot.size,
+                u,
+                ino,
+                uid: node.uid,
+                gid: node.gid,
+                mtime: node.mtime,
+                mtime_nsec: node.mtime_nsec,
+                nlink: slot.nlink,
+                reserved2: [0; 16],
+            }
+            .to_bytes()
+            .to_vec()
+        } else {
+            debug_assert_eq!((node.mtime, node.mtime_nsec), build_time);
+
+            CompactInodeHeader {
+                format: layout,
+                xattr_icount,
+                mode: node.mode(),
+                nlink: slot.nlink as u16,
+                size: slot.size as u32,
+                reserved: 0,
+                u,
+                ino,
+                uid: node.uid as u16,
+                gid: node.gid as u16,
+                reserved2: [0; 4],
+            }
+            .to_bytes()
+            .to_vec()
+        }
+    }
+}
+
+/// The blocks after the shared xattrs, with the data of every inode that isn't inline
+fn write_data(slots: &[Slot], data: &[Cow<FileData>], writer: &mut dyn Write) -> Result<()> {
+    for (slot, data) in slots.iter().zip(data) {
+        let plain_len = slot.plain_len();
+
+        if plain_len > 0 {
+            data.copy_range(0, plain_len, writer)?;
+
+            let padding = slot.data_blocks() as usize * BLOCK_SIZE - plain_len as usize;
+            writer.write_all(&vec![0; padding])?;
+        }
+    }
+
+    Ok(())
+}
+
+fn check_name(name: &[u8]) -> Result<()> {
+    if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') {
+        bail!("Invalid name {:?}", String::from_utf8_lossy(name));
+    }
+
+    if name.len() > EROFS_NAME_LEN {
+        bail!(
+            "Name {:?} is longer than {EROFS_NAME_LEN} bytes",
+            String::from_utf8_lossy(name)
+        );
+    }
+
+    Ok(())
+}
+
+/// The mtime most inodes have, so most of them can be compact. The earliest one on a tie
+fn common_mtime(slots: &[Slot]) -> (u64, u32) {
+    let mut counts: HashMap<(u64, u32), usize> = HashMap::new();
+
+    for slot in slots {
+        *counts
+            .entry((slot.node.mtime, slot.node.mtime_nsec))
+            .or_default() += 1;
+    }
+
+    counts
+        .into_iter()
+        .max_by_key(|(mtime, count)| (*count, std::cmp::Reverse(*mtime)))
+        .map(|(mtime, _)| mtime)
+        .unwrap_or_default()
+}
+
+fn oldest_mtime(slots: &[Slot]) -> (u64, u32) {
+    slots
+        .iter()
+        .map(|s| (s.node.mtime, s.node.mtime_nsec))
+        .min()
+        .unwrap_or_default()
+}
+
+/// The dirents that go in each directory block. A name never crosses into the next block
+fn dir_blocks(dirents: &[(&[u8], usize)]) -> Vec<Range<usize>> {
+    let mut blocks = vec![];
+    let mut start = 0;
+    let mut used = 0;
+
+    for (i, (name, _)) in dirents.iter().enumerate() {
+        let len = size_of::<DirEnt>() + name.len();
+
+        if used + len > BLOCK_SIZE {
+            blocks.push(start..i);
+            start = i;
+            used = 0;
+        }
+
+        used += len;
+    }
+
+    blocks.push(start..dirents.len());
+    blocks
+}
+
+/// Full blocks for all but the last block, which is only as long as its contents
+fn dir_size(dirents: &[(&[u8], usize)]) -> u64 {
+    let blocks = dir_blocks(dirents);
+    let last = &dirents[blocks.last().unwrap().clone()];
+    let last_len: usize = last
+        .iter()
+        .map(|(name, _)| size_of::<DirEnt>() + name.len())
+        .sum();
+
+    ((blocks.len() - 1) * BLOCK_SIZE + last_len) as u64
+}
+
+/// Serializes the directory, `target` gives the nid and dirent file type of a node
+fn dir_bytes(dirents: &[(&[u8], usize)], target: impl Fn(usize) -> (u64, u8)) -> Vec<u8> {
+    let mut bytes = vec![];
+
+    for block in dir_blocks(dirents) {
+        if !bytes.is_empty() {
+            bytes.resize(bytes.len().next_multiple_of(BLOCK_SIZE), 0);
+        }
+
+        let dirents = &dirents[block];
+        let mut name_offset = dirents.len() * size_of::<DirEnt>();
+
+        for (name, id) in dirents {
+            let (nid, file_type) = target(*id);
+
+            let dirent = DirEnt {
+                nid,
+                name_offset: name_offset as u16,
+                file_type,
+                reserved: 0,
+            };
+            bytes.extend_from_slice(&dirent.to_bytes());
+
+            name_offset += name.len();
+        }
+
+        for (name, _) in dirents {
+            bytes.extend_from_slice(name);
+        }
+    }
+
+    bytes
+}
+
+/// Xattrs that more than one inode has are stored once, in the shared area
+struct SharedXattrs {
+    ids: HashMap<(String, Vec<u8>), u32>,
+    area: Vec<u8>,
+}
+
+impl SharedXattrs {
+    fn new(slots: &[Slot]) -> Result<Self> {
+        let mut counts: HashMap<(&str, &[u8]), usize> = HashMap::new();
+
+        for slot in slots {
+            for (name, value) in &slot.xattrs {
+                *counts.entry((name, value)).or_default() += 1;
+            }
+        }
+
+        // Sorted, so the ids don't depend on the order of the tree
+        let mut shared: Vec<_> = counts
+            .into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(xattr, _)| xattr)
+            .collect();
+        shared.sort();
+
+        let mut ids = HashMap::new();
+        let mut area = vec![];
+
+        for (name, value) in shared {
+            ids.insert((name.to_string(), value.to_vec()), (area.len() / 4) as u32);
+            xattr_entry(name, value, &mut area)?;
+        }
+
+        Ok(SharedXattrs { ids, area })
+    }
+
+    /// The xattr area of an inode: the header, the ids of its shared xattrs and then its own
+    /// entries, each padded to 4 bytes
+    fn area(&self, xattrs: &BTreeMap<String, Vec<u8>>, filter: bool) -> Result<Vec<u8>> {
+        if xattrs.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut shared = vec![];
+        let mut inline = vec![];
+
+        for (name, value) in xattrs {
+            match self.ids.get(&(name.clone(), value.clone())) {
+                Some(id) if shared.len() < u8::MAX.into() => shared.push(*id),
+                _ => xattr_entry(name, value, &mut inline)?,
+            }
+        }
+
+        let name_filter = if filter { !name_filter(xattrs) } else { 0 };
+
+        let mut area = vec![];
+        area.extend_from_slice(&name_filter.to_le_bytes());
+        area.push(shared.len() as u8);
+        area.resize(size_of::<XattrHeaderWoShared>(), 0);
+
+        for id in shared {
+            area.extend_from_slice(&id.to_le_bytes());
+        }
+        area.extend_from_slice(&inline);
+
+        Ok(area)
+    }
+}
+
+/// Bloom filter of the xattr names, with a bit set for each name. The image has the inverse
+fn name_filter(xattrs: &BTreeMap<String, Vec<u8>>) -> u32 {
+    xattrs
+        .keys()
+        .filter_map(|name| xattr_name_index(name))
+        .map(|(index, suffix)| {
+            let hash = xxh32(
+                suffix.as_bytes(),
+                EROFS_XATTR_FILTER_SEED + u32::from(index),
+            );
+            1 << (hash % EROFS_XATTR_FILTER_BITS)
+        })
+        .fold(0, |filter, bit| filter | bit)
+}
+
+/// Appends the xattr entry for `name`, padded to 4 bytes
+fn xattr_entry(name: &str, value: &[u8], area: &mut Vec<u8>) -> Result<()> {
+    let Some((index, suffix)) = xattr_name_index(name) else {
+        bail!("Xattr {name} has a prefix EROFS can't store");
+    };
+
+    let name_len =
+        u8::try_from(suffix.len()).with_context(|| format!("Xattr name {name} is too long"))?;
+    let value_size = u16::try_from(value.len())
+        .with_context(|| format!("Value of xattr {name} is too large"))?;
+
+    area.push(name_len);
+    area.push(index);
+    area.extend_from_slice(&value_size.to_le_bytes());
+    area.extend_from_slice(suffix.as_bytes());
+    area.extend_from_slice(value);
+    area.resize(area.len().next_multiple_of(4), 0);
+
+    Ok(())
+}
+
+/// A node for the host file at `path`, directories come without their entries
+fn host_node(path: &Path, metadata: &std::fs::Metadata) -> Result<Node> {
+    let file_type = metadata.file_type();
+
+    let content = if file_type.is_dir() {
+        Content::Directory(BTreeMap::new())
+    } else if file_type.is_file() {
+        Content::Regular(FileData::Host {
+            path: path.to_path_buf(),
+            size: metadata.len(),
+        })
+    } else if file_type.is_symlink() {
+        let target =
+            std::fs::read_link(path).with_context(|| format!("Reading {}", path.display()))?;
+        Content::Symlink(target.as_os_str().as_bytes().to_vec())
+    } else if file_type.is_char_device() {
+        Content::CharDevice(metadata.rdev())
+    } else if file_type.is_block_device() {
+        Content::BlockDevice(metadata.rdev())
+    } else if file_type.is_fifo() {
+        Content::Fifo
+    } else {
+        Content::Socket
+    };
+
+    Ok(Node {
+        permissions: (metadata.mode() & 0o7777) as u16,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        // There's no way to store a time before the epoch
+        mtime: metadata.mtime().try_into().unwrap_or(0),
+        mtime_nsec: metadata.mtime_nsec() as u32,
+        xattrs: host_xattrs(path)?,
+        content,
+    })
+}
+
+fn host_xattrs(path: &Path) -> Result<BTreeMap<String, Vec<u8>>> {
+    let context = || format!("Reading xattrs of {}", path.display());
+
+    let len = rustix::fs::llistxattr(path, &mut [0u8; 0][..]).with_context(context)?;
+    let mut names = vec![0; len];
+    let len = rustix::fs::llistxattr(path, &mut names[..]).with_context(context)?;
+    names.truncate(len);
+
+    let mut xattrs = BTreeMap::new();
+
+    for name in names.split(|c| *c == 0).filter(|n| !n.is_empty()) {
+        let name = String::from_utf8_lossy(name).into_owned();
+
+        let len = rustix::fs::lgetxattr(path, &name, &mut [0u8; 0][..]).with_context(context)?;
+        let mut value = vec![0; len];
+        let len = rustix::fs::lgetxattr(path, &name, &mut value[..]).with_context(context)?;
+        value.truncate(len);
+
+        xattrs.insert(name, value);
+    }
+
+    Ok(xattrs)
+}
@@ -0,0 +1,388 @@
+use std::fmt::Display;
+
+use anyhow::{Result, bail};
+
+use crate::utils::*;
+
+// `security.capability` holds a struct vfs_cap_data
+//
+//  struct vfs_ns_cap_data {
+//      __le32 magic_etc;
+//      struct {
+//          __le32 permitted;
+//          __le32 inheritable;
+//      } data[VFS_CAP_U32];
+//      __le32 rootid; // only in revision 3
+//  };
+//
+// Revision 1 only has a single data entry (32 capabilities)
+const VFS_CAP_REVISION_MASK: u32 = 0xFF000000;
+const VFS_CAP_FLAGS_EFFECTIVE: u32 = 0x000001;
+
+const VFS_CAP_REVISION_1: u32 = 0x01000000;
+const VFS_CAP_REVISION_2: u32 = 0x02000000;
+const VFS_CAP_REVISION_3: u32 = 0x03000000;
+
+const XATTR_CAPS_SZ_1: usize = 4 + 8;
+const XATTR_CAPS_SZ_2: usize = 4 + 2 * 8;
+const XATTR_CAPS_SZ_3: usize = 4 + 2 * 8 + 4;
+
+// Indexed by capability number, see linux/capability.h
+const CAP_NAMES: [&str; 41] = [
+    "cap_chown",
+    "cap_dac_override",
+    "cap_dac_read_search",
+    "cap_fowner",
+    "cap_fsetid",
+    "cap_kill",
+    "cap_setgid",
+    "cap_setuid",
+    "cap_setpcap",
+    "cap_linux_immutable",
+    "cap_net_bind_service",
+    "cap_net_broadcast",
+    "cap_net_admin",
+    "cap_net_raw",
+    "cap_ipc_lock",
+    "cap_ipc_owner",
+    "cap_sys_module",
+    "cap_sys_rawio",
+    "cap_sys_chroot",
+    "cap_sys_ptrace",
+    "cap_sys_pacct",
+    "cap_sys_admin",
+    "cap_sys_boot",
+    "cap_sys_nice",
+    "cap_sys_resource",
+    "cap_sys_time",
+    "cap_sys_tty_config",
+    "cap_mknod",
+    "cap_lease",
+    "cap_audit_write",
+    "cap_audit_control",
+    "cap_setfcap",
+    "cap_mac_override",
+    "cap_mac_admin",
+    "cap_syslog",
+    "cap_wake_alarm",
+    "cap_block_suspend",
+    "cap_audit_read",
+    "cap_perfmon",
+    "cap_bpf",
+    "cap_checkpoint_restore",
+];
+
+pub fn cap_name(cap: u32) -> String {
+    match CAP_NAMES.get(cap as usize) {
+        Some(name) => name.to_string(),
+        // Same as libcap does for capabilities it doesn't know about
+        None => cap.to_string(),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    /// 1, 2 or 3
+    pub revision: u8,
+    pub permitted: u64,
+    pub inheritable: u64,
+    /// Whether the permitted capabilities are raised into the effective set on exec
+    pub effective: bool,
+    /// Only in revision 3, the root uid of the user namespace the caps apply in
+    pub rootid: Option<u32>,
+}
+
+fn cap_bits(set: u64) -> impl Iterator<Item = u32> {
+    (0..64).filter(move |bit| set & (1 << bit) != 0)
+}
+
+impl Capabilities {
+    #[fn_error_context::context("Parsing security.capability")]
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < 4 {
+            bail!("Capability xattr of {} bytes is too short", data.len());
+        }
+
+        let magic_etc = u32_le(data, "cap_magic_etc")?;
+
+        let (revision, expected_size) = match magic_etc & VFS_CAP_REVISION_MASK {
+            VFS_CAP_REVISION_1 => (1, XATTR_CAPS_SZ_1),
+            VFS_CAP_REVISION_2 => (2, XATTR_CAPS_SZ_2),
+            VFS_CAP_REVISION_3 => (3, XATTR_CAPS_SZ_3),
+            rev => bail!("Unknown capability revision {:#x}", rev >> 24),
+        };
+
+        if data.len() != expected_size {
+            bail!(
+                "Capability revision {revision} must be {expected_size} bytes, got {}",
+                data.len()
+            );
+        }
+
+        let mut permitted = u32_le(&data[4..], "cap_permitted")? as u64;
+        let mut inheritable = u32_le(&data[8..], "cap_inheritable")? as u64;
+
+        if revision > 1 {
+            permitted |= (u32_le(&data[12..], "cap_permitted_hi")? as u64) << 32;
+            inheritable |= (u32_le(&data[16..], "cap_inheritable_hi")? as u64) << 32;
+        }
+
+        let rootid = if revision == 3 {
+            Some(u32_le(&data[20..], "cap_rootid")?)
+        } else {
+            None
+        };
+
+        Ok(Capabilities {
+            revision,
+            permitted,
+            inheritable,
+            effective: magic_etc & VFS_CAP_FLAGS_EFFECTIVE != 0,
+            rootid,
+        })
+    }
+
+    pub fn permitted_names(&self) -> Vec<String> {
+        cap_bits(self.permitted).map(cap_name).collect()
+    }
+
+    pub fn inheritable_names(&self) -> Vec<String> {
+        cap_bits(self.inheritable).map(cap_name).collect()
+    }
+}
+
+// How libcap numbers the combinations of sets a capability is in
+const LIBCAP_EFF: usize = 1;
+const LIBCAP_PER: usize = 2;
+const LIBCAP_INH: usize = 4;
+
+fn flags_str(flags: usize) -> String {
+    let mut s = String::new();
+
+    for (flag, c) in [(LIBCAP_EFF, 'e'), (LIBCAP_INH, 'i'), (LIBCAP_PER, 'p')] {
+        if flags & flag != 0 {
+            s.push(c);
+        }
+    }
+
+    s
+}
+
+impl Capabilities {
+    /// Which sets `cap` is in, as a combination of the `LIBCAP_*` flags
+    fn flags(&self, cap: u32) -> usize {
+        let bit = 1 << cap;
+        let mut flags = 0;
+
+        // The effective bit applies to everything permitted or inheritable
+        if self.effective && (self.permitted | self.inheritable) & bit != 0 {
+            flags |= LIBCAP_EFF;
+        }
+        if self.permitted & bit != 0 {
+            flags |= LIBCAP_PER;
+        }
+        if self.inheritable & bit != 0 {
+            flags |= LIBCAP_INH;
+        }
+
+        flags
+    }
+}
+
+impl Display for Capabilities {
+    /// Same textual form as `getcap`, e.g. `cap_net_admin,cap_net_raw=ep` or
+    /// `=ep cap_sys_admin-ep`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Like libcap's cap_to_text: the combination most known capabilities share is the base,
+        // written as `=flags`, and every other combination as the capabilities that have it
+        // with the flags they add to and remove from the base
+        let mut histogram = [0; 8];
+        for cap in 0..CAP_NAMES.len() as u32 {
+            histogram[self.flags(cap)] += 1;
+        }
+
+        let mut base = 7;
+        for flags in (0..7).rev() {
+            if histogram[flags] >= histogram[base] {
+                base = flags;
+            }
+        }
+
+        let known = CAP_NAMES.len() as u32;
+        let mut groups = vec![format!("={}", flags_str(base))];
+
+        for flags in (0..8).rev() {
+            let caps: Vec<String> = (0..known)
+                .filter(|cap| self.flags(*cap) == flags)
+                .map(cap_name)
+                .collect();
+
+            if flags == base || caps.is_empty() {
+                continue;
+            }
+
+            let mut group = caps.join(",");
+
+            let added = flags & !base;
+            if added != 0 {
+                // A bare `=` as the base is left out, the first group sets its flags instead
+                let op = if groups == ["="] {
+                    groups.clear();
+                    '='
+                } else {
+                    '+'
+                };
+                group.push(op);
+                group.push_str(&flags_str(added));
+            }
+
+            let removed = base & !flags;
+            if removed != 0 {
+                group.push('-');
+                group.push_str(&flags_str(removed));
+            }
+
+            groups.push(group);
+        }
+
+        // Capabilities without a name are only ever added
+        for flags in (1..8).rev() {
+            let caps: Vec<String> = (known..64)
+                .filter(|cap| self.flags(*cap) == flags)
+                .map(cap_name)
+                .collect();
+
+            if !caps.is_empty() {
+                groups.push(format!("{}+{}", caps.join(","), flags_str(flags)));
+            }
+        }
+
+        write!(f, "{}", groups.join(" "))?;
+
+        if let Some(rootid) = self.rootid
+            && rootid != 0
+        {
+            write!(f, " [rootid={rootid}]")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::hex_decode;
+
+    /// Parses a `security.capability` value written by `setcap`
+    fn parse(hex: &str) -> Capabilities {
+        Capabilities::parse(&hex_decode(hex).unwrap()).unwrap()
+    }
+
+    // The xattrs are what `setcap` wrote for each of these, the strings are what `getcap` prints
+    // for them
+
+    #[test]
+    fn revision_2() {
+        // setcap cap_net_admin,cap_net_raw+ep
+        let caps = parse("0100000200300000000000000000000000000000");
+        assert_eq!(caps.revision, 2);
+        assert!(caps.effective);
+        assert_eq!(caps.permitted, 1 << 12 | 1 << 13);
+        assert_eq!(caps.rootid, None);
+        assert_eq!(caps.permitted_names(), ["cap_net_admin", "cap_net_raw"]);
+        assert_eq!(caps.to_string(), "cap_net_admin,cap_net_raw=ep");
+
+        // setcap cap_sys_admin=eip
+        let caps = parse("0100000200002000000020000000000000000000");
+        assert_eq!(caps.inheritable_names(), ["cap_sys_admin"]);
+        assert_eq!(caps.to_string(), "cap_sys_admin=eip");
+
+        // setcap cap_setuid+p
+        assert_eq!(
+            parse("0000000280000000000000000000000000000000").to_string(),
+            "cap_setuid=p"
+        );
+    }
+
+    #[test]
+    fn high_capabilities() {
+        // setcap 'cap_chown+i cap_bpf,cap_checkpoint_restore+p'
+        let caps = parse("0000000200000000010000008001000000000000");
+        assert_eq!(caps.permitted, 1 << 39 | 1 << 40);
+        assert_eq!(
+            caps.to_string(),
+            "cap_chown=i cap_bpf,cap_checkpoint_restore+p"
+        );
+    }
+
+    #[test]
+    fn all_capabilities() {
+        // setcap =ep
+        assert_eq!(
+            parse("01000002ffffffff00000000ff01000000000000").to_string(),
+            "=ep"
+        );
+
+        // setcap '=eip cap_sys_admin-ip'
+        assert_eq!(
+            parse("01000002ffffdfffffffdfffff010000ff010000").to_string(),
+            "=eip cap_sys_admin-eip"
+        );
+
+        // setcap '=p cap_chown+i'
+        assert_eq!(
+            parse("00000002ffffffff01000000ff01000000000000").to_string(),
+            "=p cap_chown+i"
+        );
+    }
+
+    #[test]
+    fn revision_3() {
+        // setcap -n 1000 cap_net_raw+ep
+        let caps = parse("0100000300200000000000000000000000000000e8030000");
+        assert_eq!(caps.revision, 3);
+        assert_eq!(caps.rootid, Some(1000));
+        assert_eq!(caps.to_string(), "cap_net_raw=ep [rootid=1000]");
+
+        // A rootid of 0 is the same as revision 2
+        let caps = parse("010000030020000000000000000000000000000000000000");
+        assert_eq!(caps.rootid, Some(0));
+        assert_eq!(caps.to_string(), "cap_net_raw=ep");
+    }
+
+    #[test]
+    fn revision_1() {
+        // Only 32 capabilities, no high words
+        let caps = parse("010000010030000000000000");
+        assert_eq!(caps.revision, 1);
+        assert_eq!(caps.to_string(), "cap_net_admin,cap_net_raw=ep");
+    }
+
+    #[test]
+    fn unknown_capabilities() {
+        // setcap 42+p, on a kernel with 41 capabilities
+        let caps = parse("0000000200000000000000000004000000000000");
+        assert_eq!(caps.to_string(), "= 42+p");
+    }
+
+    #[test]
+    fn invalid() {
+        let invalid = [
+            "",
+            "010000",
+            // Revision 2 with a revision 1 size, and the other way around
+            "010000020030000000000000",
+            "0100000100300000000000000000000000000000",
+            // Unknown revision
+            "0100000400300000000000000000000000000000",
+        ];
+
+        for hex in invalid {
+            assert!(
+                Capabilities::parse(&hex_decode(hex).unwrap()).is_err(),
+                "{hex:?} was accepted"
+            );
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use anyhow::{Result, bail};
+
+use crate::utils::*;
+
+pub const COMPOSEFS_MAGIC: u32 = 0xd078629a;
+pub const COMPOSEFS_VERSION: u32 = 2;
+const COMPOSEFS_HEADER_VERSION: u32 = 1;
+
+/// Set in the header flags when any inode has a POSIX ACL
+pub const COMPOSEFS_FLAGS_HAS_ACL: u32 = 1;
+
+// struct lcfs_erofs_header_s {
+//     u32 magic;
+//     u32 version;
+//     u32 flags;
+//     u32 composefs_version;
+//     u32 unused[28];
+// };
+const COMPOSEFS_HEADER_SIZE: usize = 128;
+
+#[derive(Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct ComposefsHeader {
+    pub version: u32,
+    pub flags: u32,
+    pub composefs_version: u32,
+}
+
+impl ComposefsHeader {
+    pub fn new(flags: u32) -> Self {
+        ComposefsHeader {
+            version: COMPOSEFS_HEADER_VERSION,
+            flags,
+            composefs_version: COMPOSEFS_VERSION,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; COMPOSEFS_HEADER_SIZE] {
+        let mut bytes = [0; COMPOSEFS_HEADER_SIZE];
+
+        bytes[0..4].copy_from_slice(&COMPOSEFS_MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.version.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.composefs_version.to_le_bytes());
+
+        bytes
+    }
+}
+
+/// Parses the composefs header from the first 1KiB of an image. Plain EROFS images don't have
+/// one
+pub fn parse_header(mut header: &[u8]) -> Result<Option<ComposefsHeader>> {
+    if header.len() < 16 {
+        bail!("Composefs header cut short at {} bytes", header.len());
+    }
+
+    // Composefs header is u32
+    if u32_le(header, "composefs_magic")? != COMPOSEFS_MAGIC {
+        return Ok(None);
+    }
+    header = &header[4..];
+
+    // Then we have a u32 version
+    let version = u32_le(header, "composefs_header_version")?;
+    if version != COMPOSEFS_HEADER_VERSION {
+        bail!("Unsupported composefs header version {version}");
+    }
+    header = &header[4..];
+
+    // Then we have all zero flags, u32 again
+    let flags = u32_le(header, "composefs_flags")?;
+    header = &header[4..];
+
+    // Then we have composefs_version, u32 again
+    let composefs_version = u32_le(header, "composefs_version")?;
+
+    Ok(Some(ComposefsHeader {
+        version,
+        flags,
+        composefs_version,
+    }))
+}
+
+// Composefs files are overlayfs metacopy files, these point them at their backing object
+pub const OVERLAY_REDIRECT: &str = "trusted.overlay.redirect";
+pub const OVERLAY_METACOPY: &str = "trusted.overlay.metacopy";
+
+/// The files' own `trusted.overlay.*` xattrs are stored with this prefix so overlayfs doesn't
+/// act on them
+pub const OVERLAY_ESCAPED_PREFIX: &str = "trusted.overlay.overlay.";
+
+// struct ovl_metacopy {
+//     u8 version;
+//     u8 len;         /* size of this struct, 4 without a digest */
+//     u8 flags;
+//     u8 digest_algo; /* FS_VERITY_HASH_ALG_* */
+//     u8 digest[];
+// };
+const OVL_METACOPY_HEADER_SIZE: usize = 4;
+
+// The digest algorithms of fs-verity
+pub const FS_VERITY_HASH_ALG_SHA256: u8 = 1;
+pub const FS_VERITY_HASH_ALG_SHA512: u8 = 2;
+
+/// The fs-verity digest in a `trusted.overlay.metacopy` value, if it has one
+pub fn metacopy_digest(value: &[u8]) -> Result<Option<&[u8]>> {
+    if value.len() < OVL_METACOPY_HEADER_SIZE {
+        bail!("Overlay metacopy of {} bytes is cut short", value.len());
+    }
+
+    let len = value[1] as usize;
+
+    if len < OVL_METACOPY_HEADER_SIZE || len > value.len() {
+        bail!("Invalid overlay metacopy length {len}");
+    }
+
+    if len == OVL_METACOPY_HEADER_SIZE {
+        return Ok(None);
+    }
+
+    Ok(Some(&value[OVL_METACOPY_HEADER_SIZE..len]))
+}
+
+/// The `trusted.overlay.metacopy` value for a file with `digest`, or without one
+pub fn metacopy(digest: Option<&[u8]>) -> Result<Vec<u8>> {
+    let Some(digest) = digest else {
+        return Ok(vec![0, OVL_METACOPY_HEADER_SIZE as u8, 0, 0]);
+    };
+
+    let algo = match digest.len() {
+        32 => FS_VERITY_HASH_ALG_SHA256,
+        64 => FS_VERITY_HASH_ALG_SHA512,
+        len => bail!("No fs-verity digest is {len} bytes long"),
+    };
+
+    let mut value = vec![0, (OVL_METACOPY_HEADER_SIZE + digest.len()) as u8, 0, algo];
+    value.extend_from_slice(digest);
+
+    Ok(value)
+}
+
+/// Where the object with `digest` lives in a composefs object store, `xx/yyyy...`
+pub fn object_path(digest: &[u8]) -> String {
+    let hex = hex_encode(digest);
+    format!("{}/{}", &hex[..2], &hex[2..])
+}
+
+/// The name a file's own xattr is stored under, see [`OVERLAY_ESCAPED_PREFIX`]
+pub fn escape_xattr_name(name: &str) -> String {
+    match name.strip_prefix("trusted.overlay.") {
+        Some(rest) => format!("{OVERLAY_ESCAPED_PREFIX}{rest}"),
+        None => name.to_string(),
+    }
+}
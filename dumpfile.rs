@@ -0,0 +1,510 @@
+use std::io::{BufRead, Write};
+
+use anyhow::{Context, Result, bail};
+
+use crate::builder::{Content, FileData, ImageBuilder, Node, ROOT};
+use crate::composefs::*;
+use crate::image::Image;
+use crate::metadata::FileType;
+use crate::utils::*;
+use crate::walk::WalkEntry;
+
+// Same escaping flags as `print_escaped` in composefs
+const ESCAPE_STANDARD: u8 = 0;
+const ESCAPE_EQUAL: u8 = 1 << 1;
+const ESCAPE_LONE_DASH: u8 = 1 << 2;
+
+/// Escapes a field of the dump format, backslash escapes for the usual whitespace and `\xNN`
+/// for everything else that isn't printable ASCII
+fn escape(out: &mut Vec<u8>, value: &[u8], flags: u8) {
+    if flags & ESCAPE_LONE_DASH != 0 && value == b"-" {
+        out.extend_from_slice(b"\\x2d");
+        return;
+    }
+
+    for &c in value {
+        match c {
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            b'\t' => out.extend_from_slice(b"\\t"),
+            b'=' if flags & ESCAPE_EQUAL != 0 => out.extend_from_slice(b"\\x3d"),
+            c if c.is_ascii_graphic() => out.push(c),
+            c => out.extend_from_slice(format!("\\x{c:02x}").as_bytes()),
+        }
+    }
+}
+
+/// Optional fields are `-` when empty
+fn escape_optional(out: &mut Vec<u8>, value: Option<&[u8]>, flags: u8) {
+    match value {
+        Some(value) => escape(out, value, flags),
+        None => out.push(b'-'),
+    }
+}
+
+impl Image {
+    /// Writes the image in the composefs dump format, the same as `composefs-info dump`.
+    ///
+    /// One line per inode:
+    ///
+    /// ```text
+    /// PATH SIZE MODE NLINK UID GID RDEV MTIME PAYLOAD CONTENT DIGEST XATTRS...
+    /// ```
+    ///
+    /// Hardlinks after the first have a mode of `@120000` and the first path as payload. For
+    /// composefs files the payload is the backing object and the digest comes from the overlay
+    /// metacopy xattr, files with data in the image have it as content
+    #[fn_error_context::context("Writing composefs dump")]
+    pub fn to_dump<W: Write>(&self, mut writer: W) -> Result<W> {
+        for entry in self.walk() {
+            writer.write_all(&self.dump_line(&entry?)?)?;
+        }
+
+        Ok(writer)
+    }
+
+    fn dump_line(&self, entry: &WalkEntry) -> Result<Vec<u8>> {
+        let metadata = &entry.metadata;
+        let mut line = vec![];
+
+        escape(&mut line, entry.path.as_bytes(), ESCAPE_STANDARD);
+
+        if let Some(first) = &entry.hardlink_of {
+            line.extend_from_slice(b" 0 @120000 - - - - 0.0 ");
+            escape(&mut line, first.as_bytes(), ESCAPE_LONE_DASH);
+            line.extend_from_slice(b" - -\n");
+            return Ok(line);
+        }
+
+        let mut redirect = None;
+        let mut digest = None;
+        let mut xattrs = vec![];
+
+        for xattr in self.xattrs(entry.nid)? {
+            let name = xattr.full_name();
+
+            match name.as_str() {
+                OVERLAY_REDIRECT => redirect = Some(xattr.value),
+                OVERLAY_METACOPY => digest = metacopy_digest(&xattr.value)?.map(hex_encode),
+                _ => {
+                    let name = match name.strip_prefix(OVERLAY_ESCAPED_PREFIX) {
+                        Some(rest) => format!("trusted.overlay.{rest}"),
+                        None => name,
+                    };
+                    xattrs.push((name, xattr.value));
+                }
+            }
+        }
+
+        // Directory sizes are an implementation detail of the image, composefs has them as 0
+        let size = match metadata.file_type {
+            FileType::Directory => 0,
+            _ => metadata.size,
+        };
+
+        line.extend_from_slice(
+            format!(
+                " {size} {:o} {} {} {} {} {}.{} ",
+                metadata.mode,
+                metadata.nlink,
+                metadata.uid,
+                metadata.gid,
+                metadata.rdev,
+                metadata.mtime,
+                metadata.mtime_nsec
+            )
+            .as_bytes(),
+        );
+
+        let mut content = None;
+
+        let payload = match metadata.file_type {
+            FileType::Symlink => Some(self.read_link(entry.nid)?),
+            FileType::Regular => match redirect {
+                Some(redirect) => Some(redirect.strip_prefix(b"/").unwrap_or(&redirect).to_vec()),
+                None => {
+                    if metadata.size > 0 {
+                        content = Some(self.read(entry.nid)?);
+                    }
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        escape_optional(&mut line, payload.as_deref(), ESCAPE_LONE_DASH);
+        line.push(b' ');
+        escape_optional(&mut line, content.as_deref(), ESCAPE_LONE_DASH);
+        line.push(b' ');
+        line.extend_from_slice(digest.as_deref().unwrap_or("-").as_bytes());
+
+        for (name, value) in xattrs {
+            line.push(b' ');
+            escape(&mut line, name.as_bytes(), ESCAPE_EQUAL);
+            line.push(b'=');
+            escape(&mut line, &value, ESCAPE_EQUAL);
+        }
+
+        line.push(b'\n');
+
+        Ok(line)
+    }
+}
+
+/// The inverse of [`escape`]
+fn unescape(field: &[u8]) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    let mut rest = field;
+
+    while let Some((&c, tail)) = rest.split_first() {
+        rest = tail;
+
+        if c != b'\\' {
+            out.push(c);
+            continue;
+        }
+
+        let Some((&escaped, tail)) = rest.split_first() else {
+            bail!("Trailing backslash in {:?}", String::from_utf8_lossy(field));
+        };
+        rest = tail;
+
+        match escaped {
+            b'\\' => out.push(b'\\'),
+            b'n' => out.push(b'\n'),
+            b'r' => out.push(b'\r'),
+            b't' => out.push(b'\t'),
+            b'x' => {
+                let hex = rest
+                    .get(..2)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .with_context(|| {
+                        format!("Invalid \\x escape in {:?}", String::from_utf8_lossy(field))
+                    })?;
+                out.push(hex);
+                rest = &rest[2..];
+            }
+            c => bail!(
+                "Unknown escape \\{} in {:?}",
+                c as char,
+                String::from_utf8_lossy(field)
+            ),
+        }
+    }
+
+    Ok(out)
+}
+
+fn unescape_optional(field: &[u8]) -> Result<Option<Vec<u8>>> {
+    match field {
+        b"-" => Ok(None),
+        field => unescape(field).map(Some),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(field: &[u8], what: &str) -> Result<T> {
+    std::str::from_utf8(field)
+        .ok()
+        .and_then(|field| field.parse().ok())
+        .with_context(|| format!("Invalid {what} {:?}", String::from_utf8_lossy(field)))
+}
+
+/// Splits a path into the node of its parent directory and its name
+fn parent_and_name<'a>(builder: &ImageBuilder, path: &'a [u8]) -> Result<(usize, &'a [u8])> {
+    let path = path.strip_suffix(b"/").unwrap_or(path);
+
+    let Some(slash) = path.iter().rposition(|c| *c == b'/') else {
+        bail!("Path {:?} is not absolute", String::from_utf8_lossy(path));
+    };
+    let (parent, name) = (&path[..slash], &path[slash + 1..]);
+
+    let parent = builder
+        .lookup(parent)
+        .filter(|id| builder.node(*id).file_type() == FileType::Directory)
+        .with_context(|| {
+            format!(
+                "Parent directory of {:?} has to come first",
+                String::from_utf8_lossy(path)
+            )
+        })?;
+
+    if builder.lookup(path).is_some() {
+        bail!("{:?} appears twice", String::from_utf8_lossy(path));
+    }
+
+    Ok((parent, name))
+}
+
+impl ImageBuilder {
+    /// Reads a tree in the composefs dump format, as written by [`Image::to_dump`]. Entries
+    /// come after their parent directory, hardlinks after their target. Regular files are
+    /// either in the image, given by CONTENT, or external with a PAYLOAD and/or DIGEST
+    pub fn from_dump<R: BufRead>(reader: R) -> Result<Self> {
+        let mut builder = ImageBuilder::new();
+
+        for (number, line) in reader.split(b'\n').enumerate() {
+            let line = line?;
+
+            if line.is_empty() {
+                continue;
+            }
+
+            add_dump_line(&mut builder, &line).with_context(|| format!("Line {}", number + 1))?;
+        }
+
+        Ok(builder)
+    }
+}
+
+fn add_dump_line(builder: &mut ImageBuilder, line: &[u8]) -> Result<()> {
+    let fields: Vec<&[u8]> = line.split(|c| *c == b' ').collect();
+
+    let [
+        path,
+        size,
+        mode,
+        _nlink,
+        uid,
+        gid,
+        rdev,
+        mtime,
+        payload,
+        content,
+        digest,
+        xattrs @ ..,
+    ] = fields.as_slice()
+    else {
+        bail!("Expected at least 11 fields, got {}", fields.len());
+    };
+
+    let path = unescape(path)?;
+    let payload = unescape_optional(payload)?;
+
+    if let Some(mode) = mode.strip_prefix(b"@") {
+        parse_number::<u32>(mode, "mode")?;
+
+        let Some(target) = payload else {
+            bail!("Hardlink without a target");
+        };
+        let target = builder.lookup(&target).with_context(|| {
+            format!(
+                "Hardlink target {:?} has to come first",
+                String::from_utf8_lossy(&target)
+            )
+        })?;
+
+        let (parent, name) = parent_and_name(builder, &path)?;
+        return builder.link(parent, name, target);
+    }
+
+    let mode = std::str::from_utf8(mode)
+        .ok()
+        .and_then(|mode| u16::from_str_radix(mode, 8).ok())
+        .with_context(|| format!("Invalid mode {:?}", String::from_utf8_lossy(mode)))?;
+    let size: u64 = parse_number(size, "size")?;
+    let content = unescape_optional(content)?;
+
+    let digest = match std::str::from_utf8(digest).ok() {
+        Some("-") => None,
+        Some(digest) => Some(hex_decode(digest)?),
+        None => bail!("Invalid digest {:?}", String::from_utf8_lossy(digest)),
+    };
+
+    let content = match FileType::try_from(mode)? {
+        FileType::Directory => Content::Directory(Default::default()),
+
+        FileType::Regular => match content {
+            Some(content) => {
+                if content.len() as u64 != size {
+                    bail!(
+                        "Size {size} doesn't match the {} bytes of content",
+                        content.len()
+                    );
+                }
+                Content::Regular(FileData::Bytes(content))
+            }
+            None if size == 0 && payload.is_none() && digest.is_none() => {
+                Content::Regular(FileData::Bytes(vec![]))
+            }
+            None => {
+                if payload.is_none() && digest.is_none() {
+                    bail!("File of {size} bytes needs content, a payload or a digest");
+                }
+
+                let redirect = payload
+                    .map(String::from_utf8)
+                    .transpose()
+                    .context("Payload is not UTF-8")?;
+
+                Content::Regular(FileData::External {
+                    size,
+                    redirect,
+                    digest,
+                })
+            }
+        },
+
+        FileType::Symlink => match payload {
+            Some(target) => Content::Symlink(target),
+            None => bail!("Symlink without a target"),
+        },
+
+        FileType::CharDevice => Content::CharDevice(parse_number(rdev, "rdev")?),
+        FileType::BlockDevice => Content::BlockDevice(parse_number(rdev, "rdev")?),
+        FileType::Fifo => Content::Fifo,
+        FileType::Socket => Content::Socket,
+    };
+
+    let dot = mtime
+        .iter()
+        .position(|c| *c == b'.')
+        .with_context(|| format!("Invalid mtime {:?}", String::from_utf8_lossy(mtime)))?;
+    let (mtime, mtime_nsec) = (&mtime[..dot], &mtime[dot + 1..]);
+
+    let mut node = Node::new(mode, content);
+    node.uid = parse_number(uid, "uid")?;
+    node.gid = parse_number(gid, "gid")?;
+    node.mtime = parse_number(mtime, "mtime")?;
+    node.mtime_nsec = parse_number(mtime_nsec, "mtime nsec")?;
+
+    for xattr in xattrs {
+        let Some(eq) = xattr.iter().position(|c| *c == b'=') else {
+            bail!("Xattr {:?} has no value", String::from_utf8_lossy(xattr));
+        };
+
+        let name = String::from_utf8(unescape(&xattr[..eq])?).context("Xattr name is not UTF-8")?;
+        node.xattrs.insert(name, unescape(&xattr[eq + 1..])?);
+    }
+
+    if path == b"/" {
+        let Content::Directory(entries) = &builder.node(ROOT).content else {
+            unreachable!("the root is always a directory");
+        };
+
+        if node.file_type() != FileType::Directory {
+            bail!("/ has to be a directory");
+        }
+        if !entries.is_empty() {
+            bail!("/ has to come before anything in it");
+        }
+
+        *builder.node_mut(ROOT) = node;
+        return Ok(());
+    }
+
+    let (parent, name) = parent_and_name(builder, &path)?;
+    builder.insert(parent, name, node)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every line is already in the form `to_dump` writes, in walk order
+    const DUMP: &[u8] = br"/ 0 40755 2 0 0 0 1700000000.0 - - - user.dir=x
+/a\x20b\\c 5 100644 2 1000 1000 0 1700000000.123456789 - \x00\x01\n=\xff - user.eq=a\x3db\x00c user.na\x3dme=-
+/blk 0 60660 1 0 6 2049 1700000000.0 - - -
+/chr 0 20666 1 0 0 259 1700000000.0 - - -
+/dash 1 120777 1 0 0 0 1700000000.0 \x2d - -
+/ext 100000 100644 1 0 0 0 1700000000.0 ab/cdef - aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa trusted.overlay.opaque=y
+/fifo 0 10644 1 0 0 0 1700000000.0 - - -
+/hl 0 @120000 - - - - 0.0 /a\x20b\\c - -
+";
+
+    fn build(dump: &[u8]) -> Vec<u8> {
+        ImageBuilder::from_dump(dump)
+            .unwrap()
+            .composefs()
+            .write(vec![])
+            .unwrap()
+    }
+
+    fn dump(image: Vec<u8>) -> Vec<u8> {
+        Image::from_bytes(image).unwrap().to_dump(vec![]).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let image = build(DUMP);
+        let written = dump(image.clone());
+
+        assert_eq!(
+            String::from_utf8_lossy(&written),
+            String::from_utf8_lossy(DUMP)
+        );
+        assert_eq!(build(&written), image);
+    }
+
+    #[test]
+    fn parsed_contents() {
+        let image = Image::from_bytes(build(DUMP)).unwrap();
+
+        let file = image.resolve("/a b\\c", false).unwrap();
+        assert_eq!(image.read(file).unwrap(), b"\x00\x01\n=\xff");
+
+        let xattrs: Vec<_> = image
+            .xattrs(file)
+            .unwrap()
+            .into_iter()
+            .map(|xattr| (xattr.full_name(), xattr.value))
+            .collect();
+        assert!(xattrs.contains(&("user.eq".into(), b"a=b\x00c".to_vec())));
+        assert!(xattrs.contains(&("user.na=me".into(), b"-".to_vec())));
+
+        let hardlink = image.resolve("/hl", false).unwrap();
+        assert_eq!(hardlink, file);
+        assert_eq!(image.metadata(file).unwrap().nlink, 2);
+        assert_eq!(image.metadata(file).unwrap().mtime_nsec, 123456789);
+
+        let block = image
+            .metadata(image.resolve("/blk", false).unwrap())
+            .unwrap();
+        assert_eq!(block.file_type, FileType::BlockDevice);
+        assert_eq!(block.rdev, 2049);
+
+        let dash = image.resolve("/dash", false).unwrap();
+        assert_eq!(image.read_link(dash).unwrap(), b"-");
+    }
+
+    #[test]
+    fn escapes() {
+        let mut escaped = vec![];
+        escape(&mut escaped, b"a b=\\\t\x7f", ESCAPE_EQUAL);
+        assert_eq!(escaped, br"a\x20b\x3d\\\t\x7f");
+        assert_eq!(unescape(&escaped).unwrap(), b"a b=\\\t\x7f");
+
+        // Both cases of hex digits are read
+        assert_eq!(unescape(br"\xAb\xcD").unwrap(), b"\xab\xcd");
+
+        assert!(unescape(b"trailing\\").is_err());
+        assert!(unescape(br"\x4").is_err());
+        assert!(unescape(br"\xzz").is_err());
+        assert!(unescape(br"\q").is_err());
+    }
+
+    #[test]
+    fn invalid_lines() {
+        let root = "/ 0 40755 2 0 0 0 0.0 - - -\n";
+
+        for line in [
+            "/f 0 100644 1 0 0 0 0.0 - -",
+            "/f 3 100644 1 0 0 0 0.0 - ab -",
+            "/f 10 100644 1 0 0 0 0.0 - - -",
+            "/f 0 100644 1 0 0 0 0 - - -",
+            "/f 0 108644 1 0 0 0 0.0 - - -",
+            "/hl 0 @120000 - - - - 0.0 /missing - -",
+            "/d/f 0 100644 1 0 0 0 0.0 - - -",
+            "f 0 100644 1 0 0 0 0.0 - - -",
+        ] {
+            let dump = format!("{root}{line}\n");
+            assert!(
+                ImageBuilder::from_dump(dump.as_bytes()).is_err(),
+                "{line:?} was accepted"
+            );
+        }
+    }
+}
@@ -0,0 +1,421 @@
+use std::ffi::OsStr;
+use std::fs::{File, Permissions};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{PermissionsExt, symlink};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use rustix::fs::{AtFlags, CWD, Mode, Timespec, Timestamps, XattrFlags};
+use rustix::process::{Gid, Uid};
+
+use crate::image::Image;
+use crate::metadata::{FileType, Metadata};
+use crate::walk::{Visitor, WalkControl, WalkEntry};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Ownership {
+    /// Keep the uid/gid from the image when running as root, leave it to the user otherwise
+    #[default]
+    Auto,
+    /// Always set the uid/gid from the image, failing when that's not permitted
+    Numeric,
+    /// Everything is owned by whoever runs the extraction
+    None,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+    pub ownership: Ownership,
+}
+
+/// Recreates the contents of `image` below `dest`, which is created if needed.
+///
+/// Entries that can't be recreated without more privileges, like device nodes or `trusted.`
+/// xattrs as a regular user, are skipped. What was skipped is returned, one line each
+#[fn_error_context::context("Extracting to {}", dest.display())]
+pub fn extract(image: &Image, dest: &Path, options: &ExtractOptions) -> Result<Vec<String>> {
+    std::fs::create_dir_all(dest)?;
+
+    let chown = match options.ownership {
+        Ownership::Auto => rustix::process::geteuid().is_root(),
+        Ownership::Numeric => true,
+        Ownership::None => false,
+    };
+
+    let mut extractor = Extractor {
+        image,
+        dest,
+        chown,
+        skipped: vec![],
+    };
+
+    image.walk().visit(&mut extractor)?;
+
+    Ok(extractor.skipped)
+}
+
+struct Extractor<'a> {
+    image: &'a Image,
+    dest: &'a Path,
+    chown: bool,
+    skipped: Vec<String>,
+}
+
+/// Where `path` from the image ends up, refusing anything that would land outside of `dest`
+fn target_path(dest: &Path, path: &str) -> Result<PathBuf> {
+    let mut target = dest.to_path_buf();
+
+    for name in path.split('/').skip(1).filter(|_| path != "/") {
+        if name.is_empty() || name == "." || name == ".." {
+            bail!("Refusing to extract {path:?}, it escapes the destination");
+        }
+
+        target.push(name);
+    }
+
+    Ok(target)
+}
+
+/// Everything but directories is created under this name and renamed into place once it's
+/// complete, so there never is a half written file at the real path
+fn temp_path(target: &Path) -> PathBuf {
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    target.with_file_name(format!(".{name}.erofs-tmp"))
+}
+
+fn is_permission_error(err: &std::io::Error) -> bool {
+    matches!(
+        rustix::io::Errno::from_io_error(err),
+        Some(rustix::io::Errno::PERM | rustix::io::Errno::ACCESS | rustix::io::Errno::NOTSUP)
+    )
+}
+
+impl Extractor<'_> {
+    fn skip(&mut self, path: &str, reason: impl std::fmt::Display) {
+        self.skipped.push(format!("{path}: {reason}"));
+    }
+
+    fn set_owner(&self, file: &Path, metadata: &Metadata) -> Result<()> {
+        if self.chown {
+            rustix::fs::chownat(
+                CWD,
+                file,
+                Some(Uid::from_raw(metadata.uid)),
+                Some(Gid::from_raw(metadata.gid)),
+                AtFlags::SYMLINK_NOFOLLOW,
+            )
+            .with_context(|| format!("Changing owner of {}", file.display()))?;
+        }
+
+        Ok(())
+    }
+
+    fn set_permissions(&self, file: &Path, metadata: &Metadata) -> Result<()> {
+        // Symlinks don't have permissions of their own
+        if metadata.file_type != FileType::Symlink {
+            std::fs::set_permissions(file, Permissions::from_mode(metadata.permissions().into()))
+                .with_context(|| format!("Setting permissions of {}", file.display()))?;
+        }
+
+        Ok(())
+    }
+
+    fn set_mtime(&self, file: &Path, metadata: &Metadata) -> Result<()> {
+        let time = Timespec {
+            tv_sec: metadata.mtime as i64,
+            tv_nsec: metadata.mtime_nsec.into(),
+        };
+        let times = Timestamps {
+            last_access: time,
+            last_modification: time,
+        };
+
+        rustix::fs::utimensat(CWD, file, &times, AtFlags::SYMLINK_NOFOLLOW)
+            .with_context(|| format!("Setting mtime of {}", file.display()))?;
+
+        Ok(())
+    }
+
+    fn set_xattrs(&mut self, path: &str, file: &Path, nid: u64) -> Result<()> {
+        for xattr in self.image.xattrs(nid)? {
+            let name = xattr.full_name();
+
+            if self.image.is_object_xattr(&name) {
+                continue;
+            }
+
+            if let Err(e) = rustix::fs::lsetxattr(file, &name, &xattr.value, XattrFlags::empty()) {
+                let e = std::io::Error::from(e);
+
+                if !is_permission_error(&e) {
+                    return Err(e).with_context(|| format!("Setting {name} on {}", file.display()));
+                }
+
+                self.skip(path, format_args!("xattr {name}: {e}"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Creates a new inode at `file`, returning false when it had to be skipped
+    fn create(&mut self, entry: &WalkEntry, file: &Path) -> Result<bool> {
+        let metadata = &entry.metadata;
+        let mode = Mode::from_raw_mode(metadata.permissions().into());
+
+        let result = match metadata.file_type {
+            FileType::Regular => {
+                let mut out =
+                    File::create(file).with_context(|| format!("Creating {}", file.display()))?;
+                self.image
+                    .copy_to(entry.nid, &mut out)
+                    .with_context(|| format!("Writing {}", file.display()))?;
+                return Ok(true);
+            }
+
+            FileType::Symlink => {
+                let link = self.image.read_link(entry.nid)?;
+                let link = OsStr::from_bytes(&link);
+                return symlink(link, file)
+                    .with_context(|| format!("Creating {}", file.display()))
+                    .map(|_| true);
+            }
+
+            FileType::CharDevice => rustix::fs::mknodat(
+                CWD,
+                file,
+                rustix::fs::FileType::CharacterDevice,
+                mode,
+                metadata.rdev,
+            ),
+            FileType::BlockDevice => rustix::fs::mknodat(
+                CWD,
+                file,
+                rustix::fs::FileType::BlockDevice,
+                mode,
+                metadata.rdev,
+            ),
+            FileType::Fifo => rustix::fs::mknodat(CWD, file, rustix::fs::FileType::Fifo, mode, 0),
+            FileType::Socket => {
+                rustix::fs::mknodat(CWD, file, rustix::fs::FileType::Socket, mode, 0)
+            }
+
+            FileType::Directory => unreachable!("directories are created in place"),
+        };
+
+        match result.map_err(std::io::Error::from) {
+            Ok(()) => Ok(true),
+            Err(e) if is_permission_error(&e) => {
+                self.skip(&entry.path, format_args!("{:?}: {e}", metadata.file_type));
+                Ok(false)
+            }
+            Err(e) => Err(e).with_context(|| format!("Creating {}", file.display())),
+        }
+    }
+
+    fn visit_dir(&mut self, entry: &WalkEntry, target: &Path) -> Result<()> {
+        match std::fs::symlink_metadata(target) {
+            // Reusing an existing directory is fine, a symlink to one could point anywhere
+            Ok(existing) if existing.is_dir() => {}
+            Ok(..) => bail!(
+                "Refusing to extract {}, {} exists and is not a directory",
+                entry.path,
+                target.display()
+            ),
+            Err(..) => std::fs::create_dir(target)
+                .with_context(|| format!("Creating {}", target.display()))?,
+        }
+
+        // Permissions and mtime are set once all children exist, see post_dir
+        self.set_owner(target, &entry.metadata)?;
+        self.set_xattrs(&entry.path, target, entry.nid)
+    }
+
+    fn visit_other(&mut self, entry: &WalkEntry, target: &Path) -> Result<()> {
+        let temp = temp_path(target);
+
+        if std::fs::symlink_metadata(&temp).is_ok() {
+            std::fs::remove_file(&temp)
+                .with_context(|| format!("Removing leftover {}", temp.display()))?;
+        }
+
+        if let Some(first) = &entry.hardlink_of {
+            let first = target_path(self.dest, first)?;
+
+            if let Err(e) = std::fs::hard_link(&first, &temp) {
+                self.skip(
+                    &entry.path,
+                    format_args!("hardlink to {}: {e}", first.display()),
+                );
+                return Ok(());
+            }
+        } else {
+            if !self.create(entry, &temp)? {
+                return Ok(());
+            }
+
+            // chown drops setuid bits and capabilities, so it goes first. Without root a
+            // read-only file can't get user xattrs, so they go before the permissions
+            self.set_owner(&temp, &entry.metadata)?;
+            self.set_xattrs(&entry.path, &temp, entry.nid)?;
+            self.set_permissions(&temp, &entry.metadata)?;
+            self.set_mtime(&temp, &entry.metadata)?;
+        }
+
+        std::fs::rename(&temp, target)
+            .with_context(|| format!("Renaming {} to {}", temp.display(), target.display()))
+    }
+}
+
+impl Visitor for Extractor<'_> {
+    fn visit(&mut self, entry: &WalkEntry) -> Result<WalkControl> {
+        let target = target_path(self.dest, &entry.path)?;
+
+        if entry.metadata.file_type == FileType::Directory {
+            self.visit_dir(entry, &target)?;
+        } else {
+            self.visit_other(entry, &target)?;
+        }
+
+        Ok(WalkControl::Continue)
+    }
+
+    fn post_dir(&mut self, entry: &WalkEntry) -> Result<()> {
+        let target = target_path(self.dest, &entry.path)?;
+
+        self.set_permissions(&target, &entry.metadata)?;
+        self.set_mtime(&target, &entry.metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::builder::{Content, FileData, ImageBuilder, Node, ROOT};
+
+    fn dest(name: &str) -> PathBuf {
+        let dest =
+            std::env::temp_dir().join(format!("erofs-extract-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dest);
+        dest
+    }
+
+    /// Owned by whoever runs the test, so the owner survives extracting without root
+    fn node(permissions: u16, content: Content) -> Node {
+        let mut node = Node::new(permissions, content);
+        node.uid = rustix::process::geteuid().as_raw();
+        node.gid = rustix::process::getegid().as_raw();
+        node.mtime = 1_700_000_000;
+        node.mtime_nsec = 123_456_789;
+        node
+    }
+
+    fn dump(builder: ImageBuilder) -> String {
+        let image = Image::from_bytes(builder.build_time(0, 0).write(vec![]).unwrap()).unwrap();
+        String::from_utf8(image.to_dump(vec![]).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut builder = ImageBuilder::new();
+        *builder.node_mut(ROOT) = node(0o755, Content::Directory(BTreeMap::new()));
+
+        let dir = builder
+            .insert(
+                ROOT,
+                b"dir",
+                node(0o750, Content::Directory(BTreeMap::new())),
+            )
+            .unwrap();
+        let large: Vec<u8> = (0..20000u32).map(|n| (n % 251) as u8).collect();
+        let file = builder
+            .insert(
+                dir,
+                b"large",
+                node(0o644, Content::Regular(FileData::Bytes(large))),
+            )
+            .unwrap();
+        builder.link(ROOT, b"hardlink", file).unwrap();
+
+        // Read-only, with an xattr that has to be set before that
+        let mut readonly = node(0o444, Content::Regular(FileData::Bytes(b"ro".to_vec())));
+        readonly
+            .xattrs
+            .insert("user.comment".into(), b"read only".to_vec());
+        builder.insert(ROOT, b"readonly", readonly).unwrap();
+
+        let mut empty = node(0o500, Content::Directory(BTreeMap::new()));
+        empty.xattrs.insert("user.dir".into(), b"empty".to_vec());
+        builder.insert(dir, b"empty", empty).unwrap();
+
+        let link = node(0o777, Content::Symlink(b"../readonly".to_vec()));
+        builder.insert(dir, b"link", link).unwrap();
+        builder
+            .insert(ROOT, b"fifo", node(0o600, Content::Fifo))
+            .unwrap();
+
+        let image = Image::from_bytes(builder.write(vec![]).unwrap()).unwrap();
+        let dest = dest("round-trip");
+        let options = ExtractOptions {
+            ownership: Ownership::Auto,
+        };
+
+        assert!(extract(&image, &dest, &options).unwrap().is_empty());
+        assert_eq!(dump(ImageBuilder::from_dir(&dest).unwrap()), dump(builder));
+
+        // Nothing half written left behind
+        assert!(!dest.join(".hardlink.erofs-tmp").exists());
+
+        std::fs::set_permissions(dest.join("dir/empty"), Permissions::from_mode(0o700)).unwrap();
+        std::fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn escaping_paths() {
+        let dest = Path::new("/dest");
+
+        assert_eq!(target_path(dest, "/").unwrap(), dest);
+        assert_eq!(target_path(dest, "/a/b").unwrap(), dest.join("a/b"));
+
+        for path in ["/..", "/a/../../etc", "/./a", "/a/.", "//a", "/a//b", "/a/"] {
+            assert!(target_path(dest, path).is_err(), "{path}");
+        }
+    }
+
+    #[test]
+    fn escaping_names() {
+        let mut builder = ImageBuilder::new();
+        builder
+            .insert(ROOT, b"file", Node::new(0o644, Content::Fifo))
+            .unwrap();
+        builder
+            .insert(
+                ROOT,
+                b"dir",
+                Node::new(0o755, Content::Directory(BTreeMap::new())),
+            )
+            .unwrap();
+        let mut data = builder.write(vec![]).unwrap();
+
+        // A name with a slash in it, which the walk joins into "/../x"
+        let name = data.windows(4).position(|w| w == b"file").unwrap();
+        data[name..name + 4].copy_from_slice(b"../x");
+        let image = Image::from_bytes(data).unwrap();
+        assert_eq!(image.walk().nth(2).unwrap().unwrap().path, "/../x");
+
+        let dest = dest("escaping");
+        let error = extract(&image, &dest.join("inner"), &ExtractOptions::default())
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            format!("{:#}", error.root_cause()),
+            "Refusing to extract \"/../x\", it escapes the destination"
+        );
+        assert!(!dest.join("x").exists());
+
+        std::fs::remove_dir_all(&dest).unwrap();
+    }
+}
@@ -0,0 +1,1072 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::ops::Range;
+
+use anyhow::Result;
+
+use crate::image::{Image, SUPERBLOCK_OFFSET};
+use crate::inode::*;
+use crate::metadata::FileType;
+use crate::sb::{self, *};
+use crate::walk::{Visitor, WalkControl, WalkEntry};
+
+// struct erofs_xattr_ibody_header {
+//     __le32 h_name_filter;
+//     __u8   h_shared_count;
+//     __u8   h_reserved2[7];
+//     __le32 h_shared_xattrs[0];
+// };
+const XATTR_IBODY_HEADER_SIZE: usize = 12;
+// struct erofs_xattr_entry {
+//     __u8   e_name_len;
+//     __u8   e_name_index;
+//     __le16 e_value_size;
+// };
+const XATTR_ENTRY_SIZE: usize = 4;
+
+// Set in the name index when the name starts with one of the long prefixes in the superblock
+const EROFS_XATTR_LONG_PREFIX: u8 = 0x80;
+
+#[derive(Debug, Clone)]
+pub struct Problem {
+    /// Where in the tree, for problems with an inode
+    pub path: Option<String>,
+    pub message: String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{path}: {}", self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// What [`check`] found
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+    /// Compressed files, their inode and xattrs are checked but not their data. They aren't
+    /// problems with the image
+    pub skipped: Vec<String>,
+}
+
+/// Checks the whole image, reporting everything that's wrong with it instead of stopping at
+/// the first problem. Only what's reachable from the root is looked at.
+///
+/// Compressed data is out of scope: there's no reader for the compressed indexes or any of the
+/// algorithms, so whether the pclusters decompress isn't checked, and neither are the blocks
+/// they take up. Such files end up in [`Report::skipped`]
+pub fn check(image: &Image) -> Report {
+    let mut checker = Checker {
+        image,
+        problems: vec![],
+        skipped: vec![],
+        data_end: image.superblock.blocks as u64 * image.block_size() as u64,
+        dirs: HashMap::new(),
+        links: HashMap::new(),
+    };
+
+    checker.check_superblock();
+
+    // The checker never fails the walk, errors are recorded as problems
+    let _ = image.walk().visit(&mut checker);
+
+    checker.check_nlinks();
+
+    Report {
+        problems: checker.problems,
+        skipped: checker.skipped,
+    }
+}
+
+struct Checker<'a> {
+    image: &'a Image,
+    problems: Vec<Problem>,
+    skipped: Vec<String>,
+    /// End of the last block the superblock says the image has
+    data_end: u64,
+    /// Nids of the directories seen so far, for checking ".."
+    dirs: HashMap<String, u64>,
+    /// For every non-directory nid, its first path, its nlink and how many dirents point to it
+    links: HashMap<u64, (String, u32, u32)>,
+}
+
+/// The directory `path` is in
+fn parent(path: &str) -> &str {
+    match path.rsplit_once('/') {
+        Some(("", _)) | None => "/",
+        Some((parent, _)) => parent,
+    }
+}
+
+fn join_path(parent: &str, name: &str) -> String {
+    if parent.ends_with('/') {
+        format!("{parent}{name}")
+    } else {
+        format!("{parent}/{name}")
+    }
+}
+
+impl Checker<'_> {
+    fn report(&mut self, path: Option<&str>, message: impl Into<String>) {
+        self.problems.push(Problem {
+            path: path.map(String::from),
+            message: message.into(),
+        });
+    }
+
+    fn check_superblock(&mut self) {
+        let sb = &self.image.superblock;
+        let block_size = self.image.block_size();
+
+        if sb.feature_compat & FEATURE_COMPAT_SB_CHKSUM != 0 {
+            let end = block_size.max(SUPERBLOCK_OFFSET + 128);
+
+            match self.image.data.get(SUPERBLOCK_OFFSET..end) {
+                Some(block) => {
+                    let expected = sb::checksum(block);
+
+                    if expected != sb.checksum {
+                        self.report(
+                            None,
+                            format!(
+                                "superblock: checksum is {:#010x}, should be {expected:#010x}",
+                                sb.checksum
+                            ),
+                        );
+                    }
+                }
+                None => self.report(None, "superblock: block is cut short"),
+            }
+        }
+
+        let unsupported = sb.feature_incompat & !FEATURE_INCOMPAT_SUPPORTED;
+
+        if unsupported != 0 {
+            self.report(
+                None,
+                format!("superblock: unsupported incompat features {unsupported:#x}"),
+            );
+        }
+
+        if self.data_end > self.image.data.len() as u64 {
+            self.report(
+                None,
+                format!(
+                    "superblock: {} blocks don't fit in an image of {} bytes",
+                    sb.blocks,
+                    self.image.data.len()
+                ),
+            );
+        }
+
+        if sb.meta_blkaddr >= sb.blocks {
+            self.report(
+                None,
+                format!(
+                    "superblock: meta_blkaddr {} is past the last block",
+                    sb.meta_blkaddr
+                ),
+            );
+        }
+
+        if sb.xattr_blkaddr >= sb.blocks {
+            self.report(
+                None,
+                format!(
+                    "superblock: xattr_blkaddr {} is past the last block",
+                    sb.xattr_blkaddr
+                ),
+            );
+        }
+
+        match self.image.metadata(self.image.root_nid()) {
+            Ok(root) if root.file_type != FileType::Directory => {
+                self.report(None, "superblock: root inode is not a directory")
+            }
+            _ => {}
+        }
+    }
+
+    /// The inode and its xattrs have to be in the image, and not on top of the superblock
+    fn check_inode_location(&mut self, path: &str, nid: u64, inode: &Inode) {
+        let start = match self.image.inode_offset(nid) {
+            Ok(start) => start as u64,
+            Err(e) => {
+                self.report(Some(path), format!("{e:#}"));
+                return;
+            }
+        };
+        let end = start + inode.header_size() as u64 + xattr_area_size(inode) as u64;
+
+        let sb_start = SUPERBLOCK_OFFSET as u64;
+        let sb_end = sb_start + size_of::<Superblock>() as u64;
+
+        if start < sb_end && end > sb_start {
+            self.report(
+                Some(path),
+                format!("inode at {start} overlaps the superblock"),
+            );
+        }
+
+        if end > self.data_end {
+            self.report(
+                Some(path),
+                format!("inode at {start}..{end} is past the last block"),
+            );
+        }
+    }
+
+    /// Bounds of the xattr area and every entry in it, returning whether they're sane enough to
+    /// parse
+    fn check_xattrs(&mut self, path: &str, inode: &Inode, inode_data: &[u8]) -> bool {
+        let size = xattr_area_size(inode);
+
+        if size == 0 {
+            return true;
+        }
+
+        let header_size = inode.header_size();
+
+        let Some(area) = inode_data.get(header_size..header_size + size) else {
+            self.report(
+                Some(path),
+                format!("xattrs of {size} bytes run past the end of the image"),
+            );
+            return false;
+        };
+
+        let shared_count = area[4] as usize;
+        let inline_start = XATTR_IBODY_HEADER_SIZE + shared_count * 4;
+
+        if inline_start > size {
+            self.report(
+                Some(path),
+                format!("{shared_count} shared xattrs don't fit in {size} bytes of xattrs"),
+            );
+            return false;
+        }
+
+        let shared_start =
+            self.image.superblock.xattr_blkaddr as u64 * self.image.block_size() as u64;
+
+        for id in area[XATTR_IBODY_HEADER_SIZE..inline_start].chunks_exact(4) {
+            let id = u32::from_le_bytes(id.try_into().unwrap());
+            let offset = shared_start + id as u64 * 4;
+
+            if offset + XATTR_ENTRY_SIZE as u64 > self.data_end {
+                self.report(
+                    Some(path),
+                    format!("shared xattr {id} at {offset} is past the last block"),
+                );
+                return false;
+            }
+        }
+
+        let mut entries = &area[inline_start..];
+        let mut ok = true;
+
+        while !entries.is_empty() {
+            if entries.len() < XATTR_ENTRY_SIZE {
+                self.report(
+                    Some(path),
+                    format!("{} stray bytes after the last xattr", entries.len()),
+                );
+                return false;
+            }
+
+            let name_len = entries[0] as usize;
+            let name_index = entries[1];
+            let value_size = u16::from_le_bytes([entries[2], entries[3]]) as usize;
+
+            let len = XATTR_ENTRY_SIZE + name_len + value_size;
+
+            if len > entries.len() {
+                self.report(
+                    Some(path),
+                    format!(
+                        "xattr entry of {len} bytes runs past the end of the xattr area, {} bytes are left",
+                        entries.len()
+                    ),
+                );
+                return false;
+            }
+
+            if name_index & EROFS_XATTR_LONG_PREFIX != 0 {
+                self.report(
+                    Some(path),
+                    "xattr with a long name prefix, which is unsupported",
+                );
+                ok = false;
+            } else if name_index > EROFS_XATTR_INDEX_SECURITY {
+                self.report(
+                    Some(path),
+                    format!("xattr with unknown name index {name_index}"),
+                );
+                ok = false;
+            }
+
+            entries = &entries[len.next_multiple_of(4).min(entries.len())..];
+        }
+
+        ok
+    }
+
+    /// Whether the data is where it can be read, only then is it read
+    fn check_data(&mut self, path: &str, nid: u64, inode: &Inode) -> bool {
+        let block_size = self.image.block_size() as u64;
+
+        match inode.data_layout() {
+            Ok(layout) if layout.is_compressed() => {
+                self.skipped.push(path.to_string());
+                return false;
+            }
+            Err(e) => {
+                self.report(Some(path), format!("{e}"));
+                return false;
+            }
+            Ok(..) => {}
+        }
+
+        let extents = match self.image.extents(nid) {
+            Ok(extents) => extents,
+            Err(e) => {
+                self.report(Some(path), format!("{e:#}"));
+                return false;
+            }
+        };
+
+        let mut ok = true;
+
+        for extent in extents {
+            let end = extent.physical.saturating_add(extent.len);
+
+            match extent.kind {
+                ExtentKind::Plain if end > self.data_end => {
+                    self.report(
+                        Some(path),
+                        format!(
+                            "data at {}..{end} is past the last block at {}",
+                            extent.physical, self.data_end
+                        ),
+                    );
+                    ok = false;
+                }
+
+                // The kernel can only read inline data that's in the same block as the inode
+                ExtentKind::Inline if extent.physical % block_size + extent.len > block_size => {
+                    self.report(
+                        Some(path),
+                        format!(
+                            "inline data at {}..{end} crosses a block boundary",
+                            extent.physical
+                        ),
+                    );
+                    ok = false;
+                }
+
+                _ => {}
+            }
+        }
+
+        ok
+    }
+
+    fn check_dir(&mut self, entry: &WalkEntry) {
+        let path = entry.path.as_str();
+
+        // If the directory can't be read the walk reports it
+        let Ok(dirents) = self.image.read_dir(entry.nid) else {
+            return;
+        };
+
+        for pair in dirents.windows(2) {
+            if pair[0].name.as_bytes() >= pair[1].name.as_bytes() {
+                self.report(
+                    Some(path),
+                    format!(
+                        "entries are not sorted, {:?} comes before {:?}",
+                        pair[0].name, pair[1].name
+                    ),
+                );
+                break;
+            }
+        }
+
+        let parent_nid = match path {
+            "/" => Some(entry.nid),
+            _ => self.dirs.get(parent(path)).copied(),
+        };
+
+        let mut subdirs = 0;
+
+        for (name, expected) in [(".", Some(entry.nid)), ("..", parent_nid)] {
+            match dirents.iter().find(|d| d.name == name) {
+                None => self.report(Some(path), format!("no {name:?} entry")),
+                Some(d) => {
+                    let nid = d.dirent.nid;
+
+                    if let Some(expected) = expected
+                        && nid != expected
+                    {
+                        self.report(
+                            Some(path),
+                            format!("{name:?} points at nid {nid} instead of {expected}"),
+                        );
+                    }
+                }
+            }
+        }
+
+        for dirent in &dirents {
+            let name = dirent.name.as_str();
+            let nid = dirent.dirent.nid;
+            let file_type = dirent.dirent.file_type;
+
+            if name.is_empty() || name.contains(['/', '\0']) {
+                self.report(Some(path), format!("invalid name {name:?}"));
+            }
+
+            // Anything broken about the inode itself is reported when the walk gets to it
+            let Ok(metadata) = self.image.metadata(nid) else {
+                continue;
+            };
+
+            if name != "." && name != ".." && metadata.file_type == FileType::Directory {
+                subdirs += 1;
+            }
+
+            let expected = metadata.file_type.dirent_type();
+
+            if file_type != expected && file_type != EROFS_FT_UNKNOWN {
+                self.report(
+                    Some(&join_path(path, name)),
+                    format!(
+                        "dirent file type {file_type} doesn't match the inode's {:?}",
+                        metadata.file_type
+                    ),
+                );
+            }
+        }
+
+        // Every subdirectory has a ".." pointing here, plus "." and the entry in the parent
+        let expected = 2 + subdirs;
+
+        if entry.metadata.nlink != expected {
+            self.report(
+                Some(path),
+                format!(
+                    "nlink is {}, should be {expected} for {subdirs} subdirectories",
+                    entry.metadata.nlink
+                ),
+            );
+        }
+    }
+
+    fn check_nlinks(&mut self) {
+        let mut links: Vec<_> = std::mem::take(&mut self.links).into_values().collect();
+        links.sort();
+
+        for (path, nlink, seen) in links {
+            if nlink != seen {
+                self.report(
+                    Some(&path),
+                    format!("nlink is {nlink}, but {seen} entries point to it"),
+                );
+            }
+        }
+    }
+
+    fn check_entry(&mut self, entry: &WalkEntry) -> Result<()> {
+        let path = entry.path.as_str();
+        let nid = entry.nid;
+
+        if entry.metadata.file_type != FileType::Directory {
+            self.links
+                .entry(nid)
+                .or_insert_with(|| (entry.path.clone(), entry.metadata.nlink, 0))
+                .2 += 1;
+        }
+
+        // Everything else is the same as for the first path
+        if entry.hardlink_of.is_some() {
+            return Ok(());
+        }
+
+        let inode = self.image.inode(nid)?;
+        let inode_data = self.image.inode_data(nid)?;
+
+        self.check_inode_location(path, nid, &inode);
+
+        if self.check_xattrs(path, &inode, inode_data)
+            && let Err(e) = self.image.xattrs(nid)
+        {
+            self.report(Some(path), format!("{e:#}"));
+        }
+
+        let data_ok = self.check_data(path, nid, &inode);
+
+        if entry.metadata.file_type == FileType::Directory {
+            self.dirs.insert(entry.path.clone(), nid);
+        }
+
+        // What's wrong with the data is reported already
+        if !data_ok {
+            return Ok(());
+        }
+
+        match entry.metadata.file_type {
+            FileType::Directory => self.check_dir(entry),
+            FileType::Regular => self.image.copy_to(nid, &mut std::io::sink())?,
+            FileType::Symlink => {
+                self.image.read_link(nid)?;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+fn xattr_area_size(inode: &Inode) -> usize {
+    match inode.xattr_count() {
+        0 => 0,
+        icount => (icount as usize - 1) * 4 + XATTR_IBODY_HEADER_SIZE,
+    }
+}
+
+impl Visitor for Checker<'_> {
+    fn visit(&mut self, entry: &WalkEntry) -> Result<WalkControl> {
+        if let Err(e) = self.check_entry(entry) {
+            self.report(Some(&entry.path), format!("{e:#}"));
+        }
+
+        Ok(WalkControl::Continue)
+    }
+
+    fn error(&mut self, err: anyhow::Error) -> Result<()> {
+        self.report(None, format!("{err:#}"));
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum BlockUse {
+    Unused,
+    /// Superblock, inodes, xattrs, inline data
+    Metadata,
+    /// File data of the inode at this path
+    Data(String),
+}
+
+struct Scanner<'a> {
+    image: &'a Image,
+    problems: Vec<Problem>,
+    blocks: Vec<BlockUse>,
+    /// Byte ranges of the metadata area known to belong to an inode or shared xattr
+    covered: Vec<Range<u64>>,
+    /// Whether a reachable inode is compressed, its blocks aren't known then
+    compressed: bool,
+}
+
+/// Goes over the image as a whole instead of the tree: every inode slot in the metadata area,
+/// looking for inodes no directory points to, and every block, looking for blocks used twice or
+/// not at all. Unused blocks are only reported for images without compressed files, whose blocks
+/// aren't known
+pub fn scan(image: &Image) -> Vec<Problem> {
+    let block_size = image.block_size() as u64;
+    let blocks = (image.superblock.blocks as u64).min(image.data.len() as u64 / block_size);
+
+    let mut scanner = Scanner {
+        image,
+        problems: vec![],
+        blocks: vec![BlockUse::Unused; blocks as usize],
+        covered: vec![],
+        compressed: false,
+    };
+
+    // The superblock and whatever comes before it
+    scanner.mark_metadata(0..(SUPERBLOCK_OFFSET + size_of::<Superblock>()) as u64);
+
+    let mut reachable: Vec<(u64, String)> = image
+        .walk()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.hardlink_of.is_none())
+        .map(|entry| (entry.nid, entry.path))
+        .collect();
+    reachable.sort();
+
+    // Metadata first, so data running into it is reported as such
+    for (nid, path) in &reachable {
+        scanner.mark_inode_metadata(*nid, path);
+    }
+
+    for (nid, path) in &reachable {
+        scanner.mark_inode_data(*nid, path);
+    }
+
+    scanner.scan_slots(reachable.iter().map(|(nid, _)| *nid).collect());
+
+    // The blocks of compressed files would look unused
+    if !scanner.compressed {
+        scanner.report_unused();
+    }
+
+    scanner.problems
+}
+
+fn plausible_inode(inode: &Inode) -> bool {
+    // Only the version and data layout bits are used
+    inode.format() >> 4 == 0
+        && inode.data_layout().is_ok()
+        && FileType::try_from(inode.mode()).is_ok()
+        && inode.nlink() > 0
+}
+
+impl Scanner<'_> {
+    fn report(&mut self, path: Option<&str>, message: impl Into<String>) {
+        self.problems.push(Problem {
+            path: path.map(String::from),
+            message: message.into(),
+        });
+    }
+
+    fn block_range(&self, range: &Range<u64>) -> Range<usize> {
+        let block_size = self.image.block_size() as u64;
+        let start = (range.start / block_size) as usize;
+        let end = (range.end.div_ceil(block_size) as usize).min(self.blocks.len());
+
+        start.min(end)..end
+    }
+
+    fn mark_metadata(&mut self, range: Range<u64>) {
+        for block in self.block_range(&range) {
+            if let BlockUse::Data(owner) = &self.blocks[block] {
+                let owner = owner.clone();
+                self.report(
+                    Some(&owner),
+                    format!("data block {block} is also used for metadata"),
+                );
+            }
+
+            self.blocks[block] = BlockUse::Metadata;
+        }
+
+        self.covered.push(range);
+    }
+
+    fn mark_data(&mut self, path: &str, range: Range<u64>) {
+        for block in self.block_range(&range) {
+            match &self.blocks[block] {
+                BlockUse::Unused => {}
+                BlockUse::Metadata => self.report(
+                    Some(path),
+                    format!("data block {block} is also used for metadata"),
+                ),
+                BlockUse::Data(owner) if owner != path => {
+                    let message = format!("data block {block} is also used by {owner}");
+                    self.report(Some(path), message);
+                }
+                BlockUse::Data(..) => {}
+            }
+
+            self.blocks[block] = BlockUse::Data(path.to_string());
+        }
+    }
+
+    /// Marks the inode itself, its inline data or chunk indexes and its shared xattrs
+    fn mark_inode_metadata(&mut self, nid: u64, path: &str) {
+        let (Ok(inode), Ok(inode_data)) = (self.image.inode(nid), self.image.inode_data(nid))
+        else {
+            return;
+        };
+
+        let Ok(start) = self.image.inode_offset(nid) else {
+            return;
+        };
+        let start = start as u64;
+        let size = inode
+            .meta_size(inode_data, &self.image.superblock)
+            .unwrap_or(inode.header_size());
+
+        self.mark_metadata(start..start + size as u64);
+
+        let Some(xattrs) = inode.get_xattrs(inode_data) else {
+            return;
+        };
+
+        let shared_start =
+            self.image.superblock.xattr_blkaddr as u64 * self.image.block_size() as u64;
+
+        for id in xattrs.shared_ids() {
+            let offset = shared_start + id as u64 * 4;
+
+            let Some(entry) = self.image.data.get(offset as usize..offset as usize + 4) else {
+                self.report(
+                    Some(path),
+                    format!("shared xattr {id} is past the end of the image"),
+                );
+                continue;
+            };
+
+            let len = XATTR_ENTRY_SIZE as u64
+                + entry[0] as u64
+                + u16::from_le_bytes([entry[2], entry[3]]) as u64;
+
+            self.mark_metadata(offset..offset + len.next_multiple_of(4));
+        }
+    }
+
+    fn mark_inode_data(&mut self, nid: u64, path: &str) {
+        if let Ok(inode) = self.image.inode(nid)
+            && inode
+                .data_layout()
+                .is_ok_and(InodeDataLayout::is_compressed)
+        {
+            self.compressed = true;
+            return;
+        }
+
+        let Ok(extents) = self.image.extents(nid) else {
+            return;
+        };
+
+        for extent in extents {
+            if extent.kind == ExtentKind::Plain {
+                self.mark_data(path, extent.physical..extent.physical + extent.len);
+            }
+        }
+    }
+
+    /// Goes through the metadata area 32 bytes at a time, skipping over what's known, and
+    /// reports anything that looks like an inode but isn't reachable
+    fn scan_slots(&mut self, reachable: HashSet<u64>) {
+        let block_size = self.image.block_size() as u64;
+        let meta_start = self.image.superblock.meta_blkaddr as u64 * block_size;
+        let end = self.blocks.len() as u64 * block_size;
+
+        self.covered.sort_by_key(|range| range.start);
+        let covered = std::mem::take(&mut self.covered);
+        let mut next_covered = 0;
+
+        let mut offset = meta_start.max((SUPERBLOCK_OFFSET + size_of::<Superblock>()) as u64);
+
+        while offset < end {
+            let block = (offset / block_size) as usize;
+
+            if let BlockUse::Data(..) = self.blocks[block] {
+                offset = (block as u64 + 1) * block_size;
+                continue;
+            }
+
+            // The offset only goes up, so ranges that end before it are done with
+            while covered
+                .get(next_covered)
+                .is_some_and(|range| range.end <= offset)
+            {
+                next_covered += 1;
+            }
+
+            if let Some(range) = covered.get(next_covered)
+                && range.start <= offset
+            {
+                offset = range.end.next_multiple_of(32);
+                continue;
+            }
+
+            let nid = (offset - meta_start) / 32;
+
+            match self.orphan_size(nid, &reachable) {
+                Some(size) => {
+                    let path = format!("orphan nid {nid}");
+
+                    self.report(
+                        None,
+                        format!("nid {nid} at offset {offset} looks like an inode but no directory points to it"),
+                    );
+
+                    self.mark_metadata(offset..offset + size);
+                    self.mark_inode_data(nid, &path);
+
+                    offset = (offset + size).next_multiple_of(32);
+                }
+                None => offset += 32,
+            }
+        }
+    }
+
+    /// How much of the metadata area the inode at `nid` takes up, if there's one
+    fn orphan_size(&self, nid: u64, reachable: &HashSet<u64>) -> Option<u64> {
+        if reachable.contains(&nid) {
+            return None;
+        }
+
+        let inode = self.image.inode(nid).ok()?;
+        let inode_data = self.image.inode_data(nid).ok()?;
+
+        if !plausible_inode(&inode) {
+            return None;
+        }
+
+        let size = inode.meta_size(inode_data, &self.image.superblock).ok()?;
+
+        Some(size as u64)
+    }
+
+    fn report_unused(&mut self) {
+        let mut block = 0;
+
+        while block < self.blocks.len() {
+            if self.blocks[block] != BlockUse::Unused {
+                block += 1;
+                continue;
+            }
+
+            let start = block;
+
+            while block < self.blocks.len() && self.blocks[block] == BlockUse::Unused {
+                block += 1;
+            }
+
+            let message = match block - start {
+                1 => format!("block {start} is not used by anything"),
+                _ => format!("blocks {start}..{block} are not used by anything"),
+            };
+
+            self.report(None, message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::builder::{Content, FileData, ImageBuilder, Node, ROOT};
+    use crate::utils::u16_le;
+
+    const BLOCK_SIZE: usize = 4096;
+
+    fn file(contents: &[u8]) -> Node {
+        Node::new(0o644, Content::Regular(FileData::Bytes(contents.to_vec())))
+    }
+
+    /// An image with every name in `files` in the root, and `dir` as an empty directory
+    fn builder(files: &[(&str, &[u8])]) -> ImageBuilder {
+        let mut builder = ImageBuilder::new().build_time(0, 0);
+
+        for (name, contents) in files {
+            let node = match *name {
+                "dir" => Node::new(0o755, Content::Directory(BTreeMap::new())),
+                _ => file(contents),
+            };
+
+            builder.insert(ROOT, name.as_bytes(), node).unwrap();
+        }
+
+        builder
+    }
+
+    /// The image `builder` writes, with `corrupt` applied to its bytes. The superblock checksum
+    /// is fixed up afterwards, so only the corruption is reported
+    fn corrupt(builder: &ImageBuilder, corrupt: impl FnOnce(&Image, &mut [u8])) -> Image {
+        let image = Image::from_bytes(builder.write(vec![]).unwrap()).unwrap();
+        assert!(messages(check(&image).problems).is_empty());
+        assert!(messages(scan(&image)).is_empty());
+
+        let mut data = image.data.clone();
+        corrupt(&image, &mut data);
+
+        let checksum = sb::checksum(&data[SUPERBLOCK_OFFSET..BLOCK_SIZE]);
+        data[SUPERBLOCK_OFFSET + 4..SUPERBLOCK_OFFSET + 8].copy_from_slice(&checksum.to_le_bytes());
+
+        Image::from_bytes(data).unwrap()
+    }
+
+    fn messages(problems: Vec<Problem>) -> Vec<String> {
+        problems.iter().map(Problem::to_string).collect()
+    }
+
+    fn nid(image: &Image, path: &str) -> u64 {
+        image.resolve(path, false).unwrap()
+    }
+
+    fn inode_offset(image: &Image, path: &str) -> usize {
+        image.inode_offset(nid(image, path)).unwrap()
+    }
+
+    /// Where the dirent for `name` is, in a directory that fits in one block
+    fn dirent_offset(image: &Image, dir: &str, name: &str) -> usize {
+        let nid = nid(image, dir);
+        let extents = image.extents(nid).unwrap();
+        assert_eq!(extents.len(), 1);
+
+        let index = image
+            .read_dir(nid)
+            .unwrap()
+            .iter()
+            .position(|dirent| dirent.name == name)
+            .unwrap();
+
+        extents[0].physical as usize + index * size_of::<DirEnt>()
+    }
+
+    /// Where the name of the dirent at `offset` is
+    fn name_offset(image: &Image, dir: &str, offset: usize) -> usize {
+        let start = image.extents(nid(image, dir)).unwrap()[0].physical as usize;
+        start + u16_le(&image.data[offset + 8..], "nameoff").unwrap() as usize
+    }
+
+    #[test]
+    fn unsorted_dirents() {
+        let image = corrupt(&builder(&[("a", b"a"), ("b", b"b")]), |image, data| {
+            let a = name_offset(image, "/", dirent_offset(image, "/", "a"));
+            let b = name_offset(image, "/", dirent_offset(image, "/", "b"));
+            data.swap(a, b);
+        });
+
+        assert_eq!(
+            messages(check(&image).problems),
+            ["/: entries are not sorted, \"b\" comes before \"a\""]
+        );
+    }
+
+    #[test]
+    fn dotdot() {
+        let image = corrupt(&builder(&[("dir", b"")]), |image, data| {
+            let offset = dirent_offset(image, "/dir", "..");
+            let dir = nid(image, "/dir");
+            data[offset..offset + 8].copy_from_slice(&dir.to_le_bytes());
+        });
+
+        let (dir, root) = (nid(&image, "/dir"), image.root_nid());
+        assert_eq!(
+            messages(check(&image).problems),
+            [format!(
+                "/dir: \"..\" points at nid {dir} instead of {root}"
+            )]
+        );
+    }
+
+    #[test]
+    fn nlink() {
+        let image = corrupt(&builder(&[("a", b"a")]), |image, data| {
+            // Compact inodes have the nlink right after the mode
+            let offset = inode_offset(image, "/a");
+            data[offset + 6..offset + 8].copy_from_slice(&3u16.to_le_bytes());
+        });
+
+        assert_eq!(
+            messages(check(&image).problems),
+            ["/a: nlink is 3, but 1 entries point to it"]
+        );
+    }
+
+    #[test]
+    fn huge_size() {
+        let mut builder = builder(&[]);
+        let mut node = file(b"contents");
+        // Different from the build time, so the inode is extended and has a 64-bit size
+        node.mtime = 1;
+        builder.insert(ROOT, b"a", node).unwrap();
+
+        let image = corrupt(&builder, |image, data| {
+            let offset = inode_offset(image, "/a");
+            data[offset + 8..offset + 16].copy_from_slice(&(1u64 << 50).to_le_bytes());
+        });
+
+        let nid = nid(&image, "/a");
+        assert!(matches!(image.inode(nid).unwrap(), Inode::Extended(..)));
+
+        let tail = inode_offset(&image, "/a") + size_of::<ExtendedInodeHeader>();
+        let plain = (1u64 << 50) - BLOCK_SIZE as u64;
+        assert_eq!(
+            messages(check(&image).problems),
+            [
+                format!("/a: data at 0..{plain} is past the last block at 4096"),
+                format!(
+                    "/a: inline data at {tail}..{} crosses a block boundary",
+                    tail + BLOCK_SIZE
+                ),
+            ]
+        );
+
+        // Reading it fails instead of trying to make room for all of it
+        assert!(image.read(nid).is_err());
+    }
+
+    #[test]
+    fn block_size() {
+        let image = builder(&[("a", b"a")]).write(vec![]).unwrap();
+
+        for bits in [0, 8, 17, 64, 255] {
+            let mut data = image.clone();
+            data[SUPERBLOCK_OFFSET + 12] = bits;
+
+            let error = Image::from_bytes(data).err().unwrap();
+            assert_eq!(
+                error.to_string(),
+                format!("Unsupported block size bits {bits}")
+            );
+        }
+    }
+
+    #[test]
+    fn orphan() {
+        let mut b = 0;
+
+        let image = corrupt(&builder(&[("a", b"a"), ("b", b"b")]), |image, data| {
+            b = nid(image, "/b");
+
+            let offset = dirent_offset(image, "/", "b");
+            let a = nid(image, "/a");
+            data[offset..offset + 8].copy_from_slice(&a.to_le_bytes());
+        });
+
+        let offset = image.inode_offset(b).unwrap();
+
+        assert_eq!(
+            messages(check(&image).problems),
+            ["/a: nlink is 1, but 2 entries point to it"]
+        );
+        assert_eq!(
+            messages(scan(&image)),
+            [format!(
+                "nid {b} at offset {offset} looks like an inode but no directory points to it"
+            )]
+        );
+    }
+
+    #[test]
+    fn overlapping_extents() {
+        let a = vec![1; 2 * BLOCK_SIZE];
+        let b = vec![2; 2 * BLOCK_SIZE];
+
+        let image = corrupt(&builder(&[("a", &a), ("b", &b)]), |image, data| {
+            // Compact inodes have the block address at the same place as extended ones
+            let a = inode_offset(image, "/a") + 16;
+            let b = inode_offset(image, "/b") + 16;
+            data.copy_within(a..a + 4, b);
+        });
+
+        let block = image.extents(nid(&image, "/a")).unwrap()[0].physical / BLOCK_SIZE as u64;
+        assert_eq!(image.read(nid(&image, "/b")).unwrap(), a);
+
+        assert!(messages(check(&image).problems).is_empty());
+        assert_eq!(
+            messages(scan(&image)),
+            [
+                format!("/b: data block {block} is also used by /a"),
+                format!("/b: data block {} is also used by /a", block + 1),
+                format!(
+                    "blocks {}..{} are not used by anything",
+                    block + 2,
+                    block + 4
+                ),
+            ]
+        );
+    }
+}
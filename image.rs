@@ -0,0 +1,278 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use anyhow::{Context, Result, bail};
+
+use crate::composefs::{self, ComposefsHeader};
+use crate::inode::*;
+use crate::metadata::Metadata;
+use crate::objects::ObjectStore;
+use crate::sb::*;
+use crate::trace;
+
+// The first 1KiB is free for anyone to use (composefs puts its header there), the superblock
+// comes right after
+pub const SUPERBLOCK_OFFSET: usize = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+
+// Same limit the kernel puts on following symlinks during a lookup
+const MAX_SYMLINKS: usize = 40;
+
+pub struct Image {
+    pub data: Vec<u8>,
+    pub superblock: Superblock,
+    /// Where the contents of composefs files are read from, see [`Image::with_objects`]
+    objects: Option<ObjectStore>,
+}
+
+impl Image {
+    #[fn_error_context::context("Opening image {}", path.display())]
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::from_bytes(std::fs::read(path)?)
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self> {
+        let Some(sb_bytes) = data.get(SUPERBLOCK_OFFSET..SUPERBLOCK_OFFSET + SUPERBLOCK_SIZE)
+        else {
+            bail!("Image of {} bytes is too small to be EROFS", data.len());
+        };
+
+        let superblock: Superblock =
+            unsafe { ptr::read_unaligned(sb_bytes.as_ptr() as *const Superblock) };
+
+        if superblock.magic != MAGIC_V1 {
+            bail!("Bad superblock magic {:#x}", superblock.magic);
+        }
+
+        if !(MIN_BLOCK_BITS..=MAX_BLOCK_BITS).contains(&superblock.blkszbits) {
+            bail!("Unsupported block size bits {}", superblock.blkszbits);
+        }
+
+        Ok(Image {
+            data,
+            superblock,
+            objects: None,
+        })
+    }
+
+    /// Reads files with an overlay redirect from their object in `objects`, instead of the
+    /// holes the image has for them
+    pub fn with_objects(mut self, objects: ObjectStore) -> Self {
+        self.objects = Some(objects);
+        self
+    }
+
+    pub fn objects(&self) -> Option<&ObjectStore> {
+        self.objects.as_ref()
+    }
+
+    pub fn composefs_header(&self) -> Result<Option<ComposefsHeader>> {
+        composefs::parse_header(&self.data[..SUPERBLOCK_OFFSET])
+    }
+
+    pub fn block_size(&self) -> usize {
+        1 << self.superblock.blkszbits
+    }
+
+    pub fn root_nid(&self) -> u64 {
+        self.superblock.root_nid as u64
+    }
+
+    /// Where the inode is in the image, an error when a corrupt nid or superblock puts it
+    /// beyond what can be addressed
+    pub fn inode_offset(&self, nid: u64) -> Result<usize> {
+        // inode offset = meta_blkaddr * block_size + 32 * nid
+        (self.superblock.meta_blkaddr as u64)
+            .checked_mul(self.block_size() as u64)
+            .zip(nid.checked_mul(32))
+            .and_then(|(meta, inode)| meta.checked_add(inode))
+            .and_then(|offset| usize::try_from(offset).ok())
+            .with_context(|| format!("Inode {nid} is out of range"))
+    }
+
+    /// All the bytes from the start of the inode until the end of the image
+    pub fn inode_data(&self, nid: u64) -> Result<&[u8]> {
+        let offset = self.inode_offset(nid)?;
+
+        self.data
+            .get(offset..)
+            .with_context(|| format!("Inode {nid} at offset {offset} is past the end of the image"))
+    }
+
+    #[fn_error_context::context("Reading inode {nid}")]
+    pub fn inode(&self, nid: u64) -> Result<Inode> {
+        let offset = self.inode_offset(nid)?;
+        let _span = trace::span!("inode", nid, offset);
+
+        Inode::parse(self.inode_data(nid)?)
+    }
+
+    pub fn metadata(&self, nid: u64) -> Result<Metadata> {
+        self.inode(nid)?.metadata(nid, &self.superblock)
+    }
+
+    /// Entries of the directory, including "." and ".."
+    #[fn_error_context::context("Reading directory {nid}")]
+    pub fn read_dir(&self, nid: u64) -> Result<Vec<MyDirEnt>> {
+        let offset = self.inode_offset(nid)?;
+        let _span = trace::span!("read_dir", nid, offset);
+
+        self.inode(nid)?
+            .read_dir(offset, &self.data, &self.superblock)
+    }
+
+    pub fn extents(&self, nid: u64) -> Result<Vec<Extent>> {
+        let offset = self.inode_offset(nid)?;
+        let _span = trace::span!("extents", nid, offset);
+
+        self.inode(nid)?
+            .extents(offset, &self.data, &self.superblock)
+    }
+
+    /// The object the contents of a regular file come from, if there is an object store and the
+    /// file has a redirect
+    fn object_path(&self, nid: u64) -> Result<Option<(PathBuf, u64)>> {
+        let Some(objects) = &self.objects else {
+            return Ok(None);
+        };
+
+        let inode = self.inode(nid)?;
+        if !inode.is_file() {
+            return Ok(None);
+        }
+
+        let Some(object) = self.backing_object(nid)? else {
+            return Ok(None);
+        };
+
+        Ok(Some((objects.resolve(&object.redirect)?, inode.size())))
+    }
+
+    /// Whole contents of the file
+    #[fn_error_context::context("Reading inode {nid}")]
+    pub fn read(&self, nid: u64) -> Result<Vec<u8>> {
+        let offset = self.inode_offset(nid)?;
+        let _span = trace::span!("read", nid, offset);
+
+        if let Some((path, size)) = self.object_path(nid)? {
+            let data =
+                std::fs::read(&path).with_context(|| format!("Reading {}", path.display()))?;
+            check_object_size(&path, data.len() as u64, size)?;
+
+            return Ok(data);
+        }
+
+        self.inode(nid)?
+            .read_data(offset, &self.data, &self.superblock)
+    }
+
+    /// Writes the contents of the file to `writer` without holding all of it in memory
+    #[fn_error_context::context("Reading inode {nid}")]
+    pub fn copy_to(&self, nid: u64, writer: &mut dyn Write) -> Result<()> {
+        let offset = self.inode_offset(nid)?;
+        let _span = trace::span!("copy_to", nid, offset);
+
+        if let Some((path, size)) = self.object_path(nid)? {
+            let mut file =
+                File::open(&path).with_context(|| format!("Opening {}", path.display()))?;
+            let copied = std::io::copy(&mut file, writer)
+                .with_context(|| format!("Reading {}", path.display()))?;
+
+            return check_object_size(&path, copied, size);
+        }
+
+        self.inode(nid)?
+            .copy_data(offset, &self.data, &self.superblock, writer)
+    }
+
+    #[fn_error_context::context("Reading symlink {nid}")]
+    pub fn read_link(&self, nid: u64) -> Result<Vec<u8>> {
+        let offset = self.inode_offset(nid)?;
+        let _span = trace::span!("read_link", nid, offset);
+
+        self.inode(nid)?
+            .read_link(offset, &self.data, &self.superblock)
+    }
+
+    /// Finds the nid of `path`, relative paths start at the root. Symlinks in the middle of the
+    /// path are always followed, the last component is only followed with `follow`
+    #[fn_error_context::context("Resolving {path}")]
+    pub fn resolve(&self, path: &str, follow: bool) -> Result<u64> {
+        let _span = trace::span!("resolve", path = %path, follow);
+
+        let mut links = 0;
+        self.resolve_from(self.root_nid(), path.as_bytes(), follow, &mut links)
+    }
+
+    fn resolve_from(
+        &self,
+        start: u64,
+        path: &[u8],
+        follow: bool,
+        links: &mut usize,
+    ) -> Result<u64> {
+        let mut nid = if path.starts_with(b"/") {
+            self.root_nid()
+        } else {
+            start
+        };
+
+        let components: Vec<&[u8]> = path
+            .split(|c| *c == b'/')
+            .filter(|c| !c.is_empty() && *c != b".")
+            .collect();
+
+        for (i, name) in components.iter().enumerate() {
+            let dir = nid;
+
+            // ".." of the root is the root itself, so this needs no special casing
+            nid = self
+                .read_dir(dir)?
+                .into_iter()
+                .find(|d| d.name.as_bytes() == *name)
+                .map(|d| d.dirent.nid)
+                .with_context(|| {
+                    format!(
+                        "{}: No such file or directory",
+                        String::from_utf8_lossy(name)
+                    )
+                })?;
+
+            let last = i == components.len() - 1;
+
+            if (!last || follow) && self.inode(nid)?.is_symlink() {
+                *links += 1;
+
+                if *links > MAX_SYMLINKS {
+                    bail!("Too many levels of symbolic links");
+                }
+
+                let target = self.read_link(nid)?;
+                nid = self.resolve_from(dir, &target, true, links)?;
+            }
+        }
+
+        Ok(nid)
+    }
+
+    pub fn xattrs(&self, nid: u64) -> Result<Vec<XattrSingle>> {
+        let offset = self.inode_offset(nid)?;
+        let _span = trace::span!("xattrs", nid, offset);
+
+        self.inode(nid)?
+            .all_xattrs(self.inode_data(nid)?, &self.data, &self.superblock)
+    }
+}
+
+fn check_object_size(path: &Path, len: u64, size: u64) -> Result<()> {
+    if len != size {
+        bail!(
+            "Object {} is {len} bytes, the file is {size}",
+            path.display()
+        );
+    }
+
+    Ok(())
+}
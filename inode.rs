@@ -1,25 +1,99 @@
 use std::{
     fmt::{Debug, Display},
+    io::Write,
     ops::Range,
-    usize,
 };
 
+use anyhow::{Context, bail};
+
+use crate::acl::Acl;
+use crate::caps::Capabilities;
+use crate::metadata::Metadata;
 use crate::sb::Superblock;
+use crate::trace;
 
 use std::ptr::read_unaligned;
 
-const S_IFMT: u16 = 0o170000;
-const S_IFREG: u16 = 0o100000;
-const S_IFCHR: u16 = 0o020000;
-const S_IFDIR: u16 = 0o040000;
-const S_IFBLK: u16 = 0o060000;
-const S_IFIFO: u16 = 0o010000;
-const S_IFLNK: u16 = 0o120000;
-const S_IFSOCK: u16 = 0o140000;
-
-const EROFS_I_DATALAYOUT_BIT: u8 = 1;
+pub const S_IFMT: u16 = 0o170000;
+pub const S_IFREG: u16 = 0o100000;
+pub const S_IFCHR: u16 = 0o020000;
+pub const S_IFDIR: u16 = 0o040000;
+pub const S_IFBLK: u16 = 0o060000;
+pub const S_IFIFO: u16 = 0o010000;
+pub const S_IFLNK: u16 = 0o120000;
+pub const S_IFSOCK: u16 = 0o140000;
+
+pub const S_ISUID: u16 = 0o4000;
+pub const S_ISGID: u16 = 0o2000;
+
+// Bit 0 of the format says whether the inode is extended, the data layout comes after it
+pub const EROFS_I_VERSION_EXTENDED: u16 = 1;
+pub const EROFS_I_DATALAYOUT_BIT: u8 = 1;
 const EROFS_I_DATALAYOUT_MASK: u8 = 0b00000111;
 
+// For chunk based inodes `u` holds the chunk format instead of a block address
+pub const EROFS_CHUNK_FORMAT_BLKBITS_MASK: u32 = 0x001F;
+const EROFS_CHUNK_FORMAT_INDEXES: u32 = 0x0020;
+
+// Block address of a chunk which is a hole
+pub const EROFS_NULL_ADDR: u32 = u32::MAX;
+
+pub const PATH_MAX: usize = 4096;
+
+// Longest name a dirent can have
+pub const EROFS_NAME_LEN: usize = 255;
+
+// The name index of an xattr entry selects a well known prefix for the name
+pub const EROFS_XATTR_INDEX_USER: u8 = 1;
+pub const EROFS_XATTR_INDEX_POSIX_ACL_ACCESS: u8 = 2;
+pub const EROFS_XATTR_INDEX_POSIX_ACL_DEFAULT: u8 = 3;
+pub const EROFS_XATTR_INDEX_TRUSTED: u8 = 4;
+pub const EROFS_XATTR_INDEX_LUSTRE: u8 = 5;
+pub const EROFS_XATTR_INDEX_SECURITY: u8 = 6;
+
+// Inodes have a bloom filter of their xattr names when FEATURE_COMPAT_XATTR_FILTER is set, a
+// set bit means no xattr hashes to it
+pub const EROFS_XATTR_FILTER_BITS: u32 = 32;
+pub const EROFS_XATTR_FILTER_SEED: u32 = 0x25bbe08f;
+
+pub fn xattr_prefix(name_index: u8) -> &'static str {
+    match name_index {
+        EROFS_XATTR_INDEX_USER => "user.",
+        EROFS_XATTR_INDEX_POSIX_ACL_ACCESS => "system.posix_acl_access",
+        EROFS_XATTR_INDEX_POSIX_ACL_DEFAULT => "system.posix_acl_default",
+        EROFS_XATTR_INDEX_TRUSTED => "trusted.",
+        EROFS_XATTR_INDEX_LUSTRE => "lustre.",
+        EROFS_XATTR_INDEX_SECURITY => "security.",
+        _ => "",
+    }
+}
+
+/// Splits a full xattr name into its name index and the rest of the name, the inverse of
+/// [`xattr_prefix`]. Names without a prefix EROFS knows about can't be stored
+pub fn xattr_name_index(name: &str) -> Option<(u8, &str)> {
+    // The ACLs are whole names, they have to come before anything matching on "system."
+    [
+        EROFS_XATTR_INDEX_POSIX_ACL_ACCESS,
+        EROFS_XATTR_INDEX_POSIX_ACL_DEFAULT,
+        EROFS_XATTR_INDEX_USER,
+        EROFS_XATTR_INDEX_TRUSTED,
+        EROFS_XATTR_INDEX_SECURITY,
+    ]
+    .into_iter()
+    .find_map(|index| {
+        let rest = name.strip_prefix(xattr_prefix(index))?;
+
+        match index {
+            EROFS_XATTR_INDEX_POSIX_ACL_ACCESS | EROFS_XATTR_INDEX_POSIX_ACL_DEFAULT
+                if !rest.is_empty() =>
+            {
+                None
+            }
+            _ => Some((index, rest)),
+        }
+    })
+}
+
 #[derive(Debug, Copy, Clone)]
 #[repr(C, packed)]
 pub struct DirEnt {
@@ -29,6 +103,29 @@ pub struct DirEnt {
     pub reserved: u8,
 }
 
+impl DirEnt {
+    pub fn to_bytes(&self) -> [u8; 12] {
+        let mut bytes = [0; 12];
+
+        bytes[0..8].copy_from_slice(&self.nid.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.name_offset.to_le_bytes());
+        bytes[10] = self.file_type;
+        bytes[11] = self.reserved;
+
+        bytes
+    }
+}
+
+// File types in dirents
+pub const EROFS_FT_UNKNOWN: u8 = 0;
+pub const EROFS_FT_REG_FILE: u8 = 1;
+pub const EROFS_FT_DIR: u8 = 2;
+pub const EROFS_FT_CHRDEV: u8 = 3;
+pub const EROFS_FT_BLKDEV: u8 = 4;
+pub const EROFS_FT_FIFO: u8 = 5;
+pub const EROFS_FT_SOCK: u8 = 6;
+pub const EROFS_FT_SYMLINK: u8 = 7;
+
 pub struct MyDirEnt {
     pub dirent: DirEnt,
     pub name: String,
@@ -55,7 +152,38 @@ impl Debug for MyDirEnt {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
+#[repr(C, packed)]
+pub struct ChunkIndex {
+    pub advise: u16,
+    pub device_id: u16,
+    pub blkaddr: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+#[cfg_attr(feature = "json", serde(rename_all = "snake_case"))]
+pub enum ExtentKind {
+    /// Stored in blocks of its own
+    Plain,
+    /// Stored right after the inode in the metadata area
+    Inline,
+    /// Not stored at all, reads as zeros
+    Hole,
+}
+
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct Extent {
+    /// Offset in the file
+    pub logical: u64,
+    /// Offset in the image, meaningless for holes
+    pub physical: u64,
+    pub len: u64,
+    pub kind: ExtentKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum InodeDataLayout {
     FlatPlain,
     CompressedFull,
@@ -64,6 +192,15 @@ pub enum InodeDataLayout {
     ChunkBased,
 }
 
+impl InodeDataLayout {
+    pub fn is_compressed(self) -> bool {
+        matches!(
+            self,
+            InodeDataLayout::CompressedFull | InodeDataLayout::CompressedCompact
+        )
+    }
+}
+
 impl TryFrom<u8> for InodeDataLayout {
     type Error = std::io::Error;
 
@@ -157,13 +294,22 @@ impl<'a> Debug for Xattrs<'a> {
 }
 
 pub struct XattrSingle {
-    name: String,
-    value: Box<Vec<u8>>,
+    pub name_index: u8,
+    /// Name without the prefix selected by `name_index`
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+impl XattrSingle {
+    pub fn full_name(&self) -> String {
+        format!("{}{}", xattr_prefix(self.name_index), self.name)
+    }
 }
 
 impl Debug for XattrSingle {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "\nXattrSingle {{")?;
+        writeln!(f, "\tname_index: {},", self.name_index)?;
         writeln!(f, "\tname: {},", self.name)?;
         writeln!(f, "\tvalue: {:?}", self.value)?;
         write!(f, "}}")
@@ -171,54 +317,80 @@ impl Debug for XattrSingle {
 }
 
 impl<'a> Xattrs<'a> {
-    pub fn get_all_xattrs(&self) {
-        let sizeof_entry = size_of::<ErofsXattrEntry>();
+    pub fn get_all_xattrs(&self) -> Vec<XattrSingle> {
+        let xattrs = self.parse_entries();
+
+        for attr in &xattrs {
+            if attr.name == "overlay.redirect" {
+                trace::debug!(
+                    redirect = %String::from_utf8_lossy(&attr.value),
+                    "overlay redirect"
+                );
+            }
+        }
+
+        xattrs
+    }
 
+    fn parse_entries(&self) -> Vec<XattrSingle> {
         let mut xattrs = vec![];
 
         let mut data = self.data;
 
-        // TODO: Get all xattrs
-        loop {
-            if data.len() < sizeof_entry {
-                break;
-            }
-
-            let ent = &data[..sizeof_entry];
-            let ent = unsafe { read_unaligned(ent.as_ptr() as *const ErofsXattrEntry) };
+        while let Some((xattr, len)) = parse_xattr_entry(data) {
+            xattrs.push(xattr);
+            data = &data[len.min(data.len())..];
+        }
 
-            let name_range = Range {
-                start: ent.name_index as usize,
-                end: (ent.name_index as usize + ent.name_len as usize) as usize,
-            };
+        xattrs
+    }
 
-            if name_range.end >= data.len() {
-                break;
-            }
+    /// Ids of the xattrs this inode shares with others. Each one is the offset, in u32s, into
+    /// the shared xattr area at `xattr_blkaddr`
+    pub fn shared_ids(&self) -> Vec<u32> {
+        self.header
+            .shared_xattrs
+            .chunks_exact(4)
+            .map(|id| u32::from_le_bytes(id.try_into().unwrap()))
+            .collect()
+    }
+}
 
-            let name = String::from_utf8(data[name_range.clone()].to_vec()).unwrap();
+/// Parses the xattr entry at the start of `data`, returning it along with the number of bytes it
+/// takes up including the padding
+fn parse_xattr_entry(data: &[u8]) -> Option<(XattrSingle, usize)> {
+    let sizeof_entry = size_of::<ErofsXattrEntry>();
 
-            let value_range = Range {
-                start: name_range.end,
-                end: name_range.end + ent.value_size as usize,
-            };
+    // Each entry is
+    // [ErofsXattrEntry][name][value] padded to 4 bytes
+    if data.len() < sizeof_entry {
+        return None;
+    }
 
-            let value = &data[value_range.clone()];
+    let ent = &data[..sizeof_entry];
+    let ent = unsafe { read_unaligned(ent.as_ptr() as *const ErofsXattrEntry) };
 
-            xattrs.push(XattrSingle {
-                name,
-                value: Box::new(value.to_vec()),
-            });
+    let name_range = Range {
+        start: sizeof_entry,
+        end: sizeof_entry + ent.name_len as usize,
+    };
 
-            data = &data[value_range.end..];
-        }
+    let value_range = Range {
+        start: name_range.end,
+        end: name_range.end + ent.value_size as usize,
+    };
 
-        for attr in xattrs {
-            if attr.name == "overlay.redirect" {
-                println!("{}", String::from_utf8(*attr.value).unwrap());
-            }
-        }
+    if value_range.end > data.len() {
+        return None;
     }
+
+    let xattr = XattrSingle {
+        name_index: ent.name_index,
+        name: String::from_utf8_lossy(&data[name_range]).into_owned(),
+        value: data[value_range.clone()].to_vec(),
+    };
+
+    Some((xattr, value_range.end.next_multiple_of(4)))
 }
 
 #[repr(C)]
@@ -239,6 +411,26 @@ pub struct CompactInodeHeader {
     pub reserved2: [u8; 4],
 }
 
+impl CompactInodeHeader {
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0; 32];
+
+        bytes[0..2].copy_from_slice(&self.format.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.xattr_icount.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.mode.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.nlink.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.size.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.reserved.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.u.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.ino.to_le_bytes());
+        bytes[24..26].copy_from_slice(&self.uid.to_le_bytes());
+        bytes[26..28].copy_from_slice(&self.gid.to_le_bytes());
+        bytes[28..32].copy_from_slice(&self.reserved2);
+
+        bytes
+    }
+}
+
 impl Debug for CompactInodeHeader {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "CompactInodeHeader {{")?;
@@ -280,6 +472,28 @@ pub struct ExtendedInodeHeader {
     pub reserved2: [u8; 16],
 }
 
+impl ExtendedInodeHeader {
+    pub fn to_bytes(&self) -> [u8; 64] {
+        let mut bytes = [0; 64];
+
+        bytes[0..2].copy_from_slice(&self.format.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.xattr_icount.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.mode.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.reserved.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.size.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.u.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.ino.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.uid.to_le_bytes());
+        bytes[28..32].copy_from_slice(&self.gid.to_le_bytes());
+        bytes[32..40].copy_from_slice(&self.mtime.to_le_bytes());
+        bytes[40..44].copy_from_slice(&self.mtime_nsec.to_le_bytes());
+        bytes[44..48].copy_from_slice(&self.nlink.to_le_bytes());
+        bytes[48..64].copy_from_slice(&self.reserved2);
+
+        bytes
+    }
+}
+
 impl Debug for ExtendedInodeHeader {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "ExtendedInodeHeader {{")?;
@@ -314,6 +528,34 @@ pub enum Inode {
 }
 
 impl Inode {
+    /// Parses the inode header at the start of `bytes`
+    pub fn parse(bytes: &[u8]) -> anyhow::Result<Self> {
+        let Some(first_byte) = bytes.first() else {
+            bail!("No data for inode");
+        };
+
+        let inode = if first_byte & 1 == 1 {
+            // extended inode
+            let Some(bytes) = bytes.get(..size_of::<ExtendedInodeHeader>()) else {
+                bail!("Extended inode cut short at {} bytes", bytes.len());
+            };
+
+            let ext = unsafe { read_unaligned(bytes.as_ptr() as *const ExtendedInodeHeader) };
+
+            Inode::Extended(ext)
+        } else {
+            let Some(bytes) = bytes.get(..size_of::<CompactInodeHeader>()) else {
+                bail!("Compact inode cut short at {} bytes", bytes.len());
+            };
+
+            let cpt = unsafe { read_unaligned(bytes.as_ptr() as *const CompactInodeHeader) };
+
+            Inode::Compact(cpt)
+        };
+
+        Ok(inode)
+    }
+
     pub fn mode(&self) -> u16 {
         match self {
             Inode::Compact(c) => c.mode,
@@ -325,6 +567,14 @@ impl Inode {
         (self.mode() & S_IFMT) == S_IFDIR
     }
 
+    pub fn is_file(&self) -> bool {
+        (self.mode() & S_IFMT) == S_IFREG
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        (self.mode() & S_IFMT) == S_IFLNK
+    }
+
     pub fn size(&self) -> u64 {
         match self {
             Inode::Compact(c) => c.size.into(),
@@ -339,6 +589,13 @@ impl Inode {
         }
     }
 
+    pub fn format(&self) -> u16 {
+        match self {
+            Inode::Compact(c) => c.format,
+            Inode::Extended(e) => e.format,
+        }
+    }
+
     pub fn xattr_count(&self) -> u16 {
         match self {
             Inode::Compact(c) => c.xattr_icount,
@@ -346,6 +603,47 @@ impl Inode {
         }
     }
 
+    pub fn uid(&self) -> u32 {
+        match self {
+            Inode::Compact(c) => c.uid.into(),
+            Inode::Extended(e) => e.uid,
+        }
+    }
+
+    pub fn gid(&self) -> u32 {
+        match self {
+            Inode::Compact(c) => c.gid.into(),
+            Inode::Extended(e) => e.gid,
+        }
+    }
+
+    pub fn nlink(&self) -> u32 {
+        match self {
+            Inode::Compact(c) => c.nlink.into(),
+            Inode::Extended(e) => e.nlink,
+        }
+    }
+
+    pub fn ino(&self) -> u32 {
+        match self {
+            Inode::Compact(c) => c.ino,
+            Inode::Extended(e) => e.ino,
+        }
+    }
+
+    /// Seconds and nanoseconds of the last modification. Compact inodes don't store one, they
+    /// all share the build time of the image
+    pub fn mtime(&self, superblock: &Superblock) -> (u64, u32) {
+        match self {
+            Inode::Compact(..) => (superblock.build_time, superblock.build_time_nsec),
+            Inode::Extended(e) => (e.mtime, e.mtime_nsec),
+        }
+    }
+
+    pub fn metadata(&self, nid: u64, superblock: &Superblock) -> anyhow::Result<Metadata> {
+        Metadata::new(self, nid, superblock)
+    }
+
     pub fn xattrs<'a>(&self, inode_data: &'a [u8]) -> &'a [u8] {
         // This works because the xattrs are literally after the inode header
         // The inline inode data is after the xattrs
@@ -357,21 +655,18 @@ impl Inode {
         // |  ...   | inode |  xattrs  | extents  | data inline | ... | inode ...
         // |________|_______|(optional)|(optional)|__(optional)_|_____|__________
 
-        match self {
-            Inode::Compact(compact) if compact.xattr_icount > 0 => {
-                let size = (compact.xattr_icount as usize - 1) * 4 + 12;
-                // WE skip the header bit from the data and take the rest
-                &inode_data[size_of::<CompactInodeHeader>()..][..size]
-            }
-
-            Inode::Extended(extended) if extended.xattr_icount > 0 => {
-                let size = (extended.xattr_icount as usize - 1) * 4 + 12;
-                // WE skip the header bit from the data and take the rest
-                &inode_data[size_of::<ExtendedInodeHeader>()..][..size]
-            }
+        let icount = self.xattr_count() as usize;
 
-            _ => &[],
+        if icount == 0 {
+            return &[];
         }
+
+        // A corrupt count that runs past the end of the image is taken as no xattrs at all,
+        // fsck reports it
+        let size = (icount - 1) * 4 + 12;
+        let start = self.header_size();
+
+        inode_data.get(start..start + size).unwrap_or_default()
     }
 
     pub fn data_layout(&self) -> Result<InodeDataLayout, std::io::Error> {
@@ -387,28 +682,29 @@ impl Inode {
     }
 
     /// Returns the dirent at index `num`
-    fn get_dirent(&self, inode_data: &[u8], num: usize) -> DirEnt {
+    fn get_dirent(&self, inode_data: &[u8], num: usize) -> anyhow::Result<DirEnt> {
         let dirent_size = size_of::<DirEnt>();
 
         let start = dirent_size * num;
         let end = start + dirent_size;
 
         if end > inode_data.len() {
-            println!("dirent_num: {num:?}");
-            println!("inode_data: {inode_data:?}");
-            println!("inode: {self:?}");
+            trace::warning!(
+                dirent_num = num,
+                block_len = inode_data.len(),
+                "dirent past the end of the directory block"
+            );
+            bail!("Dirent {num} is past the end of the directory block");
         }
 
         let dirent = &inode_data[start..end];
 
         // NOTE: this works out with little endian as my machine is little endian
         // This would break spectacularly on a big endian machine
-        let dirent = unsafe { read_unaligned(dirent.as_ptr() as *const DirEnt) };
-
-        dirent
+        Ok(unsafe { read_unaligned(dirent.as_ptr() as *const DirEnt) })
     }
 
-    pub fn parse_dirents(&self, inode_data: &[u8]) -> Vec<MyDirEnt> {
+    pub fn parse_dirents(&self, inode_data: &[u8]) -> anyhow::Result<Vec<MyDirEnt>> {
         // Directories are stored as follows
         // [dirent0][dirent1]...[direntN][name strings...]
         //
@@ -424,141 +720,333 @@ impl Inode {
         // To get the name of the dirent, we have to parse the next dirent, find the name offset,
         // then subtract the current name offset to get the name length
 
-        let mut dirents = vec![];
-        let mut dirent_num = 0;
+        let dirent_size = size_of::<DirEnt>();
 
-        let mut first = self.get_dirent(inode_data, dirent_num);
-        let first_nameoff = first.name_offset;
+        // The names start right after the last dirent, so the first one tells how many there are
+        let first_nameoff = self.get_dirent(inode_data, 0)?.name_offset as usize;
 
-        // There will always be two dirents, "." and ".."
-        dirent_num += 1;
-        let mut second = self.get_dirent(inode_data, dirent_num);
+        if first_nameoff == 0
+            || !first_nameoff.is_multiple_of(dirent_size)
+            || first_nameoff > inode_data.len()
+        {
+            trace::warning!(first_nameoff, "bad name offset of the first dirent");
+            bail!("Bad name offset {first_nameoff} of the first dirent");
+        }
 
-        assert!(first_nameoff % 12 == 0);
-        let num_dirents = (first_nameoff / 12) as usize;
+        let num_dirents = first_nameoff / dirent_size;
 
-        if num_dirents == 0 {
-            println!("first: {first:?}");
-            return vec![];
-        }
+        let raw = (0..num_dirents)
+            .map(|num| self.get_dirent(inode_data, num))
+            .collect::<anyhow::Result<Vec<_>>>()?;
 
-        loop {
-            if second.name_offset as usize > inode_data.len() {
-                println!("dirent_num: {dirent_num:#?}");
-                println!("first: {first:#?}");
-                println!("second: {second:#?}");
-                println!("inode_data: {inode_data:?}");
-            }
+        let mut dirents = vec![];
 
-            let my_dirent = MyDirEnt {
-                dirent: first.clone(),
-                name: String::from_utf8(
-                    inode_data[first.name_offset as usize..second.name_offset as usize].into(),
-                )
-                .unwrap(),
-            };
+        for (num, dirent) in raw.iter().enumerate() {
+            let start = dirent.name_offset as usize;
 
-            dirents.push(my_dirent);
+            // The last name runs until the end of the block, padded with zeros
+            let (end, last) = match raw.get(num + 1) {
+                Some(next) => (next.name_offset as usize, false),
+                None => (inode_data.len(), true),
+            };
 
-            first = second;
+            if start > end || end > inode_data.len() {
+                trace::warning!(
+                    dirent_num = num,
+                    start,
+                    end,
+                    block_len = inode_data.len(),
+                    "dirent name out of bounds"
+                );
+                bail!("Name of dirent {num} at {start}..{end} is out of bounds");
+            }
 
-            dirent_num += 1;
+            let mut name = &inode_data[start..end];
 
-            if dirent_num >= num_dirents {
-                break;
+            if last {
+                name = name.split(|x| *x == 0).next().unwrap_or_default();
             }
 
-            second = self.get_dirent(inode_data, dirent_num);
+            dirents.push(MyDirEnt {
+                dirent: *dirent,
+                name: String::from_utf8_lossy(name).into_owned(),
+            });
         }
 
-        // We will still be missing the last dirent here
-        let my_dirent = MyDirEnt {
-            dirent: first.clone(),
-            name: String::from_utf8(
-                inode_data[first.name_offset as usize..]
-                    .split(|x| *x == 0)
-                    .next()
-                    .unwrap()
-                    .into(),
-            )
-            .unwrap(),
-        };
-
-        dirents.push(my_dirent);
+        Ok(dirents)
+    }
 
-        return dirents;
+    pub fn header_size(&self) -> usize {
+        match self {
+            Inode::Compact(..) => size_of::<CompactInodeHeader>(),
+            Inode::Extended(..) => size_of::<ExtendedInodeHeader>(),
+        }
     }
 
-    pub fn parse_inode(
+    /// Maps the file's data to where it lives in the image
+    pub fn extents(
         &self,
-        layout: InodeDataLayout,
-        inode_data: &[u8],
+        inode_offset: usize,
         file: &[u8],
         superblock: &Superblock,
-    ) -> Vec<MyDirEnt> {
-        let header_size = match self {
-            Inode::Compact(..) => size_of::<CompactInodeHeader>(),
-            Inode::Extended(..) => size_of::<ExtendedInodeHeader>(),
-        };
+    ) -> anyhow::Result<Vec<Extent>> {
+        let block_size = 1u64 << superblock.blkszbits;
+        let size = self.size();
+
+        let inode_data = file
+            .get(inode_offset..)
+            .context("Inode is past the end of the image")?;
+
+        // Where inline data or chunk indexes start
+        let meta_end = inode_offset + self.header_size() + self.xattrs(inode_data).len();
+
+        let mut extents = vec![];
 
         use InodeDataLayout::*;
 
-        match layout {
+        match self.data_layout()? {
+            // Data is stored at inode.u * block_size
+            // why is this not properly documented? idk...
             FlatPlain => {
-                let block_size = 1 << superblock.blkszbits;
-
-                // Data is stored at inode.u * block_size
-                // why is this not properly documented? idk...
-                let data = &file[self.u() as usize * block_size..][..block_size];
+                if size > 0 {
+                    extents.push(Extent {
+                        logical: 0,
+                        physical: self.u() as u64 * block_size,
+                        len: size,
+                        kind: ExtentKind::Plain,
+                    });
+                }
+            }
 
-                if self.is_dir() {
-                    return self.parse_dirents(data);
+            // All blocks but the last one are like FlatPlain, the last one is stored right after
+            // the xattrs
+            FlatInline => {
+                let plain_len = size.div_ceil(block_size).saturating_sub(1) * block_size;
+
+                if plain_len > 0 {
+                    extents.push(Extent {
+                        logical: 0,
+                        physical: self.u() as u64 * block_size,
+                        len: plain_len,
+                        kind: ExtentKind::Plain,
+                    });
                 }
 
-                self.get_xattrs(data);
+                if size > plain_len {
+                    extents.push(Extent {
+                        logical: plain_len,
+                        physical: meta_end as u64,
+                        len: size - plain_len,
+                        kind: ExtentKind::Inline,
+                    });
+                }
             }
 
-            CompressedFull => unimplemented!("CompressedFull"),
+            // The file is divided into chunks, and the inode is followed by a table of physical
+            // block addresses, one for each chunk
+            ChunkBased => {
+                let format = self.u();
+                let chunk_size = block_size << (format & EROFS_CHUNK_FORMAT_BLKBITS_MASK);
+
+                let indexes = format & EROFS_CHUNK_FORMAT_INDEXES != 0;
+                let unit = if indexes {
+                    size_of::<ChunkIndex>()
+                } else {
+                    size_of::<u32>()
+                };
+
+                let table_start = meta_end.next_multiple_of(unit);
+
+                for i in 0..size.div_ceil(chunk_size) {
+                    let entry = file
+                        .get(table_start + i as usize * unit..)
+                        .and_then(|e| e.get(..unit))
+                        .with_context(|| format!("Chunk {i} is past the end of the image"))?;
+
+                    let blkaddr = if indexes {
+                        let index = unsafe { read_unaligned(entry.as_ptr() as *const ChunkIndex) };
+                        index.blkaddr
+                    } else {
+                        u32::from_le_bytes(entry.try_into().unwrap())
+                    };
+
+                    let logical = i * chunk_size;
+
+                    extents.push(Extent {
+                        logical,
+                        physical: if blkaddr == EROFS_NULL_ADDR {
+                            0
+                        } else {
+                            blkaddr as u64 * block_size
+                        },
+                        len: chunk_size.min(size - logical),
+                        kind: if blkaddr == EROFS_NULL_ADDR {
+                            ExtentKind::Hole
+                        } else {
+                            ExtentKind::Plain
+                        },
+                    });
+                }
+            }
 
-            // Data is stored right after the xattrs
-            FlatInline => {
-                let data = &inode_data[header_size..][..self.size() as usize];
+            layout @ (CompressedFull | CompressedCompact) => {
+                bail!("{layout:?} inodes are not supported")
+            }
+        };
 
-                if self.is_dir() {
-                    return self.parse_dirents(&data);
-                }
+        Ok(extents)
+    }
 
-                self.get_xattrs(data);
+    /// Number of bytes the inode takes up in the metadata area: the header, xattrs and then
+    /// either the inline tail or the chunk indexes
+    pub fn meta_size(&self, inode_data: &[u8], superblock: &Superblock) -> anyhow::Result<usize> {
+        let block_size = 1u64 << superblock.blkszbits;
+        let size = self.size();
+        let meta_end = self.header_size() + self.xattrs(inode_data).len();
+
+        Ok(match self.data_layout()? {
+            InodeDataLayout::FlatInline if size > 0 => {
+                let tail = size - size.div_ceil(block_size).saturating_sub(1) * block_size;
+                meta_end + tail as usize
             }
 
-            CompressedCompact => unimplemented!("CompressedCompact"),
+            InodeDataLayout::ChunkBased => {
+                let format = self.u();
+                let chunk_size = block_size << (format & EROFS_CHUNK_FORMAT_BLKBITS_MASK);
 
-            ChunkBased => {
-                if !self.is_dir() {
-                    return vec![];
-                }
+                let unit = if format & EROFS_CHUNK_FORMAT_INDEXES != 0 {
+                    size_of::<ChunkIndex>()
+                } else {
+                    size_of::<u32>()
+                };
+
+                meta_end.next_multiple_of(unit) + size.div_ceil(chunk_size) as usize * unit
+            }
 
-                // the file’s data is not stored contiguously.
-                // Instead, it’s divided into chunks, and the inode holds a table of physical block mappings for each chunk.
-                //
-                // WE DON'T USE THIS
+            _ => meta_end,
+        })
+    }
 
-                let block_size = 1 << superblock.blkszbits;
+    /// Reads the whole contents of the file
+    pub fn read_data(
+        &self,
+        inode_offset: usize,
+        file: &[u8],
+        superblock: &Superblock,
+    ) -> anyhow::Result<Vec<u8>> {
+        // The size is only as trustworthy as the image, so never reserve more than the image has
+        let mut data = Vec::with_capacity(self.size().min(file.len() as u64) as usize);
+        self.copy_data(inode_offset, file, superblock, &mut data)?;
 
-                println!(
-                    "chunk: {:?}",
-                    &file[self.u() as usize * block_size..][..block_size]
+        Ok(data)
+    }
+
+    /// Streams the data to `writer` instead of collecting it, holes come out as zeros
+    pub fn copy_data(
+        &self,
+        inode_offset: usize,
+        file: &[u8],
+        superblock: &Superblock,
+        writer: &mut dyn Write,
+    ) -> anyhow::Result<()> {
+        let zeros = [0; 4096];
+        let extents = self.extents(inode_offset, file, superblock)?;
+
+        // All of the data has to be in the image before any of it is written
+        for extent in &extents {
+            let in_image = extent
+                .physical
+                .checked_add(extent.len)
+                .is_some_and(|end| end <= file.len() as u64);
+
+            if extent.kind != ExtentKind::Hole && !in_image {
+                bail!(
+                    "Data at {}..{} is past the end of the image",
+                    extent.physical,
+                    extent.physical.saturating_add(extent.len)
                 );
             }
-        };
+        }
+
+        for extent in extents {
+            match extent.kind {
+                ExtentKind::Hole => {
+                    let mut left = extent.len;
+                    while left > 0 {
+                        let len = left.min(zeros.len() as u64);
+                        writer.write_all(&zeros[..len as usize])?;
+                        left -= len;
+                    }
+                }
+
+                ExtentKind::Plain | ExtentKind::Inline => {
+                    let start = extent.physical as usize;
+                    writer.write_all(&file[start..start + extent.len as usize])?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Entries of the directory, including "." and ".."
+    pub fn read_dir(
+        &self,
+        inode_offset: usize,
+        file: &[u8],
+        superblock: &Superblock,
+    ) -> anyhow::Result<Vec<MyDirEnt>> {
+        if !self.is_dir() {
+            bail!("Not a directory");
+        }
+
+        let data = self.read_data(inode_offset, file, superblock)?;
+
+        // Every directory block stands on its own, with its own dirents and names
+        let mut dirents = vec![];
+
+        for block in data.chunks(1 << superblock.blkszbits) {
+            dirents.extend(self.parse_dirents(block)?);
+        }
+
+        Ok(dirents)
+    }
+
+    /// Target of the symlink
+    pub fn read_link(
+        &self,
+        inode_offset: usize,
+        file: &[u8],
+        superblock: &Superblock,
+    ) -> anyhow::Result<Vec<u8>> {
+        if !self.is_symlink() {
+            bail!("Not a symlink");
+        }
+
+        if self.size() == 0 || self.size() > PATH_MAX as u64 {
+            bail!(
+                "Symlink target of {} bytes, must be 1..={PATH_MAX}",
+                self.size()
+            );
+        }
 
-        vec![]
+        let target = self.read_data(inode_offset, file, superblock)?;
+
+        if target.len() as u64 != self.size() {
+            bail!(
+                "Symlink target is {} bytes, but inode size is {}",
+                target.len(),
+                self.size()
+            );
+        }
+
+        Ok(target)
     }
 
     pub fn get_xattrs<'a>(&self, data: &'a [u8]) -> Option<Xattrs<'a>> {
         let xattrs = self.xattrs(data);
 
-        if xattrs.len() == 0 {
+        if xattrs.is_empty() {
             return None;
         }
 
@@ -567,19 +1055,156 @@ impl Inode {
         let header = &xattrs[..header_size];
         let header = unsafe { read_unaligned(header.as_ptr() as *const XattrHeaderWoShared) };
 
-        // TODO: Handle shared_count > 0
+        // The header is followed by `shared_count` u32 ids into the shared xattr area, and then
+        // the inline xattr entries
+        let shared_size = header.shared_count as usize * 4;
+
+        // Same for more shared xattrs than fit
+        let shared_xattrs = xattrs.get(header_size..header_size + shared_size)?;
+
         let header = XattrHeader {
-            shared_xattrs: if header.shared_count > 0 { &[] } else { &[] },
+            shared_xattrs,
             header,
         };
 
         let xattrs = Xattrs {
             header,
-            data: &xattrs[header_size..],
+            data: &xattrs[header_size + shared_size..],
         };
 
-        xattrs.get_all_xattrs();
-
         Some(xattrs)
     }
+
+    /// All xattrs of the inode, the shared ones followed by the inline ones
+    pub fn all_xattrs(
+        &self,
+        inode_data: &[u8],
+        file: &[u8],
+        superblock: &Superblock,
+    ) -> anyhow::Result<Vec<XattrSingle>> {
+        let Some(xattrs) = self.get_xattrs(inode_data) else {
+            return Ok(vec![]);
+        };
+
+        let block_size = 1usize << superblock.blkszbits;
+        let shared_start = superblock.xattr_blkaddr as usize * block_size;
+
+        let mut all = vec![];
+
+        for id in xattrs.shared_ids() {
+            let offset = shared_start + id as usize * 4;
+
+            let (xattr, _) = file
+                .get(offset..)
+                .and_then(parse_xattr_entry)
+                .with_context(|| format!("Shared xattr {id} at offset {offset} is corrupt"))?;
+
+            all.push(xattr);
+        }
+
+        all.extend(xattrs.parse_entries());
+
+        Ok(all)
+    }
+
+    fn find_xattr(
+        &self,
+        inode_data: &[u8],
+        file: &[u8],
+        superblock: &Superblock,
+        name_index: u8,
+        name: &str,
+    ) -> anyhow::Result<Option<XattrSingle>> {
+        Ok(self
+            .all_xattrs(inode_data, file, superblock)?
+            .into_iter()
+            .find(|x| x.name_index == name_index && x.name == name))
+    }
+
+    /// The access ACL of the inode, if it has one
+    pub fn acl(
+        &self,
+        inode_data: &[u8],
+        file: &[u8],
+        superblock: &Superblock,
+    ) -> anyhow::Result<Option<Acl>> {
+        self.get_acl(
+            inode_data,
+            file,
+            superblock,
+            EROFS_XATTR_INDEX_POSIX_ACL_ACCESS,
+        )
+    }
+
+    /// The default ACL of the inode, only ever present on directories
+    pub fn default_acl(
+        &self,
+        inode_data: &[u8],
+        file: &[u8],
+        superblock: &Superblock,
+    ) -> anyhow::Result<Option<Acl>> {
+        self.get_acl(
+            inode_data,
+            file,
+            superblock,
+            EROFS_XATTR_INDEX_POSIX_ACL_DEFAULT,
+        )
+    }
+
+    fn get_acl(
+        &self,
+        inode_data: &[u8],
+        file: &[u8],
+        superblock: &Superblock,
+        name_index: u8,
+    ) -> anyhow::Result<Option<Acl>> {
+        // ACLs have an empty name as the index says it all
+        match self.find_xattr(inode_data, file, superblock, name_index, "")? {
+            Some(x) => Ok(Some(Acl::parse(
+                &x.value,
+                name_index == EROFS_XATTR_INDEX_POSIX_ACL_DEFAULT,
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    /// File capabilities from `security.capability`
+    pub fn capabilities(
+        &self,
+        inode_data: &[u8],
+        file: &[u8],
+        superblock: &Superblock,
+    ) -> anyhow::Result<Option<Capabilities>> {
+        match self.find_xattr(
+            inode_data,
+            file,
+            superblock,
+            EROFS_XATTR_INDEX_SECURITY,
+            "capability",
+        )? {
+            Some(x) => Ok(Some(Capabilities::parse(&x.value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The SELinux label from `security.selinux`, without the trailing NUL
+    pub fn selinux_label(
+        &self,
+        inode_data: &[u8],
+        file: &[u8],
+        superblock: &Superblock,
+    ) -> anyhow::Result<Option<String>> {
+        let label = self.find_xattr(
+            inode_data,
+            file,
+            superblock,
+            EROFS_XATTR_INDEX_SECURITY,
+            "selinux",
+        )?;
+
+        Ok(label.map(|x| {
+            let value = x.value.strip_suffix(&[0]).unwrap_or(&x.value);
+            String::from_utf8_lossy(value).into_owned()
+        }))
+    }
 }
@@ -0,0 +1,150 @@
+//! Machine readable output for the inspection commands, behind the `json` feature.
+//!
+//! Every command prints a single object with a `schema_version` next to the command specific
+//! fields. The version is bumped whenever a field is removed, renamed or changes meaning, new
+//! fields may show up without a bump.
+//!
+//! Version 1:
+//!
+//! - `info`: `composefs` (null for plain EROFS) and `superblock`
+//! - `stat`: `files`, each with `path`, `metadata` and `link_target` for symlinks
+//! - `ls`: `path` and `entries`, each with `name` and `metadata`
+//! - `getfattr`: `files`, each with `path` and `xattrs`. An xattr has its full `name`, the
+//!   value as `value_base64` and as `value` too when it's valid UTF-8
+//! - `map`: `path` and `extents`, each with `logical`, `physical`, `len` and `kind` (`plain`,
+//!   `inline` or `hole`)
+//!
+//! `metadata` holds the fields of [`Metadata`], with `file_type` being one of `regular`,
+//! `directory`, `symlink`, `char_device`, `block_device`, `fifo` or `socket`
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::composefs::ComposefsHeader;
+use crate::image::Image;
+use crate::inode::{Extent, XattrSingle};
+use crate::metadata::Metadata;
+use crate::utils::*;
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct Document<'a, T: Serialize> {
+    schema_version: u32,
+    #[serde(flatten)]
+    body: &'a T,
+}
+
+/// Pretty prints `body` with the schema version added
+pub fn to_string<T: Serialize>(body: &T) -> Result<String> {
+    Ok(serde_json::to_string_pretty(&Document {
+        schema_version: SCHEMA_VERSION,
+        body,
+    })?)
+}
+
+#[derive(Serialize)]
+pub struct SuperblockInfo {
+    pub block_size: usize,
+    pub root_nid: u16,
+    pub inos: u64,
+    pub blocks: u32,
+    pub meta_blkaddr: u32,
+    pub xattr_blkaddr: u32,
+    pub build_time: u64,
+    pub build_time_nsec: u32,
+    pub uuid: String,
+    pub volume_name: String,
+    pub feature_compat: u32,
+    pub compat_features: Vec<&'static str>,
+    pub feature_incompat: u32,
+}
+
+#[derive(Serialize)]
+pub struct Info {
+    pub composefs: Option<ComposefsHeader>,
+    pub superblock: SuperblockInfo,
+}
+
+impl Info {
+    pub fn new(image: &Image) -> Result<Self> {
+        let sb = &image.superblock;
+
+        Ok(Info {
+            composefs: image.composefs_header()?,
+            superblock: SuperblockInfo {
+                block_size: image.block_size(),
+                root_nid: sb.root_nid,
+                inos: sb.inos,
+                blocks: sb.blocks,
+                meta_blkaddr: sb.meta_blkaddr,
+                xattr_blkaddr: sb.xattr_blkaddr,
+                build_time: sb.build_time,
+                build_time_nsec: sb.build_time_nsec,
+                uuid: sb.uuid_string(),
+                volume_name: sb.volume_name(),
+                feature_compat: sb.feature_compat,
+                compat_features: sb.compat_feature_names(),
+                feature_incompat: sb.feature_incompat,
+            },
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct FileStat {
+    pub path: String,
+    pub metadata: Metadata,
+    pub link_target: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Stats {
+    pub files: Vec<FileStat>,
+}
+
+#[derive(Serialize)]
+pub struct ListEntry {
+    pub name: String,
+    pub metadata: Metadata,
+}
+
+#[derive(Serialize)]
+pub struct Listing {
+    pub path: String,
+    pub entries: Vec<ListEntry>,
+}
+
+#[derive(Serialize)]
+pub struct Xattr {
+    pub name: String,
+    pub value: Option<String>,
+    pub value_base64: String,
+}
+
+impl From<&XattrSingle> for Xattr {
+    fn from(xattr: &XattrSingle) -> Self {
+        Xattr {
+            name: xattr.full_name(),
+            value: String::from_utf8(xattr.value.clone()).ok(),
+            value_base64: base64_encode(&xattr.value),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct FileXattrs {
+    pub path: String,
+    pub xattrs: Vec<Xattr>,
+}
+
+#[derive(Serialize)]
+pub struct Xattrs {
+    pub files: Vec<FileXattrs>,
+}
+
+#[derive(Serialize)]
+pub struct ExtentMap {
+    pub path: String,
+    pub extents: Vec<Extent>,
+}
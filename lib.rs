@@ -0,0 +1,22 @@
+pub mod acl;
+pub mod builder;
+pub mod caps;
+pub mod composefs;
+pub mod dumpfile;
+pub mod extract;
+pub mod fsck;
+pub mod image;
+pub mod inode;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod metadata;
+pub mod objects;
+pub mod sb;
+#[cfg(feature = "signatures")]
+pub mod signature;
+pub mod stat;
+pub mod tarball;
+mod trace;
+pub mod utils;
+pub mod verity;
+pub mod walk;
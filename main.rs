@@ -1,119 +1,1139 @@
-#![allow(unused_assignments)]
-#![allow(dead_code)]
+use std::collections::HashSet;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
-mod inode;
-mod sb;
-mod utils;
+use anyhow::{Context, Result, bail};
+use clap::{Args, Parser, Subcommand};
 
-use inode::*;
-use sb::*;
-use std::ptr;
+use erofs::builder::ImageBuilder;
+use erofs::extract::{ExtractOptions, Ownership, extract};
+use erofs::image::Image;
+use erofs::inode::*;
+use erofs::metadata::FileType;
+use erofs::objects::{ImportMode, ObjectStore};
+use erofs::stat::{Stat, mode_string};
+use erofs::utils::*;
+use erofs::verity::{self, HashAlgorithm};
+use erofs::walk::WalkEntry;
 
-use anyhow::Result;
+// Exit codes
+// 0: success
+// 1: the command failed, the reason is on stderr
+// 2: bad usage, this is what clap exits with
+// 4: fsck found problems in the image
+const EXIT_FAILURE: u8 = 1;
+const EXIT_PROBLEMS: u8 = 4;
 
-const COMPOSEFS_MAGIC: u32 = 0xd078629a;
-const COMPOSEFS_VERSION: u32 = 2;
+#[derive(Parser)]
+#[command(name = "erofs", about = "Inspect and build EROFS and composefs images")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
 
-fn assert_header(mut header: &[u8]) {
-    // Composefs header is u32
-    let compsefs_header: &[u8; 4] = &header[..4].try_into().unwrap();
-    let magic_num = u32::from_le_bytes(*compsefs_header);
-    assert_eq!(magic_num, COMPOSEFS_MAGIC);
-    header = &header[4..];
+    /// Print JSON instead of text, for info, ls, stat, getfattr and map
+    #[arg(long, global = true)]
+    json: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the superblock and composefs header
+    Info { image: PathBuf },
+
+    /// List a directory
+    Ls {
+        image: PathBuf,
+        #[arg(default_value = "/")]
+        path: String,
+        /// Long listing with mode, owner, size and mtime
+        #[arg(short, long)]
+        long: bool,
+    },
+
+    /// Write the contents of files to stdout
+    Cat {
+        image: PathBuf,
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// Read the contents of composefs files from this object directory
+        #[arg(long)]
+        objects: Option<PathBuf>,
+    },
+
+    /// Print file metadata the same way as coreutils stat
+    Stat {
+        image: PathBuf,
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+
+    /// Print the directory hierarchy
+    Tree {
+        image: PathBuf,
+        #[arg(default_value = "/")]
+        path: String,
+    },
+
+    /// Print where the data of a file is stored in the image
+    Map { image: PathBuf, path: String },
+
+    /// Print extended attributes
+    Getfattr {
+        image: PathBuf,
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// Only print the attribute with this name
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Print values as well as names
+        #[arg(short, long)]
+        dump: bool,
+    },
+
+    /// Recreate the image contents in a directory
+    Extract {
+        image: PathBuf,
+        dest: PathBuf,
+        /// Always keep the uid/gid from the image, not only when running as root
+        #[arg(long)]
+        numeric_owner: bool,
+        /// Leave everything owned by the current user, even when running as root
+        #[arg(long, conflicts_with = "numeric_owner")]
+        no_owner: bool,
+        /// Read the contents of composefs files from this object directory
+        #[arg(long)]
+        objects: Option<PathBuf>,
+    },
+
+    /// Write the image contents as a pax tar archive
+    Tar {
+        image: PathBuf,
+        /// Write to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Read the contents of composefs files from this object directory
+        #[arg(long)]
+        objects: Option<PathBuf>,
+    },
+
+    /// Check the image for consistency, except for the data of compressed files
+    Fsck {
+        image: PathBuf,
+        /// Also scan every inode slot and block for orphans, overlaps and unused space
+        #[arg(long)]
+        scan: bool,
+        /// Check that the objects composefs files point at are in this directory
+        #[arg(long)]
+        objects: Option<PathBuf>,
+        /// Also compare the fs-verity digests of the objects to the ones in the image
+        #[arg(long, requires = "objects")]
+        digests: bool,
+    },
+
+    /// Print every inode in the composefs dump format
+    Dump { image: PathBuf },
+
+    /// Build an image from a directory
+    Mkfs {
+        source: PathBuf,
+        image: PathBuf,
+        #[command(flatten)]
+        options: BuildOptions,
+    },
+
+    /// Build a composefs image
+    Mkcomposefs {
+        /// A directory, or a composefs dump file with --from-file, `-` for stdin
+        source: PathBuf,
+        image: PathBuf,
+        /// Read SOURCE as a composefs dump file instead of a directory
+        #[arg(long)]
+        from_file: bool,
+        /// Copy the contents of files into this object directory, without it only their
+        /// digests are computed
+        #[arg(long, conflicts_with = "from_file")]
+        digest_store: Option<PathBuf>,
+        /// Reflink objects to the files where the file system supports it
+        #[arg(long, requires = "digest_store")]
+        reflink: bool,
+        /// Hardlink objects to the files instead of copying them
+        #[arg(long, requires = "digest_store", conflicts_with = "reflink")]
+        hardlink: bool,
+        #[command(flatten)]
+        options: BuildOptions,
+    },
+
+    /// Build an image from a tar archive without unpacking it
+    FromTar {
+        /// `-` for stdin
+        tar: PathBuf,
+        image: PathBuf,
+        /// Write a composefs image, with file contents in this object directory
+        #[arg(long)]
+        objects: Option<PathBuf>,
+        #[command(flatten)]
+        options: BuildOptions,
+    },
+
+    /// Print the fs-verity digests of host files, or of files in an image
+    Measure {
+        /// Read FILES from this image instead of the host
+        #[arg(long)]
+        image: Option<PathBuf>,
+        /// Read the contents of composefs files in IMAGE from this object directory
+        #[arg(long, requires = "image")]
+        objects: Option<PathBuf>,
+        #[arg(required = true)]
+        files: Vec<String>,
+        /// sha256 or sha512
+        #[arg(long, default_value = "sha256")]
+        algorithm: HashAlgorithm,
+        /// Hex salt, at most 32 bytes
+        #[arg(long)]
+        salt: Option<String>,
+    },
+
+    /// Check the fs-verity digest of an image file, and its signature if there is one
+    Verify {
+        image: PathBuf,
+        /// Hex SHA-256 fs-verity digest, optionally prefixed with `sha256:`
+        #[arg(long)]
+        expected_digest: String,
+        /// Detached PKCS#7 signature of the digest, as for fs-verity built-in signatures
+        #[arg(long, requires = "certificate")]
+        signature: Option<PathBuf>,
+        /// Certificate of the signer, DER or PEM
+        #[arg(long, requires = "signature")]
+        certificate: Option<PathBuf>,
+    },
 
-    // Then we have a u32 version
-    let version: &[u8; 4] = &header[..4].try_into().unwrap();
-    let version = u32::from_le_bytes(*version);
-    assert_eq!(version, 1);
-    header = &header[4..];
+    /// List the objects none of the images point at, and remove them with --delete
+    Gc {
+        #[arg(long)]
+        objects: PathBuf,
+        #[arg(required = true)]
+        images: Vec<PathBuf>,
+        /// Remove the unreferenced objects instead of only listing them
+        #[arg(long)]
+        delete: bool,
+    },
 
-    // Then we have all zero flags, u32 again
-    let flags: &[u8; 4] = &header[..4].try_into().unwrap();
-    let flags = u32::from_le_bytes(*flags);
-    assert_eq!(flags, 0);
-    header = &header[4..];
+    /// Print the raw superblock and every inode header
+    Debug { image: PathBuf },
 
-    // Then we have composefs_version, u32 again
-    let composefs_version: &[u8; 4] = &header[..4].try_into().unwrap();
-    let composefs_version = u32::from_le_bytes(*composefs_version);
-    assert_eq!(composefs_version, COMPOSEFS_VERSION);
+    /// List files with capabilities, SELinux labels or setuid/setgid bits
+    Audit { image: PathBuf },
 }
 
-fn get_inode_offset(superblock: &Superblock, nid: usize) -> usize {
-    let block_size = 1 << superblock.blkszbits;
+/// Superblock fields of the images the build commands write
+#[derive(Args)]
+struct BuildOptions {
+    /// Volume name, at most 16 bytes
+    #[arg(short = 'L', long)]
+    label: Option<String>,
+    /// A UUID, `random`, `clear` for all zeros or `derive` to hash it from the image. mkfs
+    /// picks a random one unless SOURCE_DATE_EPOCH is set, the others clear it
+    #[arg(short = 'U', long)]
+    uuid: Option<String>,
+    /// Timestamp of the compact inodes, SOURCE_DATE_EPOCH by default. Inodes with another mtime
+    /// get an extended inode
+    #[arg(short = 'T', long, value_name = "SECS")]
+    build_time: Option<u64>,
+}
+
+/// Opens the image, reading composefs files from `objects` when it's given
+fn open_image(image: &Path, objects: Option<&Path>) -> Result<Image> {
+    let image = Image::open(image)?;
 
-    // inode offset = meta_blkaddr * block_size + 32 * nid
-    superblock.meta_blkaddr as usize * block_size as usize + 32usize * nid
+    Ok(match objects {
+        Some(objects) => image.with_objects(ObjectStore::open(objects)?),
+        None => image,
+    })
 }
 
-fn main() -> Result<()> {
-    let file = std::fs::read("./file.erofs")?;
-    let file: &[u8] = &file;
+fn info(image: &Image) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
 
-    println!("file_len: {}", file.len());
+    if let Some(header) = image.composefs_header()? {
+        writeln!(
+            stdout,
+            "composefs:        header version {}, composefs version {}, flags {:#x}",
+            header.version, header.composefs_version, header.flags
+        )?;
+    }
 
-    // First 1kb is our data
-    // We can put anything in here
-    let header = &file[..1024];
-    assert_header(header);
+    let sb = &image.superblock;
 
-    // After header of 1KB we have the superblock
-    let superblock = &file[1024..2048];
-    assert_superblock(superblock)?;
+    writeln!(stdout, "block size:       {}", image.block_size())?;
+    writeln!(stdout, "root nid:         {}", sb.root_nid)?;
+    writeln!(stdout, "inodes:           {}", sb.inos)?;
+    writeln!(stdout, "blocks:           {}", sb.blocks)?;
+    writeln!(stdout, "meta blkaddr:     {}", sb.meta_blkaddr)?;
+    writeln!(stdout, "xattr blkaddr:    {}", sb.xattr_blkaddr)?;
+    writeln!(
+        stdout,
+        "build time:       {}",
+        format_timestamp(sb.build_time as i64, sb.build_time_nsec.into())
+    )?;
+    writeln!(stdout, "uuid:             {}", sb.uuid_string())?;
+    writeln!(stdout, "volume name:      {}", sb.volume_name())?;
+    writeln!(
+        stdout,
+        "feature compat:   {:#x} ({})",
+        sb.feature_compat,
+        sb.compat_feature_names().join(", ")
+    )?;
+    writeln!(stdout, "feature incompat: {:#x}", sb.feature_incompat)?;
 
-    let sb_bytes: &[u8; 1024] = superblock.try_into().expect("slice must be 1024 bytes");
-    let superblock: Superblock =
-        unsafe { ptr::read_unaligned(sb_bytes.as_ptr() as *const Superblock) };
+    Ok(())
+}
 
-    let block_size = 1 << superblock.blkszbits;
+/// Names and nids of the entries of a directory, or just the file itself
+fn list(image: &Image, path: &str) -> Result<Vec<(String, u64)>> {
+    let nid = image.resolve(path, true)?;
 
-    println!("superblock: {superblock:#?}");
-    println!("block_size: {}", block_size);
+    if !image.inode(nid)?.is_dir() {
+        return Ok(vec![(path.to_string(), nid)]);
+    }
 
-    let mut nids = vec![superblock.root_nid as usize];
+    Ok(image
+        .read_dir(nid)?
+        .into_iter()
+        .filter(|d| d.name != "." && d.name != "..")
+        .map(|d| (d.name, d.dirent.nid))
+        .collect())
+}
 
-    while nids.len() > 0 {
-        let nid = nids[0];
-        nids = nids[1..].into();
+fn ls(image: &Image, path: &str, long: bool) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
 
-        let inode_start = get_inode_offset(&superblock, nid);
+    for (name, nid) in list(image, path)? {
+        if !long {
+            writeln!(stdout, "{name}")?;
+            continue;
+        }
 
-        let inode_first_byte = file[inode_start];
+        let metadata = image.metadata(nid)?;
 
-        let bytes = &file[inode_start..];
+        let name = if metadata.file_type == FileType::Symlink {
+            let target = image.read_link(nid)?;
+            format!("{name} -> {}", String::from_utf8_lossy(&target))
+        } else {
+            name
+        };
+
+        let size = if metadata.file_type.is_device() {
+            format!("{}, {}", metadata.rdev_major(), metadata.rdev_minor())
+        } else {
+            metadata.size.to_string()
+        };
 
-        let inode = if inode_first_byte & 1 == 1 {
-            // extended inode
-            let bytes = &bytes[..std::mem::size_of::<ExtendedInodeHeader>()];
-            let ext = unsafe { ptr::read_unaligned(bytes.as_ptr() as *const ExtendedInodeHeader) };
+        // Only down to the minute, like ls
+        let mtime = format_timestamp(metadata.mtime as i64, 0);
 
-            Inode::Extended(ext)
+        writeln!(
+            stdout,
+            "{} {:>3} {:>5} {:>5} {:>10} {} {name}",
+            mode_string(metadata.mode, metadata.file_type),
+            metadata.nlink,
+            metadata.uid,
+            metadata.gid,
+            size,
+            &mtime[..16],
+        )?;
+    }
+
+    Ok(())
+}
+
+fn cat(image: &Image, paths: &[String]) -> Result<()> {
+    let mut stdout = BufWriter::new(std::io::stdout().lock());
+
+    for path in paths {
+        let nid = image.resolve(path, true)?;
+
+        if image.inode(nid)?.is_dir() {
+            bail!("{path}: Is a directory");
+        }
+
+        image.copy_to(nid, &mut stdout)?;
+    }
+
+    stdout.flush()?;
+
+    Ok(())
+}
+
+/// Prints each path like coreutils `stat` would for the mounted image
+fn stat(image: &Image, paths: &[String]) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+
+    for path in paths {
+        let nid = image.resolve(path, false)?;
+        let metadata = image.metadata(nid)?;
+
+        let link_target = if metadata.file_type == FileType::Symlink {
+            Some(image.read_link(nid)?)
         } else {
-            let bytes = &bytes[..std::mem::size_of::<CompactInodeHeader>()];
-            let cpt = unsafe { ptr::read_unaligned(bytes.as_ptr() as *const CompactInodeHeader) };
+            None
+        };
+
+        write!(
+            stdout,
+            "{}",
+            Stat {
+                name: path,
+                metadata: &metadata,
+                link_target: link_target.as_deref(),
+            }
+        )?;
+    }
 
-            Inode::Compact(cpt)
+    Ok(())
+}
+
+fn tree(image: &Image, path: &str) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+
+    let entries = image.walk_from(path)?.collect::<Result<Vec<_>>>()?;
+
+    // Whether each entry is the last one in its directory, so the next entry at the same
+    // depth, if any, comes after everything below the parent
+    let last: Vec<bool> = (0..entries.len())
+        .map(|i| {
+            let depth = entries[i].depth;
+
+            entries[i + 1..]
+                .iter()
+                .find(|e| e.depth <= depth)
+                .is_none_or(|e| e.depth < depth)
+        })
+        .collect();
+
+    // For every depth, whether the ancestor at that depth was the last in its directory
+    let mut ancestors_last: Vec<bool> = vec![];
+
+    for (entry, &is_last) in entries.iter().zip(&last) {
+        if entry.depth == 0 {
+            writeln!(stdout, "{path}")?;
+            continue;
+        }
+
+        ancestors_last.truncate(entry.depth - 1);
+
+        let prefix: String = ancestors_last
+            .iter()
+            .map(|&last| if last { "    " } else { "│   " })
+            .collect();
+        let branch = if is_last { "└── " } else { "├── " };
+        let name = entry.path.rsplit('/').next().unwrap_or_default();
+
+        if entry.metadata.file_type == FileType::Symlink {
+            let target = image.read_link(entry.nid)?;
+            writeln!(
+                stdout,
+                "{prefix}{branch}{name} -> {}",
+                String::from_utf8_lossy(&target)
+            )?;
+        } else {
+            writeln!(stdout, "{prefix}{branch}{name}")?;
+        }
+
+        ancestors_last.push(is_last);
+    }
+
+    Ok(())
+}
+
+fn map(image: &Image, path: &str) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+
+    let nid = image.resolve(path, true)?;
+
+    writeln!(
+        stdout,
+        "{:>12} {:>12} {:>10} kind",
+        "logical", "physical", "length"
+    )?;
+
+    for extent in image.extents(nid)? {
+        let physical = match extent.kind {
+            ExtentKind::Hole => String::from("-"),
+            _ => extent.physical.to_string(),
         };
 
-        let dirents = inode.parse_inode(
-            inode.data_layout()?,
-            &file[inode_start..],
-            &file,
-            &superblock,
-        );
+        writeln!(
+            stdout,
+            "{:>12} {:>12} {:>10} {:?}",
+            extent.logical, physical, extent.len, extent.kind
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Same as getfattr's automatic encoding, text when it's printable, base64 otherwise
+fn encode_xattr_value(value: &[u8]) -> String {
+    // Text values like SELinux labels are usually NUL terminated
+    let text = value.strip_suffix(&[0]).unwrap_or(value);
+
+    if !text.iter().all(|c| c.is_ascii_graphic() || *c == b' ') {
+        return format!("0s{}", base64_encode(value));
+    }
+
+    let mut out = String::from("\"");
+
+    for c in text {
+        match c {
+            b'"' | b'\\' => out.push_str(&format!("\\{:03o}", c)),
+            _ => out.push(*c as char),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+fn getfattr(image: &Image, paths: &[String], name: Option<&str>, dump: bool) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+
+    for path in paths {
+        let nid = image.resolve(path, true)?;
+
+        let xattrs: Vec<_> = image
+            .xattrs(nid)?
+            .into_iter()
+            .filter(|x| name.is_none_or(|name| x.full_name() == name))
+            .collect();
+
+        if xattrs.is_empty() {
+            if let Some(name) = name {
+                bail!("{path}: {name}: No such attribute");
+            }
+
+            continue;
+        }
+
+        // getfattr strips the leading slash from absolute paths
+        match path.trim_start_matches('/') {
+            "" => writeln!(stdout, "# file: /")?,
+            relative => writeln!(stdout, "# file: {relative}")?,
+        }
+
+        for xattr in xattrs {
+            if dump || name.is_some() {
+                writeln!(
+                    stdout,
+                    "{}={}",
+                    xattr.full_name(),
+                    encode_xattr_value(&xattr.value)
+                )?;
+            } else {
+                writeln!(stdout, "{}", xattr.full_name())?;
+            }
+        }
+
+        writeln!(stdout)?;
+    }
+
+    Ok(())
+}
+
+/// Checks everything reachable in the image, returning the number of problems found
+fn fsck(image: &Image, scan: bool, objects: Option<&Path>, digests: bool) -> Result<usize> {
+    let mut stdout = std::io::stdout().lock();
+
+    let report = erofs::fsck::check(image);
+    let mut problems = report.problems;
+
+    for path in &report.skipped {
+        eprintln!("{path}: compressed, the data is not checked");
+    }
+
+    if scan {
+        problems.extend(erofs::fsck::scan(image));
+    }
+
+    if let Some(objects) = objects {
+        problems.extend(ObjectStore::open(objects)?.check(image, digests)?);
+    }
+
+    for problem in &problems {
+        writeln!(stdout, "{problem}")?;
+    }
+
+    Ok(problems.len())
+}
+
+fn debug(image: &Image) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+
+    writeln!(stdout, "superblock: {:#?}", image.superblock)?;
+
+    for entry in image.walk() {
+        let WalkEntry { path, nid, .. } = entry?;
+
+        writeln!(stdout, "{path} (nid {nid})")?;
+        writeln!(stdout, "{:#?}", image.inode(nid)?)?;
+
+        for xattr in image.xattrs(nid)? {
+            writeln!(stdout, "{xattr:?}")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reports every file carrying capabilities, an SELinux label or setuid/setgid bits
+fn audit(image: &Image) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+
+    for entry in image.walk() {
+        let WalkEntry { path, nid, .. } = entry?;
+        let inode = image.inode(nid)?;
+        let inode_data = image.inode_data(nid)?;
+
+        let mut findings = vec![];
+
+        if inode.mode() & S_ISUID != 0 {
+            findings.push(String::from("setuid"));
+        }
+
+        // setgid on directories only means new files inherit the group
+        if inode.mode() & S_ISGID != 0 && !inode.is_dir() {
+            findings.push(String::from("setgid"));
+        }
+
+        if let Some(caps) = inode.capabilities(inode_data, &image.data, &image.superblock)? {
+            findings.push(format!("caps={caps}"));
+        }
+
+        if let Some(label) = inode.selinux_label(inode_data, &image.data, &image.superblock)? {
+            findings.push(format!("selinux={label}"));
+        }
 
-        if !inode.is_dir() {
-            let xattrs = inode.get_xattrs(&file[inode_start..]);
+        if !findings.is_empty() {
+            writeln!(stdout, "{path}\t{}", findings.join("\t"))?;
         }
+    }
+
+    Ok(())
+}
+
+/// `SOURCE_DATE_EPOCH`, which reproducible builds set to pin timestamps
+fn source_date_epoch() -> Result<Option<u64>> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(secs) => {
+            Ok(Some(secs.parse().with_context(|| {
+                format!("Invalid SOURCE_DATE_EPOCH {secs:?}")
+            })?))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+fn random_uuid() -> Result<[u8; 16]> {
+    let mut uuid = [0; 16];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut uuid))
+        .context("Generating uuid")?;
+
+    // Version 4, variant 1
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+
+    Ok(uuid)
+}
+
+fn parse_uuid(uuid: &str) -> Result<[u8; 16]> {
+    hex_decode(&uuid.replace('-', ""))
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .with_context(|| format!("Invalid uuid {uuid:?}"))
+}
+
+fn apply_build_options(
+    mut builder: ImageBuilder,
+    options: &BuildOptions,
+    default_uuid: &str,
+) -> Result<ImageBuilder> {
+    if let Some(secs) = options.build_time.or(source_date_epoch()?) {
+        builder = builder.build_time(secs, 0);
+    }
+
+    builder = match options.uuid.as_deref().unwrap_or(default_uuid) {
+        "random" => builder.uuid(random_uuid()?),
+        "clear" => builder.uuid([0; 16]),
+        "derive" => builder.derive_uuid(),
+        uuid => builder.uuid(parse_uuid(uuid)?),
+    };
+
+    if let Some(label) = &options.label {
+        builder = builder.volume_name(label)?;
+    }
+
+    Ok(builder)
+}
+
+fn mkfs(source: &Path, image: &Path, options: &BuildOptions) -> Result<()> {
+    // A random uuid would make the build differ every time
+    let default_uuid = match source_date_epoch()? {
+        Some(_) => "derive",
+        None => "random",
+    };
+
+    let builder = apply_build_options(ImageBuilder::from_dir(source)?, options, default_uuid)?;
+
+    let file =
+        std::fs::File::create(image).with_context(|| format!("Creating {}", image.display()))?;
+    builder.write(std::io::BufWriter::new(file))?.flush()?;
+
+    Ok(())
+}
+
+fn mkcomposefs(
+    source: &Path,
+    image: &Path,
+    from_file: bool,
+    digest_store: Option<&Path>,
+    mode: ImportMode,
+    options: &BuildOptions,
+) -> Result<()> {
+    let builder = if !from_file {
+        let objects = digest_store.map(ObjectStore::create).transpose()?;
+
+        let mut builder = ImageBuilder::from_dir(source)?;
+        builder.import_objects(objects.as_ref(), mode)?;
+        builder
+    } else if source == Path::new("-") {
+        ImageBuilder::from_dump(std::io::stdin().lock())?
+    } else {
+        let file =
+            std::fs::File::open(source).with_context(|| format!("Opening {}", source.display()))?;
+        ImageBuilder::from_dump(std::io::BufReader::new(file))
+            .with_context(|| format!("Reading {}", source.display()))?
+    };
+    let builder = apply_build_options(builder.composefs(), options, "clear")?;
 
-        for dirent in dirents {
-            if dirent.name == "." || dirent.name == ".." {
-                continue;
+    let file =
+        std::fs::File::create(image).with_context(|| format!("Creating {}", image.display()))?;
+    builder.write(std::io::BufWriter::new(file))?.flush()?;
+
+    Ok(())
+}
+
+fn from_tar(
+    tar: &Path,
+    image: &Path,
+    objects: Option<&Path>,
+    options: &BuildOptions,
+) -> Result<()> {
+    let objects = objects.map(ObjectStore::create).transpose()?;
+
+    let builder = if tar == Path::new("-") {
+        ImageBuilder::from_tar(std::io::stdin().lock(), objects.as_ref())?
+    } else {
+        let file =
+            std::fs::File::open(tar).with_context(|| format!("Opening {}", tar.display()))?;
+        ImageBuilder::from_tar(std::io::BufReader::new(file), objects.as_ref())
+            .with_context(|| format!("Reading {}", tar.display()))?
+    };
+
+    let builder = match objects {
+        Some(_) => builder.composefs(),
+        None => builder,
+    };
+    let builder = apply_build_options(builder, options, "clear")?;
+
+    let file =
+        std::fs::File::create(image).with_context(|| format!("Creating {}", image.display()))?;
+    builder.write(std::io::BufWriter::new(file))?.flush()?;
+
+    Ok(())
+}
+
+/// Prints digests the way `fsverity measure` does
+fn measure(
+    image: Option<&Path>,
+    objects: Option<&Path>,
+    files: &[String],
+    algorithm: HashAlgorithm,
+    salt: Option<&str>,
+) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+
+    let salt = salt.map(hex_decode).transpose()?.unwrap_or_default();
+    let image = image.map(|image| open_image(image, objects)).transpose()?;
+
+    for file in files {
+        let digest = match &image {
+            Some(image) => {
+                let nid = image.resolve(file, true)?;
+                image
+                    .file_verity_digest(nid, algorithm, &salt)
+                    .with_context(|| format!("Measuring {file}"))?
             }
+            None => verity::file_digest(Path::new(file), algorithm, &salt)?,
+        };
+
+        writeln!(
+            stdout,
+            "{}:{} {file}",
+            algorithm.name(),
+            hex_encode(&digest)
+        )?;
+    }
+
+    Ok(())
+}
+
+fn verify(
+    image: &Path,
+    expected_digest: &str,
+    signature: Option<&Path>,
+    certificate: Option<&Path>,
+) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+
+    let expected = expected_digest
+        .strip_prefix("sha256:")
+        .unwrap_or(expected_digest);
+    let expected = hex_decode(expected).context("Parsing --expected-digest")?;
+
+    let digest = Image::open(image)?.verity_digest();
+    if digest != expected {
+        bail!(
+            "{} has fs-verity digest {}, expected {}",
+            image.display(),
+            hex_encode(&digest),
+            hex_encode(&expected)
+        );
+    }
+
+    if let (Some(signature), Some(certificate)) = (signature, certificate) {
+        verify_signature(&digest, signature, certificate)?;
+    }
+
+    writeln!(stdout, "{}: OK", image.display())?;
+
+    Ok(())
+}
+
+#[cfg(feature = "signatures")]
+fn verify_signature(digest: &[u8], signature: &Path, certificate: &Path) -> Result<()> {
+    let read =
+        |path: &Path| std::fs::read(path).with_context(|| format!("Reading {}", path.display()));
+
+    erofs::signature::verify_signature(
+        HashAlgorithm::Sha256,
+        digest,
+        &read(signature)?,
+        &read(certificate)?,
+    )
+}
 
-            nids.push(dirent.dirent.nid as usize);
+#[cfg(not(feature = "signatures"))]
+fn verify_signature(_digest: &[u8], _signature: &Path, _certificate: &Path) -> Result<()> {
+    bail!("Checking signatures needs erofs built with the signatures feature");
+}
+
+fn gc(objects: &Path, images: &[PathBuf], delete: bool) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+
+    let objects = ObjectStore::open(objects)?;
+
+    // Every image is read before anything is removed
+    let mut referenced = HashSet::new();
+    for image in images {
+        referenced.extend(objects.referenced(&Image::open(image)?)?);
+    }
+
+    let all = objects.list()?;
+    let total: u64 = all.iter().map(|(_, size)| size).sum();
+    let mut unreferenced = 0;
+    let mut unreferenced_bytes = 0;
+
+    for (path, size) in &all {
+        if referenced.contains(path) {
+            continue;
         }
+
+        writeln!(stdout, "{size}\t{}", path.display())?;
+        unreferenced += 1;
+        unreferenced_bytes += size;
+
+        if delete {
+            std::fs::remove_file(path).with_context(|| format!("Removing {}", path.display()))?;
+        }
+    }
+
+    if delete {
+        writeln!(
+            stdout,
+            "Removed {unreferenced} of {} objects, {unreferenced_bytes} of {total} bytes",
+            all.len()
+        )?;
+    } else {
+        writeln!(
+            stdout,
+            "{unreferenced} of {} objects are unreferenced, {unreferenced_bytes} of {total} bytes \
+             (dry run, use --delete to remove them)",
+            all.len()
+        )?;
     }
 
     Ok(())
 }
+
+#[cfg(feature = "json")]
+fn run_json(command: Command) -> Result<ExitCode> {
+    let mut stdout = std::io::stdout().lock();
+
+    use erofs::json::*;
+
+    let json = match command {
+        Command::Info { image } => to_string(&Info::new(&Image::open(&image)?)?)?,
+
+        Command::Ls { image, path, .. } => {
+            let image = Image::open(&image)?;
+            let mut entries = vec![];
+
+            for (name, nid) in list(&image, &path)? {
+                entries.push(ListEntry {
+                    name,
+                    metadata: image.metadata(nid)?,
+                });
+            }
+
+            to_string(&Listing { path, entries })?
+        }
+
+        Command::Stat { image, paths } => {
+            let image = Image::open(&image)?;
+            let mut files = vec![];
+
+            for path in paths {
+                let nid = image.resolve(&path, false)?;
+                let metadata = image.metadata(nid)?;
+
+                let link_target = if metadata.file_type == FileType::Symlink {
+                    Some(String::from_utf8_lossy(&image.read_link(nid)?).into_owned())
+                } else {
+                    None
+                };
+
+                files.push(FileStat {
+                    path,
+                    metadata,
+                    link_target,
+                });
+            }
+
+            to_string(&Stats { files })?
+        }
+
+        Command::Getfattr {
+            image, paths, name, ..
+        } => {
+            let image = Image::open(&image)?;
+            let mut files = vec![];
+
+            for path in paths {
+                let xattrs = image
+                    .xattrs(image.resolve(&path, true)?)?
+                    .iter()
+                    .filter(|x| name.as_ref().is_none_or(|name| &x.full_name() == name))
+                    .map(Xattr::from)
+                    .collect();
+
+                files.push(FileXattrs { path, xattrs });
+            }
+
+            to_string(&Xattrs { files })?
+        }
+
+        Command::Map { image, path } => {
+            let image = Image::open(&image)?;
+            let extents = image.extents(image.resolve(&path, true)?)?;
+
+            to_string(&ExtentMap { path, extents })?
+        }
+
+        _ => bail!("--json is only supported by info, ls, stat, getfattr and map"),
+    };
+
+    writeln!(stdout, "{json}")?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(not(feature = "json"))]
+fn run_json(_command: Command) -> Result<ExitCode> {
+    bail!("--json needs erofs to be built with the json feature")
+}
+
+fn run(command: Command, json: bool) -> Result<ExitCode> {
+    if json {
+        return run_json(command);
+    }
+
+    match command {
+        Command::Info { image } => info(&Image::open(&image)?)?,
+        Command::Ls { image, path, long } => ls(&Image::open(&image)?, &path, long)?,
+        Command::Cat {
+            image,
+            paths,
+            objects,
+        } => cat(&open_image(&image, objects.as_deref())?, &paths)?,
+        Command::Stat { image, paths } => stat(&Image::open(&image)?, &paths)?,
+        Command::Map { image, path } => map(&Image::open(&image)?, &path)?,
+        Command::Tree { image, path } => tree(&Image::open(&image)?, &path)?,
+        Command::Getfattr {
+            image,
+            paths,
+            name,
+            dump,
+        } => getfattr(&Image::open(&image)?, &paths, name.as_deref(), dump)?,
+        Command::Extract {
+            image,
+            dest,
+            numeric_owner,
+            no_owner,
+            objects,
+        } => {
+            let ownership = if numeric_owner {
+                Ownership::Numeric
+            } else if no_owner {
+                Ownership::None
+            } else {
+                Ownership::Auto
+            };
+
+            let image = open_image(&image, objects.as_deref())?;
+            let skipped = extract(&image, &dest, &ExtractOptions { ownership })?;
+
+            for skipped in skipped {
+                eprintln!("erofs: skipped {skipped}");
+            }
+        }
+        Command::Tar {
+            image,
+            output,
+            objects,
+        } => {
+            let image = open_image(&image, objects.as_deref())?;
+
+            match output {
+                Some(output) => {
+                    let file = std::fs::File::create(&output)
+                        .with_context(|| format!("Creating {}", output.display()))?;
+                    image.to_tar(std::io::BufWriter::new(file))?.flush()?;
+                }
+                None => {
+                    image.to_tar(std::io::stdout().lock())?.flush()?;
+                }
+            }
+        }
+        Command::Fsck {
+            image,
+            scan,
+            objects,
+            digests,
+        } => {
+            let problems = fsck(&Image::open(&image)?, scan, objects.as_deref(), digests)?;
+
+            if problems > 0 {
+                eprintln!("erofs: {problems} problems found");
+                return Ok(ExitCode::from(EXIT_PROBLEMS));
+            }
+        }
+        Command::Dump { image } => {
+            Image::open(&image)?
+                .to_dump(std::io::BufWriter::new(std::io::stdout().lock()))?
+                .flush()?;
+        }
+        Command::Mkfs {
+            source,
+            image,
+            options,
+        } => mkfs(&source, &image, &options)?,
+        Command::Mkcomposefs {
+            source,
+            image,
+            from_file,
+            digest_store,
+            reflink,
+            hardlink,
+            options,
+        } => {
+            let mode = if reflink {
+                ImportMode::Reflink
+            } else if hardlink {
+                ImportMode::Hardlink
+            } else {
+                ImportMode::Copy
+            };
+
+            mkcomposefs(
+                &source,
+                &image,
+                from_file,
+                digest_store.as_deref(),
+                mode,
+                &options,
+            )?
+        }
+        Command::FromTar {
+            tar,
+            image,
+            objects,
+            options,
+        } => from_tar(&tar, &image, objects.as_deref(), &options)?,
+        Command::Measure {
+            image,
+            objects,
+            files,
+            algorithm,
+            salt,
+        } => measure(
+            image.as_deref(),
+            objects.as_deref(),
+            &files,
+            algorithm,
+            salt.as_deref(),
+        )?,
+        Command::Verify {
+            image,
+            expected_digest,
+            signature,
+            certificate,
+        } => verify(
+            &image,
+            &expected_digest,
+            signature.as_deref(),
+            certificate.as_deref(),
+        )?,
+        Command::Gc {
+            objects,
+            images,
+            delete,
+        } => gc(&objects, &images, delete)?,
+        Command::Debug { image } => debug(&Image::open(&image)?)?,
+        Command::Audit { image } => audit(&Image::open(&image)?)?,
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match run(cli.command, cli.json) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("erofs: {e:#}");
+            ExitCode::from(EXIT_FAILURE)
+        }
+    }
+}
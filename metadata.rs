@@ -0,0 +1,241 @@
+use anyhow::{Result, bail};
+
+use crate::inode::*;
+use crate::sb::Superblock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+#[cfg_attr(feature = "json", serde(rename_all = "snake_case"))]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+}
+
+impl TryFrom<u16> for FileType {
+    type Error = anyhow::Error;
+
+    /// From the `S_IFMT` bits of a mode
+    fn try_from(mode: u16) -> Result<Self, Self::Error> {
+        match mode & S_IFMT {
+            S_IFREG => Ok(FileType::Regular),
+            S_IFDIR => Ok(FileType::Directory),
+            S_IFLNK => Ok(FileType::Symlink),
+            S_IFCHR => Ok(FileType::CharDevice),
+            S_IFBLK => Ok(FileType::BlockDevice),
+            S_IFIFO => Ok(FileType::Fifo),
+            S_IFSOCK => Ok(FileType::Socket),
+            _ => bail!("Invalid file type in mode {mode:#o}"),
+        }
+    }
+}
+
+impl FileType {
+    pub fn is_device(&self) -> bool {
+        matches!(self, FileType::CharDevice | FileType::BlockDevice)
+    }
+
+    /// The `S_IFMT` bits of a mode
+    pub fn mode_bits(&self) -> u16 {
+        match self {
+            FileType::Regular => S_IFREG,
+            FileType::Directory => S_IFDIR,
+            FileType::Symlink => S_IFLNK,
+            FileType::CharDevice => S_IFCHR,
+            FileType::BlockDevice => S_IFBLK,
+            FileType::Fifo => S_IFIFO,
+            FileType::Socket => S_IFSOCK,
+        }
+    }
+
+    /// The `file_type` a dirent pointing at this should have
+    pub fn dirent_type(&self) -> u8 {
+        match self {
+            FileType::Regular => EROFS_FT_REG_FILE,
+            FileType::Directory => EROFS_FT_DIR,
+            FileType::Symlink => EROFS_FT_SYMLINK,
+            FileType::CharDevice => EROFS_FT_CHRDEV,
+            FileType::BlockDevice => EROFS_FT_BLKDEV,
+            FileType::Fifo => EROFS_FT_FIFO,
+            FileType::Socket => EROFS_FT_SOCK,
+        }
+    }
+}
+
+/// Everything `stat` would tell about an inode, the same for compact and extended inodes
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct Metadata {
+    pub nid: u64,
+    pub file_type: FileType,
+    /// Full mode, including the file type bits
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u32,
+    pub ino: u32,
+    pub size: u64,
+    pub mtime: u64,
+    pub mtime_nsec: u32,
+    /// Device number in the same encoding as `st_rdev`, 0 for anything not a device
+    pub rdev: u64,
+    pub block_size: u64,
+}
+
+impl Metadata {
+    pub fn new(inode: &Inode, nid: u64, superblock: &Superblock) -> Result<Self> {
+        let file_type = FileType::try_from(inode.mode())?;
+        let (mtime, mtime_nsec) = inode.mtime(superblock);
+
+        let rdev = if file_type.is_device() {
+            decode_dev(inode.u())
+        } else {
+            0
+        };
+
+        Ok(Metadata {
+            nid,
+            file_type,
+            mode: inode.mode(),
+            uid: inode.uid(),
+            gid: inode.gid(),
+            nlink: inode.nlink(),
+            ino: inode.ino(),
+            size: inode.size(),
+            mtime,
+            mtime_nsec,
+            rdev,
+            block_size: 1 << superblock.blkszbits,
+        })
+    }
+
+    /// Permission bits, including setuid/setgid/sticky
+    pub fn permissions(&self) -> u16 {
+        self.mode & !S_IFMT
+    }
+
+    pub fn rdev_major(&self) -> u32 {
+        (((self.rdev >> 32) & 0xfffff000) | ((self.rdev >> 8) & 0xfff)) as u32
+    }
+
+    pub fn rdev_minor(&self) -> u32 {
+        (((self.rdev >> 12) & 0xffffff00) | (self.rdev & 0xff)) as u32
+    }
+}
+
+/// Same as `std::os::unix::fs::MetadataExt`, so inodes in an image can be compared with files
+/// on a live filesystem
+pub trait MetadataExt {
+    fn dev(&self) -> u64;
+    fn ino(&self) -> u64;
+    fn mode(&self) -> u32;
+    fn nlink(&self) -> u64;
+    fn uid(&self) -> u32;
+    fn gid(&self) -> u32;
+    fn rdev(&self) -> u64;
+    fn size(&self) -> u64;
+    fn atime(&self) -> i64;
+    fn atime_nsec(&self) -> i64;
+    fn mtime(&self) -> i64;
+    fn mtime_nsec(&self) -> i64;
+    fn ctime(&self) -> i64;
+    fn ctime_nsec(&self) -> i64;
+    fn blksize(&self) -> u64;
+    fn blocks(&self) -> u64;
+}
+
+// Reports what the kernel's EROFS driver reports for a mounted image
+impl MetadataExt for Metadata {
+    /// An image on its own isn't on any device
+    fn dev(&self) -> u64 {
+        0
+    }
+
+    /// The kernel uses the nid as the inode number, the on-disk `ino` is only for 32-bit stat
+    fn ino(&self) -> u64 {
+        self.nid
+    }
+
+    fn mode(&self) -> u32 {
+        self.mode.into()
+    }
+
+    fn nlink(&self) -> u64 {
+        self.nlink.into()
+    }
+
+    fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    fn rdev(&self) -> u64 {
+        self.rdev
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    // EROFS only stores one timestamp, it's used for all three
+
+    fn atime(&self) -> i64 {
+        self.mtime as i64
+    }
+
+    fn atime_nsec(&self) -> i64 {
+        self.mtime_nsec.into()
+    }
+
+    fn mtime(&self) -> i64 {
+        self.mtime as i64
+    }
+
+    fn mtime_nsec(&self) -> i64 {
+        self.mtime_nsec.into()
+    }
+
+    fn ctime(&self) -> i64 {
+        self.mtime as i64
+    }
+
+    fn ctime_nsec(&self) -> i64 {
+        self.mtime_nsec.into()
+    }
+
+    fn blksize(&self) -> u64 {
+        self.block_size
+    }
+
+    /// In 512 byte units, the size rounded up to whole blocks
+    fn blocks(&self) -> u64 {
+        self.size.next_multiple_of(self.block_size) >> 9
+    }
+}
+
+/// Device nodes keep their device number in `i_u`, in the kernel's `new_encode_dev` format
+fn decode_dev(dev: u32) -> u64 {
+    let major = ((dev & 0xfff00) >> 8) as u64;
+    let minor = ((dev & 0xff) | ((dev >> 12) & 0xfff00)) as u64;
+
+    // glibc's makedev
+    ((major & 0xfffff000) << 32)
+        | ((major & 0xfff) << 8)
+        | ((minor & 0xffffff00) << 12)
+        | (minor & 0xff)
+}
+
+/// The inverse of [`decode_dev`], from an `st_rdev` to what goes in `i_u`
+pub(crate) fn encode_dev(rdev: u64) -> u32 {
+    let major = (((rdev >> 32) & 0xfffff000) | ((rdev >> 8) & 0xfff)) as u32;
+    let minor = (((rdev >> 12) & 0xffffff00) | (rdev & 0xff)) as u32;
+
+    (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+}
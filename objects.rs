@@ -0,0 +1,615 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Context, Result, bail};
+use rustix::io::Errno;
+
+use crate::composefs::*;
+use crate::fsck::Problem;
+use crate::image::Image;
+use crate::metadata::FileType;
+use crate::utils::hex_encode;
+use crate::verity::{self, FsVerityHasher, HashAlgorithm};
+
+/// Where the contents of a composefs file are, from its overlay xattrs
+#[derive(Debug, Clone)]
+pub struct BackingObject {
+    /// Path of the object, relative to the store even though it starts with `/`
+    pub redirect: Vec<u8>,
+    /// The fs-verity digest the object must have, if the metacopy has one
+    pub digest: Option<Vec<u8>>,
+}
+
+impl Image {
+    /// The object a composefs file's contents are in, `None` for files without a redirect
+    pub fn backing_object(&self, nid: u64) -> Result<Option<BackingObject>> {
+        let mut redirect = None;
+        let mut digest = None;
+
+        for xattr in self.xattrs(nid)? {
+            match xattr.full_name().as_str() {
+                OVERLAY_REDIRECT => redirect = Some(xattr.value),
+                OVERLAY_METACOPY => digest = metacopy_digest(&xattr.value)?.map(<[u8]>::to_vec),
+                _ => {}
+            }
+        }
+
+        Ok(redirect.map(|redirect| BackingObject { redirect, digest }))
+    }
+
+    /// Whether the xattr only points at the object store, which is left out once the contents
+    /// are read from there
+    pub fn is_object_xattr(&self, name: &str) -> bool {
+        self.objects().is_some() && (name == OVERLAY_REDIRECT || name == OVERLAY_METACOPY)
+    }
+}
+
+/// Files up to this size stay in a composefs image instead of going to the object store
+pub const INLINE_MAX: u64 = 64;
+
+/// How [`ObjectStore::import`] puts a host file in the store
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImportMode {
+    #[default]
+    Copy,
+    /// Share the data with the original where the file system can, copy it otherwise
+    Reflink,
+    /// Make the object another name for the original, changing one changes both. Copies where
+    /// that isn't possible, like across file systems
+    Hardlink,
+}
+
+/// A composefs object directory, where file contents are stored as `xx/yyyy...` by their
+/// fs-verity digest
+pub struct ObjectStore {
+    root: PathBuf,
+}
+
+impl ObjectStore {
+    /// Opens the existing store at `root`
+    #[fn_error_context::context("Opening object store {}", root.display())]
+    pub fn open(root: &Path) -> Result<Self> {
+        if !std::fs::metadata(root)?.is_dir() {
+            bail!("Not a directory");
+        }
+
+        Ok(ObjectStore {
+            root: root.to_path_buf(),
+        })
+    }
+
+    /// Opens the store at `root`, creating the directory if it doesn't exist
+    #[fn_error_context::context("Creating object store {}", root.display())]
+    pub fn create(root: &Path) -> Result<Self> {
+        std::fs::create_dir_all(root)?;
+
+        Ok(ObjectStore {
+            root: root.to_path_buf(),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where the object with `digest` is, whether it exists or not
+    pub fn path(&self, digest: &[u8]) -> PathBuf {
+        self.root.join(object_path(digest))
+    }
+
+    /// The path of the object a redirect points at. It has to stay inside the store
+    pub fn resolve(&self, redirect: &[u8]) -> Result<PathBuf> {
+        let components: Vec<&[u8]> = redirect
+            .split(|c| *c == b'/')
+            .filter(|c| !c.is_empty())
+            .collect();
+
+        if components.is_empty() || components.iter().any(|c| *c == b"." || *c == b"..") {
+            bail!("Invalid redirect {:?}", String::from_utf8_lossy(redirect));
+        }
+
+        Ok(self.root.join(OsStr::from_bytes(&components.join(&b'/'))))
+    }
+
+    /// Checks that every file in `image` with a redirect has its object here, and that the
+    /// object is as large as the file. With `digests` the fs-verity digest of every object is
+    /// computed too and compared to the one in the metacopy
+    #[fn_error_context::context("Checking objects in {}", self.root.display())]
+    pub fn check(&self, image: &Image, digests: bool) -> Result<Vec<Problem>> {
+        let mut problems = vec![];
+
+        for entry in image.walk() {
+            let entry = entry?;
+
+            if entry.hardlink_of.is_some() || entry.metadata.file_type != FileType::Regular {
+                continue;
+            }
+
+            let Some(object) = image.backing_object(entry.nid)? else {
+                continue;
+            };
+
+            if let Some(message) = self.check_object(&object, entry.metadata.size, digests) {
+                problems.push(Problem {
+                    path: Some(entry.path),
+                    message,
+                });
+            }
+        }
+
+        Ok(problems)
+    }
+
+    /// What's wrong with the object, if anything
+    fn check_object(&self, object: &BackingObject, size: u64, digests: bool) -> Option<String> {
+        let path = match self.resolve(&object.redirect) {
+            Ok(path) => path,
+            Err(e) => return Some(format!("{e:#}")),
+        };
+
+        let metadata = match std::fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Some(format!("Object {} is missing", path.display()));
+            }
+            Err(e) => return Some(format!("Object {}: {e}", path.display())),
+        };
+
+        if !metadata.is_file() {
+            return Some(format!("Object {} is not a regular file", path.display()));
+        }
+
+        if metadata.len() != size {
+            return Some(format!(
+                "Object {} is {} bytes, the file is {size}",
+                path.display(),
+                metadata.len()
+            ));
+        }
+
+        if let (true, Some(expected)) = (digests, &object.digest) {
+            let digest = HashAlgorithm::for_digest(expected)
+                .and_then(|algorithm| verity::file_digest(&path, algorithm, &[]));
+
+            match digest {
+                Ok(digest) if digest == *expected => {}
+                Ok(digest) => {
+                    return Some(format!(
+                        "Object {} has fs-verity digest {}, expected {}",
+                        path.display(),
+                        hex_encode(&digest),
+                        hex_encode(expected)
+                    ));
+                }
+                Err(e) => return Some(format!("{e:#}")),
+            }
+        }
+
+        None
+    }
+
+    /// The objects every file with a redirect in `image` points at
+    pub fn referenced(&self, image: &Image) -> Result<HashSet<PathBuf>> {
+        let mut referenced = HashSet::new();
+
+        for entry in image.walk() {
+            let entry = entry?;
+
+            if entry.hardlink_of.is_some() || entry.metadata.file_type != FileType::Regular {
+                continue;
+            }
+
+            if let Some(object) = image.backing_object(entry.nid)? {
+                referenced.insert(self.resolve(&object.redirect)?);
+            }
+        }
+
+        Ok(referenced)
+    }
+
+    /// Every object in the store with its size, the files in the `xx/` directories. Anything
+    /// starting with `.` is left out, like leftovers of [`ObjectStore::insert`]
+    #[fn_error_context::context("Listing objects in {}", self.root.display())]
+    pub fn list(&self) -> Result<Vec<(PathBuf, u64)>> {
+        let mut objects = vec![];
+
+        for dir in std::fs::read_dir(&self.root)? {
+            let dir = dir?;
+            let name = dir.file_name();
+
+            if name.len() != 2
+                || !name.as_bytes().iter().all(u8::is_ascii_hexdigit)
+                || !dir.file_type()?.is_dir()
+            {
+                continue;
+            }
+
+            for object in std::fs::read_dir(dir.path())? {
+                let object = object?;
+                let metadata = object.metadata()?;
+
+                if object.file_name().as_bytes().starts_with(b".") || !metadata.is_file() {
+                    continue;
+                }
+
+                objects.push((object.path(), metadata.len()));
+            }
+        }
+
+        objects.sort();
+
+        Ok(objects)
+    }
+
+    /// Objects are written under a name of their own and renamed into place once they are
+    /// complete, so other threads and processes writing to the store don't get in the way
+    fn temp_path(&self) -> PathBuf {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        self.root.join(format!(
+            ".tmp-object-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+
+    /// Copies everything from `reader` into the store. Returns the digest and the size, objects
+    /// that are already there are left alone
+    pub fn insert<R: Read>(&self, reader: R) -> Result<(Vec<u8>, u64)> {
+        let temp = self.temp_path();
+
+        let result = self.insert_from(reader, &temp);
+
+        // Only left over if the object was there already or something failed
+        let _ = std::fs::remove_file(&temp);
+
+        result
+    }
+
+    fn insert_from<R: Read>(&self, mut reader: R, temp: &Path) -> Result<(Vec<u8>, u64)> {
+        let mut file = BufWriter::new(
+            File::create(temp).with_context(|| format!("Creating {}", temp.display()))?,
+        );
+        let mut hasher = FsVerityHasher::new();
+        let mut buf = vec![0; 1 << 16];
+        let mut size = 0;
+
+        loop {
+            let len = reader.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+
+            hasher.update(&buf[..len]);
+            file.write_all(&buf[..len])
+                .with_context(|| format!("Writing {}", temp.display()))?;
+            size += len as u64;
+        }
+        file.flush()
+            .with_context(|| format!("Writing {}", temp.display()))?;
+
+        let digest = hasher.finalize();
+        self.place(temp, &digest)?;
+
+        Ok((digest, size))
+    }
+
+    /// Moves the finished object at `temp` to where objects with `digest` go, unless one is
+    /// there already
+    fn place(&self, temp: &Path, digest: &[u8]) -> Result<()> {
+        let path = self.path(digest);
+
+        if !path.exists() {
+            let dir = path.parent().expect("objects are in a subdirectory");
+            std::fs::create_dir_all(dir).with_context(|| format!("Creating {}", dir.display()))?;
+            std::fs::rename(temp, &path)
+                .with_context(|| format!("Moving object to {}", path.display()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds the host file at `path` to the store, unless an object with the same contents is
+    /// there already. Returns the digest
+    #[fn_error_context::context("Importing {}", path.display())]
+    pub fn import(&self, path: &Path, mode: ImportMode) -> Result<Vec<u8>> {
+        let temp = self.temp_path();
+
+        let result = self.import_to(path, mode, &temp);
+
+        // Only left over if the object was there already or something failed
+        let _ = std::fs::remove_file(&temp);
+
+        result
+    }
+
+    fn import_to(&self, path: &Path, mode: ImportMode, temp: &Path) -> Result<Vec<u8>> {
+        let source = File::open(path).context("Opening file")?;
+
+        let shared = match mode {
+            ImportMode::Copy => false,
+            ImportMode::Reflink => reflink(&source, temp)?,
+            ImportMode::Hardlink => hardlink(path, temp)?,
+        };
+
+        if !shared {
+            return Ok(self.insert_from(source, temp)?.0);
+        }
+
+        // The digest is of what ended up in the store, the original may have changed since
+        let digest = verity::file_digest(temp, HashAlgorithm::Sha256, &[])?;
+        self.place(temp, &digest)?;
+
+        Ok(digest)
+    }
+}
+
+/// Clones `source` to `to`, false where the file system can't share the data
+fn reflink(source: &File, to: &Path) -> Result<bool> {
+    let dest = File::create(to).with_context(|| format!("Creating {}", to.display()))?;
+
+    match rustix::fs::ioctl_ficlone(&dest, source) {
+        Ok(()) => Ok(true),
+        // Different file systems, or one without reflinks
+        Err(Errno::XDEV | Errno::OPNOTSUPP | Errno::INVAL | Errno::NOTTY) => Ok(false),
+        Err(e) => {
+            Err(std::io::Error::from(e)).with_context(|| format!("Cloning into {}", to.display()))
+        }
+    }
+}
+
+/// Links `from` as `to`, false where the file system can't do that
+fn hardlink(from: &Path, to: &Path) -> Result<bool> {
+    match std::fs::hard_link(from, to) {
+        Ok(()) => Ok(true),
+        // A different file system, or one that doesn't allow links to the file
+        Err(e) if matches!(Errno::from_io_error(&e), Some(Errno::XDEV | Errno::PERM)) => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("Linking {}", to.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{Content, FileData, ImageBuilder, Node, ROOT};
+
+    /// An empty store in a directory of its own
+    fn store(name: &str) -> ObjectStore {
+        let root =
+            std::env::temp_dir().join(format!("erofs-objects-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+
+        ObjectStore::create(&root).unwrap()
+    }
+
+    /// Name, size, redirect and digest of a file with its contents in the store
+    type External<'a> = (&'a str, u64, Option<&'a str>, Option<&'a [u8]>);
+
+    /// A composefs image with these files in the root
+    fn image(files: &[External]) -> Image {
+        let mut builder = ImageBuilder::new().composefs();
+
+        for (name, size, redirect, digest) in files {
+            let data = FileData::External {
+                size: *size,
+                redirect: redirect.map(String::from),
+                digest: digest.map(<[u8]>::to_vec),
+            };
+            let node = Node::new(0o644, Content::Regular(data));
+            builder.insert(ROOT, name.as_bytes(), node).unwrap();
+        }
+
+        Image::from_bytes(builder.write(vec![]).unwrap()).unwrap()
+    }
+
+    fn messages(problems: Vec<Problem>) -> Vec<String> {
+        problems.iter().map(Problem::to_string).collect()
+    }
+
+    #[test]
+    fn check() {
+        let store = store("check");
+
+        let (good, _) = store.insert(&b"good"[..]).unwrap();
+        let missing = verity::digest(b"missing");
+        let (short, _) = store.insert(&b"short"[..]).unwrap();
+
+        // Where the object for "wrong" should be, but with other contents of the same size
+        let wrong = verity::digest(b"wrong");
+        let (other, _) = store.insert(&b"other"[..]).unwrap();
+        std::fs::create_dir_all(store.path(&wrong).parent().unwrap()).unwrap();
+        std::fs::rename(store.path(&other), store.path(&wrong)).unwrap();
+
+        let image = image(&[
+            ("good", 4, None, Some(&good)),
+            ("missing", 7, None, Some(&missing)),
+            ("short", 6, None, Some(&short)),
+            ("wrong", 5, None, Some(&wrong)),
+            ("escape", 5, Some("ab/../../escape"), None),
+        ]);
+
+        let path = |digest: &[u8]| store.path(digest).display().to_string();
+        let expected = [
+            "/escape: Invalid redirect \"/ab/../../escape\"".to_string(),
+            format!("/missing: Object {} is missing", path(&missing)),
+            format!("/short: Object {} is 5 bytes, the file is 6", path(&short)),
+        ];
+        assert_eq!(messages(store.check(&image, false).unwrap()), expected);
+
+        let mut expected = expected.to_vec();
+        expected.push(format!(
+            "/wrong: Object {} has fs-verity digest {}, expected {}",
+            path(&wrong),
+            hex_encode(&verity::digest(b"other")),
+            hex_encode(&wrong)
+        ));
+        assert_eq!(messages(store.check(&image, true).unwrap()), expected);
+
+        std::fs::remove_dir_all(store.root()).unwrap();
+    }
+
+    #[test]
+    fn resolve() {
+        let store = ObjectStore {
+            root: PathBuf::from("/objects"),
+        };
+
+        for (redirect, path) in [
+            (&b"/ab/cdef"[..], "/objects/ab/cdef"),
+            (b"ab/cdef", "/objects/ab/cdef"),
+            (b"//ab//cdef/", "/objects/ab/cdef"),
+            (b"/ab/..cdef", "/objects/ab/..cdef"),
+        ] {
+            assert_eq!(store.resolve(redirect).unwrap(), PathBuf::from(path));
+        }
+
+        // Nothing that could leave the store
+        for redirect in [
+            &b""[..],
+            b"/",
+            b"..",
+            b"/../etc/passwd",
+            b"/ab/../../etc/passwd",
+            b"/ab/./cdef",
+            b"/ab/cdef/..",
+        ] {
+            assert!(store.resolve(redirect).is_err(), "{redirect:?}");
+        }
+    }
+
+    #[test]
+    fn unreferenced() {
+        let store = store("unreferenced");
+
+        let digests: Vec<_> = ["a", "b", "c", "d"]
+            .into_iter()
+            .map(|contents| store.insert(contents.as_bytes()).unwrap().0)
+            .collect();
+
+        // Not objects, whatever they are
+        std::fs::write(store.root().join(".tmp-object-1-0"), "tmp").unwrap();
+        std::fs::write(store.path(&digests[0]).with_file_name(".tmp"), "tmp").unwrap();
+        std::fs::create_dir(store.root().join("xy")).unwrap();
+        std::fs::write(store.root().join("xy/file"), "file").unwrap();
+
+        // "c" with a redirect spelled differently from what its path in the store is
+        let c = format!("//{}", object_path(&digests[2]).replace('/', "//"));
+        let images = [
+            image(&[
+                ("a", 1, None, Some(&digests[0])),
+                ("b", 1, None, Some(&digests[1])),
+            ]),
+            image(&[("b", 1, None, Some(&digests[1])), ("c", 1, Some(&c), None)]),
+        ];
+
+        let all = store.list().unwrap();
+        let mut expected: Vec<_> = digests.iter().map(|d| (store.path(d), 1)).collect();
+        expected.sort();
+        assert_eq!(all, expected);
+
+        let mut referenced = HashSet::new();
+        for image in &images {
+            referenced.extend(store.referenced(image).unwrap());
+        }
+
+        let unreferenced: Vec<_> = all
+            .into_iter()
+            .filter(|(path, _)| !referenced.contains(path))
+            .collect();
+        assert_eq!(unreferenced, [(store.path(&digests[3]), 1)]);
+
+        std::fs::remove_dir_all(store.root()).unwrap();
+    }
+
+    /// The names in the store's directory, to see that no temporary files are left
+    fn entries(store: &ObjectStore) -> Vec<String> {
+        let mut names: Vec<_> = std::fs::read_dir(store.root())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn import_dedup() {
+        let store = store("dedup");
+        let source = store.root().with_extension("src");
+        std::fs::create_dir_all(&source).unwrap();
+
+        let contents = vec![7; 100_000];
+        std::fs::write(source.join("a"), &contents).unwrap();
+        std::fs::write(source.join("b"), &contents).unwrap();
+
+        let a = store.import(&source.join("a"), ImportMode::Copy).unwrap();
+        let b = store.import(&source.join("b"), ImportMode::Copy).unwrap();
+
+        assert_eq!(a, verity::digest(&contents));
+        assert_eq!(a, b);
+        assert_eq!(store.list().unwrap(), [(store.path(&a), 100_000)]);
+        assert_eq!(std::fs::read(store.path(&a)).unwrap(), contents);
+        assert_eq!(entries(&store), [hex_encode(&a[..1])]);
+
+        std::fs::remove_dir_all(store.root()).unwrap();
+        std::fs::remove_dir_all(&source).unwrap();
+    }
+
+    /// Imports a new file into `store` with `mode`, returns the link count of the file after
+    fn import_links(store: &ObjectStore, source: &Path, mode: ImportMode) -> u64 {
+        use std::os::unix::fs::MetadataExt;
+
+        let contents = format!("{mode:?} {}", source.display()).into_bytes();
+        std::fs::write(source, &contents).unwrap();
+
+        let digest = store.import(source, mode).unwrap();
+        assert_eq!(digest, verity::digest(&contents));
+        assert_eq!(std::fs::read(store.path(&digest)).unwrap(), contents);
+        assert_eq!(entries(store), [hex_encode(&digest[..1])]);
+
+        let nlink = std::fs::metadata(source).unwrap().nlink();
+        std::fs::remove_dir_all(store.path(&digest).parent().unwrap()).unwrap();
+
+        nlink
+    }
+
+    #[test]
+    fn import_modes() {
+        let store = store("modes");
+        let source = store.root().with_extension("src");
+
+        assert_eq!(import_links(&store, &source, ImportMode::Copy), 1);
+        assert_eq!(import_links(&store, &source, ImportMode::Hardlink), 2);
+        // Cloned or copied, depending on the file system, but never linked
+        assert_eq!(import_links(&store, &source, ImportMode::Reflink), 1);
+
+        std::fs::remove_dir_all(store.root()).unwrap();
+        std::fs::remove_file(&source).unwrap();
+    }
+
+    #[test]
+    fn import_across_file_systems() {
+        use std::os::unix::fs::MetadataExt;
+
+        let store = store("across");
+        let shm = Path::new("/dev/shm");
+        let device = |path: &Path| std::fs::metadata(path).map(|m| m.dev()).ok();
+
+        if device(shm).is_none() || device(shm) == device(store.root()) {
+            eprintln!("no other file system to import from");
+            return;
+        }
+
+        // Neither links nor clones work, so both copy
+        let source = shm.join(format!("erofs-objects-across-{}", std::process::id()));
+        assert_eq!(import_links(&store, &source, ImportMode::Hardlink), 1);
+        assert_eq!(import_links(&store, &source, ImportMode::Reflink), 1);
+
+        std::fs::remove_dir_all(store.root()).unwrap();
+        std::fs::remove_file(&source).unwrap();
+    }
+}
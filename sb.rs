@@ -1,12 +1,29 @@
 use crate::utils::*;
-use anyhow::Result;
 use std::fmt::Debug;
 
-const MAGIC_V1: u32 = 0xE0F5E1E2;
-const BLOCK_BITS: u8 = 12;
-
-const FEATURE_COMPAT_MTIME: u32 = 2;
-const FEATURE_COMPAT_XATTR_FILTER: u32 = 4;
+pub const MAGIC_V1: u32 = 0xE0F5E1E2;
+/// The only block size this crate writes, and the one composefs uses
+pub const BLOCK_BITS: u8 = 12;
+/// Block sizes from 512 bytes to 64KiB, images with other sizes can't be read
+pub const MIN_BLOCK_BITS: u8 = 9;
+pub const MAX_BLOCK_BITS: u8 = 16;
+
+pub const FEATURE_COMPAT_SB_CHKSUM: u32 = 1;
+pub const FEATURE_COMPAT_MTIME: u32 = 2;
+pub const FEATURE_COMPAT_XATTR_FILTER: u32 = 4;
+
+pub const FEATURE_INCOMPAT_ZERO_PADDING: u32 = 0x01;
+pub const FEATURE_INCOMPAT_COMPR_CFGS: u32 = 0x02;
+pub const FEATURE_INCOMPAT_CHUNKED_FILE: u32 = 0x04;
+pub const FEATURE_INCOMPAT_DEVICE_TABLE: u32 = 0x08;
+pub const FEATURE_INCOMPAT_ZTAILPACKING: u32 = 0x10;
+pub const FEATURE_INCOMPAT_FRAGMENTS: u32 = 0x20;
+pub const FEATURE_INCOMPAT_XATTR_PREFIXES: u32 = 0x40;
+
+/// Incompat features this crate knows how to read, zero padding only matters for compressed
+/// data
+pub const FEATURE_INCOMPAT_SUPPORTED: u32 =
+    FEATURE_INCOMPAT_ZERO_PADDING | FEATURE_INCOMPAT_CHUNKED_FILE;
 
 #[repr(C)]
 pub struct Superblock {
@@ -42,6 +59,75 @@ pub struct Superblock {
     pub reserved2: [u8; 23],
 }
 
+impl Superblock {
+    pub fn to_bytes(&self) -> [u8; 128] {
+        let mut bytes = [0; 128];
+
+        bytes[0..4].copy_from_slice(&self.magic.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.feature_compat.to_le_bytes());
+        bytes[12] = self.blkszbits;
+        bytes[13] = self.extslots;
+        bytes[14..16].copy_from_slice(&self.root_nid.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.inos.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.build_time.to_le_bytes());
+        bytes[32..36].copy_from_slice(&self.build_time_nsec.to_le_bytes());
+        bytes[36..40].copy_from_slice(&self.blocks.to_le_bytes());
+        bytes[40..44].copy_from_slice(&self.meta_blkaddr.to_le_bytes());
+        bytes[44..48].copy_from_slice(&self.xattr_blkaddr.to_le_bytes());
+        bytes[48..64].copy_from_slice(&self.uuid);
+        bytes[64..80].copy_from_slice(&self.volume_name);
+        bytes[80..84].copy_from_slice(&self.feature_incompat.to_le_bytes());
+        bytes[84..86].copy_from_slice(&self.available_compr_algs.to_le_bytes());
+        bytes[86..88].copy_from_slice(&self.extra_devices.to_le_bytes());
+        bytes[88..90].copy_from_slice(&self.devt_slotoff.to_le_bytes());
+        bytes[90] = self.dirblkbits;
+        bytes[91] = self.xattr_prefix_count;
+        bytes[92..96].copy_from_slice(&self.xattr_prefix_start.to_le_bytes());
+        bytes[96..104].copy_from_slice(&self.packed_nid.to_le_bytes());
+        bytes[104] = self.xattr_filter_reserved;
+        bytes[105..128].copy_from_slice(&self.reserved2);
+
+        bytes
+    }
+
+    /// Names of the compat features that are set, unknown bits are left out
+    pub fn compat_feature_names(&self) -> Vec<&'static str> {
+        [
+            (FEATURE_COMPAT_SB_CHKSUM, "sb_chksum"),
+            (FEATURE_COMPAT_MTIME, "mtime"),
+            (FEATURE_COMPAT_XATTR_FILTER, "xattr_filter"),
+        ]
+        .into_iter()
+        .filter(|(bit, _)| self.feature_compat & bit != 0)
+        .map(|(_, name)| name)
+        .collect()
+    }
+
+    /// The uuid in the usual 8-4-4-4-12 form
+    pub fn uuid_string(&self) -> String {
+        let hex = hex_encode(&self.uuid);
+        format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    }
+
+    /// The volume name up to the first NUL
+    pub fn volume_name(&self) -> String {
+        let name = self
+            .volume_name
+            .split(|b| *b == 0)
+            .next()
+            .unwrap_or_default();
+        String::from_utf8_lossy(name).into_owned()
+    }
+}
+
 impl Debug for Superblock {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "Superblock {{ ")?;
@@ -73,39 +159,11 @@ impl Debug for Superblock {
     }
 }
 
-pub fn assert_superblock(mut superblock: &[u8]) -> Result<()> {
-    // First is superblock magic
-    assert_eq!(u32_le(superblock, "superblock_magic")?, MAGIC_V1);
-    superblock = &superblock[4..];
-
-    assert_eq!(u32_le(superblock, "sb_checksum")?, 0);
-    superblock = &superblock[4..];
-
-    assert_eq!(
-        u32_le(superblock, "sb_feature_compat")?,
-        FEATURE_COMPAT_MTIME | FEATURE_COMPAT_XATTR_FILTER
-    );
-    superblock = &superblock[4..];
-
-    // blkszbits
-    assert_eq!(superblock[0], BLOCK_BITS);
-    superblock = &superblock[1..];
-
-    // extslots
-    assert_eq!(superblock[0], 0);
-    superblock = &superblock[1..];
-
-    // TODO: Idk where this 36 comes from
-    assert_eq!(u16_le(superblock, "sb_root_nid")?, 36);
-    superblock = &superblock[2..];
-
-    let inos = u64_le(superblock, "sb_inos")?;
-    println!("num inodes: {inos}");
-    superblock = &superblock[8..];
-
-    let build_time = u64_le(superblock, "sb_build_time")?;
-    println!("build time: {build_time}");
-    superblock = &superblock[8..];
+/// Checksum of the superblock, over everything from the start of the superblock to the end of
+/// its block, with the checksum field itself taken as 0
+pub fn checksum(sb_to_block_end: &[u8]) -> u32 {
+    let mut data = sb_to_block_end.to_vec();
+    data[4..8].fill(0);
 
-    Ok(())
+    crc32c(!0, &data)
 }
@@ -0,0 +1,149 @@
+use anyhow::{Context, Result, bail};
+use openssl::pkcs7::{Pkcs7, Pkcs7Flags};
+use openssl::stack::Stack;
+use openssl::x509::X509;
+use openssl::x509::store::X509StoreBuilder;
+
+use crate::verity::{self, HashAlgorithm};
+
+/// Checks a detached PKCS#7 signature of an fs-verity digest, made like the kernel expects for
+/// `FS_IOC_ENABLE_VERITY` over the formatted digest. The signer has to be `certificate`, it isn't
+/// checked against any CA. Both can be DER or PEM
+#[fn_error_context::context("Checking signature")]
+pub fn verify_signature(
+    algorithm: HashAlgorithm,
+    digest: &[u8],
+    signature: &[u8],
+    certificate: &[u8],
+) -> Result<()> {
+    let signature = Pkcs7::from_der(signature)
+        .or_else(|_| Pkcs7::from_pem(signature))
+        .context("Parsing PKCS#7 signature")?;
+    let certificate = X509::from_der(certificate)
+        .or_else(|_| X509::from_pem(certificate))
+        .context("Parsing certificate")?;
+
+    let mut certs = Stack::new()?;
+    certs.push(certificate)?;
+    let store = X509StoreBuilder::new()?.build();
+
+    // Only the given certificate can be the signer, and it's trusted as it is
+    let flags = Pkcs7Flags::BINARY | Pkcs7Flags::NOINTERN | Pkcs7Flags::NOVERIFY;
+    let content = verity::formatted_digest(algorithm, digest);
+
+    if signature
+        .verify(&certs, &store, Some(&content), None, flags)
+        .is_err()
+    {
+        bail!("Signature doesn't match the digest and certificate");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::X509NameBuilder;
+
+    use super::*;
+    use crate::builder::{Content, FileData, ImageBuilder, Node, ROOT};
+    use crate::image::Image;
+
+    /// A self-signed certificate and its key
+    fn certificate(name: &str) -> (X509, PKey<Private>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap();
+        let subject = subject.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        builder.set_serial_number(&serial).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_issuer_name(&subject).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        (builder.build(), key)
+    }
+
+    /// Signs the digest the same way `fsverity sign` does
+    fn sign(certificate: &X509, key: &PKey<Private>, digest: &[u8]) -> Pkcs7 {
+        let content = verity::formatted_digest(HashAlgorithm::Sha256, digest);
+        let flags =
+            Pkcs7Flags::DETACHED | Pkcs7Flags::BINARY | Pkcs7Flags::NOATTR | Pkcs7Flags::NOCERTS;
+
+        Pkcs7::sign(certificate, key, &Stack::new().unwrap(), &content, flags).unwrap()
+    }
+
+    fn image() -> Image {
+        let mut builder = ImageBuilder::new();
+        let node = Node::new(
+            0o644,
+            Content::Regular(FileData::Bytes(b"signed contents".to_vec())),
+        );
+        builder.insert(ROOT, b"file", node).unwrap();
+
+        Image::from_bytes(builder.write(vec![]).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn image_digest() {
+        let image = image();
+        let path = std::env::temp_dir().join(format!("erofs-signature-{}", std::process::id()));
+        std::fs::write(&path, &image.data).unwrap();
+
+        // The same as fs-verity would measure the image file
+        let digest = image.verity_digest();
+        assert_eq!(
+            digest,
+            verity::file_digest(&path, HashAlgorithm::Sha256, &[]).unwrap()
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn signatures() {
+        let digest = image().verity_digest();
+        let (cert, key) = certificate("signer");
+        let signature = sign(&cert, &key, &digest);
+
+        let der = (signature.to_der().unwrap(), cert.to_der().unwrap());
+        let pem = (signature.to_pem().unwrap(), cert.to_pem().unwrap());
+
+        for (signature, cert) in [&der, &pem] {
+            verify_signature(HashAlgorithm::Sha256, &digest, signature, cert).unwrap();
+        }
+
+        let (signature, cert) = &der;
+
+        let mut other_digest = digest.clone();
+        other_digest[0] ^= 1;
+        assert!(verify_signature(HashAlgorithm::Sha256, &other_digest, signature, cert).is_err());
+
+        // The algorithm is part of what's signed
+        assert!(verify_signature(HashAlgorithm::Sha512, &digest, signature, cert).is_err());
+
+        let (other_cert, _) = certificate("signer");
+        let other_cert = other_cert.to_der().unwrap();
+        assert!(verify_signature(HashAlgorithm::Sha256, &digest, signature, &other_cert).is_err());
+
+        assert!(verify_signature(HashAlgorithm::Sha256, &digest, b"garbage", cert).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use std::fmt::Display;
+
+use crate::metadata::*;
+use crate::utils::*;
+
+/// `ls -l` style permission string, e.g. `drwxr-xr-x`
+pub fn mode_string(mode: u16, file_type: FileType) -> String {
+    let mut s = String::with_capacity(10);
+
+    s.push(match file_type {
+        FileType::Regular => '-',
+        FileType::Directory => 'd',
+        FileType::Symlink => 'l',
+        FileType::CharDevice => 'c',
+        FileType::BlockDevice => 'b',
+        FileType::Fifo => 'p',
+        FileType::Socket => 's',
+    });
+
+    // (read, write, execute, special bit, char when special and executable)
+    let classes = [
+        (0o400, 0o200, 0o100, 0o4000, 's'),
+        (0o040, 0o020, 0o010, 0o2000, 's'),
+        (0o004, 0o002, 0o001, 0o1000, 't'),
+    ];
+
+    for (r, w, x, special, special_char) in classes {
+        s.push(if mode & r != 0 { 'r' } else { '-' });
+        s.push(if mode & w != 0 { 'w' } else { '-' });
+
+        s.push(match (mode & x != 0, mode & special != 0) {
+            (true, true) => special_char,
+            (false, true) => special_char.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+
+    s
+}
+
+fn file_type_string(metadata: &Metadata) -> &'static str {
+    match metadata.file_type {
+        FileType::Regular if metadata.size == 0 => "regular empty file",
+        FileType::Regular => "regular file",
+        FileType::Directory => "directory",
+        FileType::Symlink => "symbolic link",
+        FileType::CharDevice => "character special file",
+        FileType::BlockDevice => "block special file",
+        FileType::Fifo => "fifo",
+        FileType::Socket => "socket",
+    }
+}
+
+/// Looks the id up in a host `/etc/passwd` style file, like `stat` would for a mounted image
+fn lookup_name(db: &str, id: u32) -> String {
+    let contents = std::fs::read_to_string(db).unwrap_or_default();
+
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let entry_id = fields.nth(1)?.parse::<u32>().ok()?;
+            (entry_id == id).then(|| name.to_string())
+        })
+        .next()
+        .unwrap_or_else(|| String::from("UNKNOWN"))
+}
+
+// Same as the default quoting style of coreutils, only quote when the shell would need it
+fn quote_name(name: &str) -> String {
+    let plain = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "/._-+,:@%^=".contains(c));
+
+    if plain {
+        name.to_string()
+    } else {
+        format!("'{}'", name.replace('\'', "'\\''"))
+    }
+}
+
+/// Displays as the output of coreutils `stat` for a file in the image. Timestamps are printed in
+/// local time following `TZ`, and the device is always 0,0 as the image itself doesn't live on one
+pub struct Stat<'a> {
+    pub name: &'a str,
+    pub metadata: &'a Metadata,
+    pub link_target: Option<&'a [u8]>,
+}
+
+impl<'a> Display for Stat<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let metadata = self.metadata;
+        let dev = metadata.dev();
+
+        match self.link_target {
+            Some(target) => writeln!(
+                f,
+                "  File: {} -> {}",
+                quote_name(self.name),
+                quote_name(&String::from_utf8_lossy(target))
+            )?,
+            None => writeln!(f, "  File: {}", quote_name(self.name))?,
+        }
+
+        writeln!(
+            f,
+            "  Size: {:<10}\tBlocks: {:<10} IO Block: {:<6} {}",
+            metadata.size(),
+            metadata.blocks(),
+            metadata.blksize(),
+            file_type_string(metadata)
+        )?;
+
+        if metadata.file_type.is_device() {
+            writeln!(
+                f,
+                "Device: {},{}\tInode: {:<11} Links: {:<5} Device type: {},{}",
+                dev >> 8,
+                dev & 0xff,
+                metadata.ino(),
+                metadata.nlink(),
+                metadata.rdev_major(),
+                metadata.rdev_minor()
+            )?;
+        } else {
+            writeln!(
+                f,
+                "Device: {},{}\tInode: {:<11} Links: {}",
+                dev >> 8,
+                dev & 0xff,
+                metadata.ino(),
+                metadata.nlink()
+            )?;
+        }
+
+        writeln!(
+            f,
+            "Access: ({:04o}/{:>10})  Uid: ({:>5}/{:>8})   Gid: ({:>5}/{:>8})",
+            metadata.permissions(),
+            mode_string(metadata.mode, metadata.file_type),
+            metadata.uid(),
+            lookup_name("/etc/passwd", metadata.uid()),
+            metadata.gid(),
+            lookup_name("/etc/group", metadata.gid()),
+        )?;
+
+        writeln!(
+            f,
+            "Access: {}",
+            format_timestamp(metadata.atime(), metadata.atime_nsec())
+        )?;
+        writeln!(
+            f,
+            "Modify: {}",
+            format_timestamp(metadata.mtime(), metadata.mtime_nsec())
+        )?;
+        writeln!(
+            f,
+            "Change: {}",
+            format_timestamp(metadata.ctime(), metadata.ctime_nsec())
+        )?;
+        writeln!(f, " Birth: -")
+    }
+}
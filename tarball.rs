@@ -0,0 +1,661 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+use anyhow::{Context, Result, bail};
+use tar::{Archive, Builder, Entry, EntryType, Header};
+
+use crate::builder::{Content, FileData, ImageBuilder, Node, ROOT};
+use crate::image::Image;
+use crate::metadata::FileType;
+use crate::objects::{INLINE_MAX, ObjectStore};
+use crate::trace;
+use crate::walk::WalkEntry;
+
+/// Size of the name and linkname fields in a ustar header, anything longer goes in a pax record
+const USTAR_NAME_LEN: usize = 100;
+
+// OCI layers delete files from the layers below with whiteouts, `.wh.NAME` for one file and
+// `.wh..wh..opq` for everything in the directory that came from below
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+const OPAQUE_WHITEOUT: &[u8] = b".wh..wh..opq";
+const OVERLAY_OPAQUE: &str = "trusted.overlay.opaque";
+
+impl Image {
+    /// Writes the whole image as a POSIX pax tar archive, in directory order.
+    ///
+    /// Paths are relative to the root, which is `./`. Inodes seen before are written as
+    /// hardlinks to the first path, xattrs as `SCHILY.xattr.*` records. Sockets can't be
+    /// represented in tar and are left out
+    #[fn_error_context::context("Writing tar archive")]
+    pub fn to_tar<W: Write>(&self, writer: W) -> Result<W> {
+        let mut builder = Builder::new(writer);
+
+        for entry in self.walk() {
+            self.append_tar_entry(&mut builder, &entry?)?;
+        }
+
+        Ok(builder.into_inner()?)
+    }
+
+    fn append_tar_entry<W: Write>(
+        &self,
+        builder: &mut Builder<W>,
+        entry: &WalkEntry,
+    ) -> Result<()> {
+        let metadata = &entry.metadata;
+
+        let mut pax: Vec<(String, Vec<u8>)> = vec![];
+        let mut header = Header::new_ustar();
+        let mut data = vec![];
+
+        let mut name = format!(".{}", entry.path);
+
+        let (entry_type, link) = match &entry.hardlink_of {
+            Some(first) => (EntryType::Link, Some(format!(".{first}").into_bytes())),
+
+            None => match metadata.file_type {
+                FileType::Directory => {
+                    if !name.ends_with('/') {
+                        name.push('/');
+                    }
+                    (EntryType::Directory, None)
+                }
+                FileType::Regular => {
+                    data = self.read(entry.nid)?;
+                    (EntryType::Regular, None)
+                }
+                FileType::Symlink => (EntryType::Symlink, Some(self.read_link(entry.nid)?)),
+                FileType::CharDevice => (EntryType::Char, None),
+                FileType::BlockDevice => (EntryType::Block, None),
+                FileType::Fifo => (EntryType::Fifo, None),
+                FileType::Socket => {
+                    trace::warning!(path = %entry.path, "sockets can't be stored in tar");
+                    return Ok(());
+                }
+            },
+        };
+
+        let old = header.as_old_mut();
+
+        set_field(&mut old.name, name.as_bytes(), "path", &mut pax);
+
+        if let Some(link) = &link {
+            set_field(&mut old.linkname, link, "linkpath", &mut pax);
+        }
+
+        header.set_entry_type(entry_type);
+        header.set_mode(metadata.permissions().into());
+        header.set_uid(metadata.uid.into());
+        header.set_gid(metadata.gid.into());
+        header.set_mtime(metadata.mtime);
+        header.set_size(data.len() as u64);
+
+        if metadata.file_type.is_device() && entry.hardlink_of.is_none() {
+            header.set_device_major(metadata.rdev_major())?;
+            header.set_device_minor(metadata.rdev_minor())?;
+        }
+
+        if metadata.mtime_nsec != 0 {
+            pax.push((
+                String::from("mtime"),
+                format!("{}.{:09}", metadata.mtime, metadata.mtime_nsec).into_bytes(),
+            ));
+        }
+
+        // A hardlink shares the xattrs of the first entry
+        if entry.hardlink_of.is_none() {
+            for xattr in self.xattrs(entry.nid)? {
+                let name = xattr.full_name();
+
+                if !self.is_object_xattr(&name) {
+                    pax.push((format!("SCHILY.xattr.{name}"), xattr.value));
+                }
+            }
+        }
+
+        builder.append_pax_extensions(pax.iter().map(|(k, v)| (k.as_str(), v.as_slice())))?;
+
+        header.set_cksum();
+        builder.append(&header, data.as_slice())?;
+
+        Ok(())
+    }
+}
+
+/// Fills a ustar name field, adding a pax record with the full value if it doesn't fit
+fn set_field(field: &mut [u8], value: &[u8], key: &str, pax: &mut Vec<(String, Vec<u8>)>) {
+    let len = value.len().min(USTAR_NAME_LEN);
+    field[..len].copy_from_slice(&value[..len]);
+
+    if value.len() > USTAR_NAME_LEN {
+        pax.push((key.to_string(), value.to_vec()));
+    }
+}
+
+impl ImageBuilder {
+    /// Reads a tar archive, such as an OCI layer, straight into a tree without unpacking it.
+    ///
+    /// Regular files are kept in memory, or with `objects` copied to the object store and
+    /// referenced by digest, except for the smallest ones. Missing parent directories are
+    /// created with mode 0755, a later entry for the same path replaces the earlier one and
+    /// directories keep their contents. Whiteouts become what overlayfs expects in a lower
+    /// layer: `.wh.NAME` a 0/0 character device `NAME`, `.wh..wh..opq` the `trusted.overlay.opaque`
+    /// xattr on its directory. Directory entries are sorted by name, so the archive order only
+    /// matters where a path appears twice
+    #[fn_error_context::context("Reading tar archive")]
+    pub fn from_tar<R: Read>(reader: R, objects: Option<&ObjectStore>) -> Result<Self> {
+        let mut builder = ImageBuilder::new();
+        let mut archive = Archive::new(reader);
+
+        // The tar crate splits pax records at newlines, which binary xattr values can have, so
+        // the extension headers are handled here
+        let mut pax = None;
+        let mut long_name = None;
+        let mut long_link = None;
+
+        for entry in archive.entries()?.raw(true) {
+            let mut entry = entry?;
+            let entry_type = entry.header().entry_type();
+
+            if entry_type.is_pax_local_extensions() {
+                let mut data = vec![];
+                entry.read_to_end(&mut data)?;
+                pax = Some(parse_pax(&data)?);
+                continue;
+            }
+
+            // Only applies to the entries after it, and nothing we keep is in there
+            if entry_type.is_pax_global_extensions() {
+                continue;
+            }
+
+            if entry_type.is_gnu_longname() || entry_type.is_gnu_longlink() {
+                let mut data = vec![];
+                entry.read_to_end(&mut data)?;
+                while data.last() == Some(&0) {
+                    data.pop();
+                }
+
+                if entry_type.is_gnu_longname() {
+                    long_name = Some(data);
+                } else {
+                    long_link = Some(data);
+                }
+                continue;
+            }
+
+            let mut extended = Extended {
+                path: long_name.take(),
+                link: long_link.take(),
+                records: vec![],
+            };
+
+            for (key, value) in pax.take().unwrap_or_default() {
+                match key.as_str() {
+                    "path" => extended.path = Some(value),
+                    "linkpath" => extended.link = Some(value),
+                    _ => extended.records.push((key, value)),
+                }
+            }
+
+            let path = extended
+                .path
+                .take()
+                .unwrap_or_else(|| entry.header().path_bytes().into_owned());
+
+            add_tar_entry(&mut builder, &mut entry, &path, extended, objects)
+                .with_context(|| format!("Adding {:?}", String::from_utf8_lossy(&path)))?;
+        }
+
+        Ok(builder)
+    }
+
+    /// Finds the directory at `components`, creating whatever is missing
+    fn make_dirs(&mut self, components: &[&[u8]]) -> Result<usize> {
+        let mut dir = ROOT;
+
+        for name in components {
+            let Content::Directory(entries) = &self.node(dir).content else {
+                unreachable!("only directories are followed");
+            };
+
+            dir = match entries.get(*name) {
+                Some(id) if self.node(*id).file_type() == FileType::Directory => *id,
+                Some(_) => bail!("{:?} is not a directory", String::from_utf8_lossy(name)),
+                None => self.insert(
+                    dir,
+                    name,
+                    Node::new(0o755, Content::Directory(BTreeMap::new())),
+                )?,
+            };
+        }
+
+        Ok(dir)
+    }
+}
+
+/// What the extension headers in front of an entry say about it
+struct Extended {
+    path: Option<Vec<u8>>,
+    link: Option<Vec<u8>>,
+    /// The other pax records
+    records: Vec<(String, Vec<u8>)>,
+}
+
+fn add_tar_entry<R: Read>(
+    builder: &mut ImageBuilder,
+    entry: &mut Entry<R>,
+    path: &[u8],
+    extended: Extended,
+    objects: Option<&ObjectStore>,
+) -> Result<()> {
+    let header = entry.header();
+    let entry_type = header.entry_type();
+
+    if entry_type.is_gnu_sparse() {
+        bail!("Sparse files are not supported");
+    }
+
+    let link = extended
+        .link
+        .or_else(|| header.link_name_bytes().map(|link| link.into_owned()));
+
+    let components = split_path(path)?;
+    let (parent, name) = match components.split_last() {
+        Some((name, parents)) => (builder.make_dirs(parents)?, Some(*name)),
+        None => (ROOT, None),
+    };
+
+    if name == Some(OPAQUE_WHITEOUT) {
+        builder
+            .node_mut(parent)
+            .xattrs
+            .insert(OVERLAY_OPAQUE.to_string(), b"y".to_vec());
+        return Ok(());
+    }
+
+    if entry_type.is_hard_link() {
+        let (Some(name), Some(target)) = (name, link) else {
+            bail!("Invalid hardlink");
+        };
+        let target = builder
+            .lookup(&split_path(&target)?.join(&b'/'))
+            .with_context(|| {
+                format!(
+                    "Hardlink target {:?} has to come first",
+                    String::from_utf8_lossy(&target)
+                )
+            })?;
+
+        return builder.link(parent, name, target);
+    }
+
+    let mut node = Node::new((header.mode()? & 0o7777) as u16, Content::Fifo);
+    node.uid = header.uid()?.try_into().context("uid out of range")?;
+    node.gid = header.gid()?.try_into().context("gid out of range")?;
+    node.mtime = header.mtime()?;
+
+    // Only devices fill in the fields
+    let rdev = if entry_type.is_character_special() || entry_type.is_block_special() {
+        let major = header
+            .device_major()?
+            .context("Device without a major number")?;
+        let minor = header
+            .device_minor()?
+            .context("Device without a minor number")?;
+        rustix::fs::makedev(major, minor)
+    } else {
+        0
+    };
+
+    for (key, value) in extended.records {
+        match key.as_str() {
+            "mtime" => (node.mtime, node.mtime_nsec) = parse_pax_time(&value)?,
+            "uid" => node.uid = parse_pax_number(&value)?,
+            "gid" => node.gid = parse_pax_number(&value)?,
+            // Without the pre-processing the data is as long as the header says
+            "size" if parse_pax_number::<u64>(&value)? != entry.size() => {
+                bail!("Sizes in pax records are not supported");
+            }
+            _ => {
+                if let Some(xattr) = key.strip_prefix("SCHILY.xattr.") {
+                    node.xattrs.insert(xattr.to_string(), value);
+                }
+            }
+        }
+    }
+
+    node.content = if name.is_some_and(|name| name.starts_with(WHITEOUT_PREFIX)) {
+        node.permissions = 0;
+        node.xattrs.clear();
+        Content::CharDevice(0)
+    } else if entry_type.is_dir() {
+        // Keep whatever is in there already
+        let existing = match (name, &builder.node(parent).content) {
+            (Some(name), Content::Directory(entries)) => entries.get(name).copied(),
+            _ => Some(ROOT),
+        };
+
+        match existing.map(|id| &mut builder.node_mut(id).content) {
+            Some(Content::Directory(entries)) => Content::Directory(std::mem::take(entries)),
+            _ => Content::Directory(BTreeMap::new()),
+        }
+    } else if entry_type.is_file() || entry_type.is_contiguous() {
+        let size = entry.size();
+
+        match objects {
+            Some(objects) if size > INLINE_MAX => {
+                let (digest, size) = objects.insert(&mut *entry)?;
+
+                Content::Regular(FileData::External {
+                    size,
+                    redirect: None,
+                    digest: Some(digest),
+                })
+            }
+            _ => {
+                let mut data = Vec::with_capacity(size as usize);
+                entry.read_to_end(&mut data)?;
+                Content::Regular(FileData::Bytes(data))
+            }
+        }
+    } else if entry_type.is_symlink() {
+        Content::Symlink(link.context("Symlink without a target")?)
+    } else if entry_type.is_character_special() {
+        Content::CharDevice(rdev)
+    } else if entry_type.is_block_special() {
+        Content::BlockDevice(rdev)
+    } else if entry_type.is_fifo() {
+        Content::Fifo
+    } else {
+        bail!("Unsupported entry type {entry_type:?}");
+    };
+
+    let name = match name {
+        Some(name) => name.strip_prefix(WHITEOUT_PREFIX).unwrap_or(name),
+        None => {
+            if node.file_type() != FileType::Directory {
+                bail!("The root has to be a directory");
+            }
+
+            *builder.node_mut(ROOT) = node;
+            return Ok(());
+        }
+    };
+
+    builder.insert(parent, name, node)?;
+
+    Ok(())
+}
+
+/// The components of a path in an archive, which is relative to the root whether it starts
+/// with `/`, `./` or neither
+fn split_path(path: &[u8]) -> Result<Vec<&[u8]>> {
+    let components: Vec<&[u8]> = path
+        .split(|c| *c == b'/')
+        .filter(|c| !c.is_empty() && *c != b".")
+        .collect();
+
+    if components.contains(&&b".."[..]) {
+        bail!("Path {:?} goes up", String::from_utf8_lossy(path));
+    }
+
+    Ok(components)
+}
+
+/// The records of a pax extension header, `LEN KEY=VALUE\n` where LEN counts the whole record
+fn parse_pax(mut data: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut records = vec![];
+
+    while !data.is_empty() {
+        let record = data
+            .iter()
+            .position(|c| *c == b' ')
+            .and_then(|space| {
+                let len: usize = std::str::from_utf8(&data[..space]).ok()?.parse().ok()?;
+                let record = data.get(space + 1..len)?.strip_suffix(b"\n")?;
+                let eq = record.iter().position(|c| *c == b'=')?;
+                let key = String::from_utf8(record[..eq].to_vec()).ok()?;
+
+                data = &data[len..];
+                Some((key, record[eq + 1..].to_vec()))
+            })
+            .context("Malformed pax record")?;
+
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+fn parse_pax_number<T: std::str::FromStr>(value: &[u8]) -> Result<T> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .with_context(|| format!("Invalid pax number {:?}", String::from_utf8_lossy(value)))
+}
+
+/// A pax timestamp, seconds with an optional fraction
+fn parse_pax_time(value: &[u8]) -> Result<(u64, u32)> {
+    let value = std::str::from_utf8(value)
+        .ok()
+        .with_context(|| format!("Invalid pax time {:?}", String::from_utf8_lossy(value)))?;
+    let (secs, fraction) = value.split_once('.').unwrap_or((value, ""));
+
+    let secs = secs
+        .parse()
+        .with_context(|| format!("Invalid pax time {value:?}"))?;
+
+    if !fraction.bytes().all(|c| c.is_ascii_digit()) {
+        bail!("Invalid pax time {value:?}");
+    }
+
+    // Anything past nanoseconds is dropped
+    let nsec = format!("{:0<9.9}", fraction)
+        .parse()
+        .with_context(|| format!("Invalid pax time {value:?}"))?;
+
+    Ok((secs, nsec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(builder: ImageBuilder) -> Image {
+        Image::from_bytes(builder.write(vec![]).unwrap()).unwrap()
+    }
+
+    fn dump(image: &Image) -> String {
+        String::from_utf8(image.to_dump(vec![]).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let long = "x".repeat(120);
+        let source = format!(
+            "/ 0 40755 4 0 0 0 1700000000.0 - - - user.root=1
+/dev 0 40700 2 0 0 0 1700000000.5 - - -
+/dev/blk 0 60660 1 0 6 2049 1700000000.0 - - -
+/dev/chr 0 20620 1 0 5 1025 1700000000.0 - - -
+/dev/fifo 0 10600 1 0 0 0 1700000000.0 - - -
+/file 11 104755 2 1000 100 0 1700000000.123456789 - contents\\x00\\n\\xff - security.capability=\\x00\\x00\\x00\\x02\\x000\\x00\\x00\\x00\\x00\\x00\\x00\\x00\\x00\\x00\\x00\\x00\\x00\\x00\\x00 user.multi=line\\nvalue
+/hardlink 0 @120000 - - - - 0.0 /file - -
+/link 242 120777 1 0 0 0 1700000000.0 /{long}/{long} - -
+/{long} 0 40755 2 0 0 0 1700000000.0 - - -
+/{long}/{long} 3 100600 1 0 0 0 1700000000.0 - abc -
+"
+        );
+
+        let original = image(ImageBuilder::from_dump(source.as_bytes()).unwrap());
+        let tar = original.to_tar(vec![]).unwrap();
+        let copy = image(ImageBuilder::from_tar(tar.as_slice(), None).unwrap());
+
+        assert_eq!(dump(&copy), dump(&original));
+        assert_eq!(dump(&original), source);
+    }
+
+    /// A ustar archive of `(type, path, data)`, paths and pax headers are written as they are given
+    fn archive(entries: &[(EntryType, &str, &[u8])]) -> Vec<u8> {
+        let mut builder = Builder::new(vec![]);
+
+        for (entry_type, path, data) in entries {
+            let mut header = Header::new_ustar();
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_entry_type(*entry_type);
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(1700000000);
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            builder.append(&header, *data).unwrap();
+        }
+
+        builder.into_inner().unwrap()
+    }
+
+    fn from_tar(entries: &[(EntryType, &str, &[u8])]) -> Result<Image> {
+        Ok(image(ImageBuilder::from_tar(
+            archive(entries).as_slice(),
+            None,
+        )?))
+    }
+
+    #[test]
+    fn malformed_pax() {
+        let records: [&[u8]; 9] = [
+            b"a=b\n",
+            b"5 a=b\n",
+            b"7 a=b\n",
+            b"0 a=b\n",
+            b"-6 a=b\n",
+            b"99999999999999999999999 a=b\n",
+            b"6 ab\n\n",
+            b"6 a=b\r",
+            b"6 a=b\n1",
+        ];
+
+        for record in records {
+            let result = from_tar(&[
+                (EntryType::XHeader, "pax", record),
+                (EntryType::Regular, "file", b"data"),
+            ]);
+
+            let error = format!("{:#}", result.err().unwrap());
+            assert!(error.contains("Malformed pax record"), "{error}");
+        }
+    }
+
+    #[test]
+    fn pax_records() {
+        let image = from_tar(&[
+            (
+                EntryType::XHeader,
+                "pax",
+                b"23 mtime=1700000001.25\n12 uid=1000\n27 SCHILY.xattr.user.a=\n=\n\n",
+            ),
+            (EntryType::Regular, "file", b"data"),
+        ])
+        .unwrap();
+
+        let nid = image.resolve("/file", false).unwrap();
+        let metadata = image.metadata(nid).unwrap();
+        assert_eq!(
+            (metadata.mtime, metadata.mtime_nsec),
+            (1700000001, 250000000)
+        );
+        assert_eq!(metadata.uid, 1000);
+
+        let xattrs = image.xattrs(nid).unwrap();
+        assert_eq!(xattrs[0].full_name(), "user.a");
+        assert_eq!(xattrs[0].value, b"\n=\n");
+
+        // The data would have to be read from somewhere else
+        assert!(
+            from_tar(&[
+                (EntryType::XHeader, "pax", b"12 size=100\n"),
+                (EntryType::Regular, "file", b"data"),
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn whiteouts() {
+        let image = from_tar(&[
+            (EntryType::Directory, "d/", b""),
+            (EntryType::Regular, "d/a", b"a"),
+            (EntryType::Regular, "d/b", b"b"),
+            (EntryType::Regular, "d/.wh.a", b""),
+            (EntryType::Regular, ".wh.gone", b""),
+        ])
+        .unwrap();
+
+        for path in ["/d/a", "/gone"] {
+            let metadata = image.metadata(image.resolve(path, false).unwrap()).unwrap();
+            assert_eq!(metadata.file_type, FileType::CharDevice, "{path}");
+            assert_eq!((metadata.rdev, metadata.permissions()), (0, 0), "{path}");
+        }
+
+        let b = image.resolve("/d/b", false).unwrap();
+        assert_eq!(image.read(b).unwrap(), b"b");
+
+        let names: Vec<_> = image
+            .read_dir(image.resolve("/d", false).unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|dirent| dirent.name)
+            .collect();
+        assert_eq!(names, [".", "..", "a", "b"]);
+    }
+
+    #[test]
+    fn opaque_directory() {
+        let image = from_tar(&[
+            (EntryType::Directory, "d/", b""),
+            (EntryType::Regular, "d/kept", b""),
+            (EntryType::Regular, "d/.wh..wh..opq", b""),
+        ])
+        .unwrap();
+
+        let d = image.resolve("/d", false).unwrap();
+        let xattrs = image.xattrs(d).unwrap();
+        assert_eq!(xattrs.len(), 1);
+        assert_eq!(xattrs[0].full_name(), OVERLAY_OPAQUE);
+        assert_eq!(xattrs[0].value, b"y");
+
+        // Only what's below in other layers is hidden
+        assert!(image.resolve("/d/kept", false).is_ok());
+        assert!(image.resolve("/d/.wh..wh..opq", false).is_err());
+    }
+
+    #[test]
+    fn paths() {
+        let image = from_tar(&[
+            (EntryType::Regular, "./a/b/file", b"x"),
+            (EntryType::Directory, "a/", b""),
+        ])
+        .unwrap();
+
+        let a = image
+            .metadata(image.resolve("/a/b", false).unwrap())
+            .unwrap();
+        assert_eq!(a.file_type, FileType::Directory);
+        assert_eq!(a.permissions(), 0o755);
+
+        // A later entry for the directory keeps what's in it
+        let a = image.metadata(image.resolve("/a", false).unwrap()).unwrap();
+        assert_eq!(a.permissions(), 0o644);
+        assert!(image.resolve("/a/b/file", false).is_ok());
+
+        assert!(from_tar(&[(EntryType::Regular, "a/../../etc", b"")]).is_err());
+        assert!(
+            from_tar(&[
+                (EntryType::Regular, "a", b""),
+                (EntryType::Regular, "a/b", b""),
+            ])
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::BTreeMap;
+
+use erofs::builder::{Content, FileData, ImageBuilder, Node, ROOT};
+use erofs::fsck;
+use erofs::image::Image;
+use erofs::inode::InodeDataLayout;
+
+const BLOCK_SIZE: usize = 4096;
+
+/// Writes the image and reads it back, it has to pass fsck
+fn build(builder: &ImageBuilder) -> Image {
+    let image = Image::from_bytes(builder.write(vec![]).unwrap()).unwrap();
+
+    let report = fsck::check(&image);
+    assert!(report.problems.is_empty(), "{:?}", report.problems);
+    assert!(report.skipped.is_empty(), "{:?}", report.skipped);
+
+    let problems = fsck::scan(&image);
+    assert!(problems.is_empty(), "{problems:?}");
+
+    image
+}
+
+/// Xattrs by full name, without the ones composefs adds for external files
+fn xattrs(image: &Image, nid: u64) -> BTreeMap<String, Vec<u8>> {
+    image
+        .xattrs(nid)
+        .unwrap()
+        .into_iter()
+        .map(|xattr| (xattr.full_name(), xattr.value))
+        .filter(|(name, _)| !name.starts_with("trusted.overlay."))
+        .collect()
+}
+
+/// Walks the builder's tree and checks that the image has the same at every path
+fn compare(builder: &ImageBuilder, image: &Image, id: usize, path: &str) {
+    let node = builder.node(id);
+    let nid = image
+        .resolve(if path.is_empty() { "/" } else { path }, false)
+        .unwrap();
+    let metadata = image.metadata(nid).unwrap();
+
+    assert_eq!(metadata.file_type, node.file_type(), "{path}");
+    assert_eq!(metadata.permissions(), node.permissions, "{path}");
+    assert_eq!((metadata.uid, metadata.gid), (node.uid, node.gid), "{path}");
+    assert_eq!(
+        (metadata.mtime, metadata.mtime_nsec),
+        (node.mtime, node.mtime_nsec),
+        "{path}"
+    );
+    assert_eq!(xattrs(image, nid), node.xattrs, "{path}");
+
+    match &node.content {
+        Content::Directory(entries) => {
+            let names: Vec<_> = image
+                .read_dir(nid)
+                .unwrap()
+                .into_iter()
+                .map(|dirent| dirent.name)
+                .filter(|name| name != "." && name != "..")
+                .collect();
+            let expected: Vec<_> = entries
+                .keys()
+                .map(|name| String::from_utf8(name.clone()).unwrap())
+                .collect();
+            assert_eq!(names, expected, "{path}");
+
+            for (name, child) in entries {
+                let name = std::str::from_utf8(name).unwrap();
+                compare(builder, image, *child, &format!("{path}/{name}"));
+            }
+        }
+
+        Content::Regular(data) => {
+            assert_eq!(metadata.size, data.size(), "{path}");
+
+            if let FileData::Bytes(bytes) = data {
+                assert!(image.read(nid).unwrap() == *bytes, "{path}");
+            }
+        }
+
+        Content::Symlink(target) => assert_eq!(image.read_link(nid).unwrap(), *target, "{path}"),
+        Content::CharDevice(rdev) | Content::BlockDevice(rdev) => {
+            assert_eq!(metadata.rdev, *rdev, "{path}")
+        }
+        Content::Fifo | Content::Socket => {}
+    }
+}
+
+fn layout(image: &Image, path: &str) -> InodeDataLayout {
+    let nid = image.resolve(path, false).unwrap();
+    image.inode(nid).unwrap().data_layout().unwrap()
+}
+
+fn file(contents: Vec<u8>) -> Node {
+    Node::new(0o644, Content::Regular(FileData::Bytes(contents)))
+}
+
+#[test]
+fn inline_tails() {
+    let mut builder = ImageBuilder::new();
+    let mut names = vec![];
+
+    // Files of every kind of size, with xattrs of different sizes in front of the tails so the
+    // inodes end up all over the blocks
+    for (i, size) in [
+        1, 100, 2000, 4000, 4095, 4096, 4097, 6000, 8191, 8192, 12000,
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        for xattrs in 0..6 {
+            let name = format!("file-{size}-{xattrs}");
+            let mut node = file((0..size).map(|n| (n * 7 + i) as u8).collect());
+            node.mtime = 1_700_000_000 + i as u64;
+            node.uid = 1000;
+
+            for n in 0..xattrs {
+                node.xattrs
+                    .insert(format!("user.{name}-{n}"), vec![n as u8; 150 * n]);
+            }
+
+            builder.insert(ROOT, name.as_bytes(), node).unwrap();
+            names.push(name);
+        }
+    }
+
+    let link = Node::new(0o777, Content::Symlink(vec![b'x'; 3000]));
+    builder.insert(ROOT, b"link", link).unwrap();
+
+    let image = build(&builder);
+    compare(&builder, &image, ROOT, "");
+
+    let mut inline = 0;
+
+    for name in names
+        .iter()
+        .map(|name| format!("/{name}"))
+        .chain(["/link".into()])
+    {
+        let nid = image.resolve(&name, false).unwrap();
+        let inode = image.inode(nid).unwrap();
+
+        if inode.data_layout().unwrap() != InodeDataLayout::FlatInline {
+            continue;
+        }
+
+        // The inode, its xattrs and the tail all within one block
+        let offset = image.inode_offset(nid).unwrap();
+        let meta_size = inode
+            .meta_size(image.inode_data(nid).unwrap(), &image.superblock)
+            .unwrap();
+        assert!(
+            offset % BLOCK_SIZE + meta_size <= BLOCK_SIZE,
+            "{name} at {offset} takes up {meta_size} bytes"
+        );
+
+        inline += 1;
+    }
+
+    assert!(inline > names.len() / 2, "only {inline} inline tails");
+    assert_eq!(layout(&image, "/file-8192-0"), InodeDataLayout::FlatPlain);
+    assert_eq!(layout(&image, "/file-6000-2"), InodeDataLayout::FlatInline);
+}
+
+#[test]
+fn dirents_across_blocks() {
+    let mut builder = ImageBuilder::new();
+    let dir = builder
+        .insert(
+            ROOT,
+            b"dir",
+            Node::new(0o755, Content::Directory(BTreeMap::new())),
+        )
+        .unwrap();
+
+    // Names of different lengths inserted out of order, ".." and "." sort in between
+    for i in (0..600).rev() {
+        let name = format!("{}{i}", "-+.".repeat(i % 40));
+        let node = match i % 4 {
+            0 => Node::new(0o755, Content::Directory(BTreeMap::new())),
+            1 => Node::new(0o777, Content::Symlink(name.clone().into_bytes())),
+            2 => Node::new(0o600, Content::Fifo),
+            _ => file(name.clone().into_bytes()),
+        };
+
+        builder.insert(dir, name.as_bytes(), node).unwrap();
+    }
+
+    let file = format!("dir/{}3", "-+.".repeat(3));
+    let file = builder.lookup(file.as_bytes()).unwrap();
+    builder.link(dir, b"hardlink", file).unwrap();
+
+    let image = build(&builder);
+    compare(&builder, &image, ROOT, "");
+
+    let nid = image.resolve("/dir", false).unwrap();
+    assert!(image.metadata(nid).unwrap().size > 10 * BLOCK_SIZE as u64);
+
+    // All of them sorted, not just the ones within a block
+    let names: Vec<_> = image
+        .read_dir(nid)
+        .unwrap()
+        .into_iter()
+        .map(|dirent| dirent.name)
+        .collect();
+    assert!(names.is_sorted(), "{names:?}");
+    assert_eq!(names.len(), 600 + 3);
+
+    assert_eq!(
+        image.resolve("/dir/hardlink", false).unwrap(),
+        image.resolve("/dir/-+.-+.-+.3", false).unwrap()
+    );
+}
+
+#[test]
+fn shared_xattrs() {
+    let mut builder = ImageBuilder::new();
+
+    for i in 0..4 {
+        let mut node = file(format!("file {i}").into_bytes());
+        node.xattrs
+            .insert("user.shared".into(), b"the same everywhere".to_vec());
+        node.xattrs.insert(
+            "security.selinux".into(),
+            b"system_u:object_r:etc_t:s0".to_vec(),
+        );
+        node.xattrs.insert("user.own".into(), vec![i; 10]);
+
+        builder
+            .insert(ROOT, format!("file{i}").as_bytes(), node)
+            .unwrap();
+    }
+
+    let mut node = Node::new(0o755, Content::Directory(BTreeMap::new()));
+    node.xattrs
+        .insert("user.shared".into(), b"the same everywhere".to_vec());
+    builder.insert(ROOT, b"dir", node).unwrap();
+
+    let image = build(&builder);
+    compare(&builder, &image, ROOT, "");
+    assert_ne!(image.superblock.xattr_blkaddr, 0);
+
+    for (path, shared) in [("/file0", 2), ("/file3", 2), ("/dir", 1), ("/", 0)] {
+        let nid = image.resolve(path, false).unwrap();
+        let inode = image.inode(nid).unwrap();
+        let ids = inode
+            .get_xattrs(image.inode_data(nid).unwrap())
+            .map(|xattrs| xattrs.shared_ids())
+            .unwrap_or_default();
+
+        assert_eq!(ids.len(), shared, "{path}");
+    }
+}
+
+#[test]
+fn chunk_based() {
+    let mut builder = ImageBuilder::new().composefs();
+
+    for (name, size) in [
+        ("one", 1),
+        ("block", BLOCK_SIZE as u64),
+        ("blocks", 10 * BLOCK_SIZE as u64 + 1),
+        ("large", (16 << 20) + 1),
+    ] {
+        let mut node = Node::new(
+            0o644,
+            Content::Regular(FileData::External {
+                size,
+                redirect: Some(format!("/ab/{name}")),
+                digest: None,
+            }),
+        );
+        node.xattrs
+            .insert("user.name".into(), name.as_bytes().to_vec());
+
+        builder.insert(ROOT, name.as_bytes(), node).unwrap();
+    }
+
+    let external = Node::new(
+        0o644,
+        Content::Regular(FileData::External {
+            size: 0,
+            redirect: Some("/ab/empty".into()),
+            digest: None,
+        }),
+    );
+    builder.insert(ROOT, b"empty", external).unwrap();
+    builder
+        .insert(ROOT, b"inline", file(b"inline".to_vec()))
+        .unwrap();
+
+    let image = build(&builder);
+    compare(&builder, &image, ROOT, "");
+
+    for name in ["one", "block", "blocks", "large"] {
+        let path = format!("/{name}");
+        assert_eq!(layout(&image, &path), InodeDataLayout::ChunkBased, "{path}");
+
+        let nid = image.resolve(&path, false).unwrap();
+        let redirect = image
+            .xattrs(nid)
+            .unwrap()
+            .into_iter()
+            .find(|xattr| xattr.full_name() == "trusted.overlay.redirect")
+            .unwrap();
+        assert_eq!(redirect.value, format!("/ab/{name}").into_bytes());
+    }
+
+    // Holes all the way, without an object store
+    let nid = image.resolve("/blocks", false).unwrap();
+    assert_eq!(image.read(nid).unwrap(), vec![0; 10 * BLOCK_SIZE + 1]);
+
+    assert_eq!(layout(&image, "/empty"), InodeDataLayout::FlatPlain);
+    assert_eq!(layout(&image, "/inline"), InodeDataLayout::FlatInline);
+}
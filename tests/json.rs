@@ -0,0 +1,143 @@
+#![cfg(feature = "json")]
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+
+use erofs::builder::{Content, FileData, ImageBuilder, Node, ROOT};
+use serde_json::Value;
+
+const METADATA: [&str; 12] = [
+    "block_size",
+    "file_type",
+    "gid",
+    "ino",
+    "mode",
+    "mtime",
+    "mtime_nsec",
+    "nid",
+    "nlink",
+    "rdev",
+    "size",
+    "uid",
+];
+
+/// Runs `erofs --json` with `args`, checking the schema version of what it prints
+fn json(image: &Path, args: &[&str]) -> Value {
+    let output = Command::new(env!("CARGO_BIN_EXE_erofs"))
+        .arg("--json")
+        .arg(args[0])
+        .arg(image)
+        .args(&args[1..])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+
+    let value: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(value["schema_version"], 1, "{value}");
+
+    value
+}
+
+/// The field names of an object, sorted
+fn keys(value: &Value) -> Vec<&str> {
+    let mut keys: Vec<_> = value
+        .as_object()
+        .unwrap()
+        .keys()
+        .map(String::as_str)
+        .collect();
+    keys.sort();
+    keys
+}
+
+#[test]
+fn fields() {
+    let mut builder = ImageBuilder::new();
+    let mut file = Node::new(0o644, Content::Regular(FileData::Bytes(vec![1; 5000])));
+    file.xattrs = BTreeMap::from([
+        ("user.text".into(), b"value".to_vec()),
+        ("user.binary".into(), vec![0xff, 0]),
+    ]);
+    builder.insert(ROOT, b"file", file).unwrap();
+    let link = Node::new(0o777, Content::Symlink(b"file".to_vec()));
+    builder.insert(ROOT, b"link", link).unwrap();
+
+    let image = std::env::temp_dir().join(format!("erofs-json-{}.erofs", std::process::id()));
+    std::fs::write(&image, builder.write(vec![]).unwrap()).unwrap();
+
+    let info = json(&image, &["info"]);
+    assert_eq!(keys(&info), ["composefs", "schema_version", "superblock"]);
+    assert_eq!(info["composefs"], Value::Null);
+    assert_eq!(
+        keys(&info["superblock"]),
+        [
+            "block_size",
+            "blocks",
+            "build_time",
+            "build_time_nsec",
+            "compat_features",
+            "feature_compat",
+            "feature_incompat",
+            "inos",
+            "meta_blkaddr",
+            "root_nid",
+            "uuid",
+            "volume_name",
+            "xattr_blkaddr",
+        ]
+    );
+    assert_eq!(info["superblock"]["block_size"], 4096);
+
+    let stat = json(&image, &["stat", "/file", "/link"]);
+    assert_eq!(keys(&stat), ["files", "schema_version"]);
+    let files = stat["files"].as_array().unwrap();
+    for file in files {
+        assert_eq!(keys(file), ["link_target", "metadata", "path"]);
+        assert_eq!(keys(&file["metadata"]), METADATA);
+    }
+    assert_eq!(files[0]["path"], "/file");
+    assert_eq!(files[0]["link_target"], Value::Null);
+    assert_eq!(files[0]["metadata"]["file_type"], "regular");
+    assert_eq!(files[0]["metadata"]["size"], 5000);
+    assert_eq!(files[1]["link_target"], "file");
+    assert_eq!(files[1]["metadata"]["file_type"], "symlink");
+
+    let ls = json(&image, &["ls", "/"]);
+    assert_eq!(keys(&ls), ["entries", "path", "schema_version"]);
+    assert_eq!(ls["path"], "/");
+    let entries = ls["entries"].as_array().unwrap();
+    let names: Vec<_> = entries.iter().map(|entry| &entry["name"]).collect();
+    assert_eq!(names, ["file", "link"]);
+    for entry in entries {
+        assert_eq!(keys(entry), ["metadata", "name"]);
+        assert_eq!(keys(&entry["metadata"]), METADATA);
+    }
+
+    let getfattr = json(&image, &["getfattr", "/file"]);
+    assert_eq!(keys(&getfattr), ["files", "schema_version"]);
+    let file = &getfattr["files"][0];
+    assert_eq!(keys(file), ["path", "xattrs"]);
+    assert_eq!(
+        file["xattrs"],
+        serde_json::json!([
+            { "name": "user.binary", "value": null, "value_base64": "/wA=" },
+            { "name": "user.text", "value": "value", "value_base64": "dmFsdWU=" },
+        ])
+    );
+
+    let map = json(&image, &["map", "/file"]);
+    assert_eq!(keys(&map), ["extents", "path", "schema_version"]);
+    let kinds: Vec<_> = map["extents"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|extent| {
+            assert_eq!(keys(extent), ["kind", "len", "logical", "physical"]);
+            &extent["kind"]
+        })
+        .collect();
+    assert_eq!(kinds, ["plain", "inline"]);
+
+    std::fs::remove_file(&image).unwrap();
+}
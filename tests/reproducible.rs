@@ -0,0 +1,143 @@
+use std::path::{Path, PathBuf};
+
+use erofs::builder::{Content, FileData, ImageBuilder, Node, ROOT};
+use rustix::fs::{AtFlags, CWD, Timespec, Timestamps, utimensat};
+use sha2::{Digest, Sha256};
+
+const MTIME: i64 = 1_700_000_000;
+
+fn set_mtime(path: &Path) {
+    let time = Timespec {
+        tv_sec: MTIME,
+        tv_nsec: 0,
+    };
+    let times = Timestamps {
+        last_access: time,
+        last_modification: time,
+    };
+    utimensat(CWD, path, &times, AtFlags::SYMLINK_NOFOLLOW).unwrap();
+}
+
+/// The same tree every time, but with the entries created in `names` order
+fn make_tree(root: &Path, names: &[&str]) {
+    let _ = std::fs::remove_dir_all(root);
+    std::fs::create_dir_all(root.join("dir")).unwrap();
+
+    for name in names {
+        let path = root.join(name);
+
+        match *name {
+            "link" => std::os::unix::fs::symlink("dir/big", &path).unwrap(),
+            "hardlink" => std::fs::hard_link(root.join("small"), &path).unwrap(),
+            "dir/big" => std::fs::write(&path, vec![7; 10000]).unwrap(),
+            _ => std::fs::write(&path, name).unwrap(),
+        }
+    }
+
+    for name in names.iter().chain(&["dir", ""]) {
+        set_mtime(&root.join(name));
+    }
+}
+
+fn build(root: &Path, xattrs: &[(&str, &str)]) -> Vec<u8> {
+    let mut builder = ImageBuilder::from_dir(root).unwrap();
+
+    for (path, name) in xattrs {
+        let id = builder.lookup(path.as_bytes()).unwrap();
+        builder
+            .node_mut(id)
+            .xattrs
+            .insert(name.to_string(), b"value".to_vec());
+    }
+
+    builder
+        .build_time(MTIME as u64, 0)
+        .derive_uuid()
+        .write(vec![])
+        .unwrap()
+}
+
+/// The uuid field of the superblock
+fn uuid(image: &[u8]) -> &[u8] {
+    &image[1072..1088]
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("erofs-{name}-{}", std::process::id()))
+}
+
+#[test]
+fn build_twice_from_dir() {
+    let first = temp_dir("reproducible-first");
+    let second = temp_dir("reproducible-second");
+
+    make_tree(
+        &first,
+        &["small", "hardlink", "dir/big", "link", "dir/other"],
+    );
+    make_tree(
+        &second,
+        &["dir/other", "link", "dir/big", "small", "hardlink"],
+    );
+
+    // Shared xattrs, added in a different order
+    let one = build(
+        &first,
+        &[
+            ("/small", "user.a"),
+            ("/dir", "user.b"),
+            ("/link", "user.a"),
+        ],
+    );
+    let xattrs = [
+        ("/link", "user.a"),
+        ("/small", "user.a"),
+        ("/dir", "user.b"),
+    ];
+    let two = build(&second, &xattrs);
+    assert_eq!(Sha256::digest(&one), Sha256::digest(&two));
+
+    // Only the contents of a file that isn't inline differ, which the uuid has to pick up
+    std::fs::write(second.join("dir/big"), vec![8; 10000]).unwrap();
+    set_mtime(&second.join("dir/big"));
+    let three = build(&second, &xattrs);
+    assert_ne!(uuid(&one), uuid(&three));
+
+    std::fs::remove_dir_all(first).unwrap();
+    std::fs::remove_dir_all(second).unwrap();
+}
+
+#[test]
+fn build_twice_composefs() {
+    let build = |reverse: bool| {
+        let mut builder = ImageBuilder::new().composefs();
+        let mut names = vec!["a", "b", "c"];
+        if reverse {
+            names.reverse();
+        }
+
+        for name in names {
+            let mut node = Node::new(
+                0o644,
+                Content::Regular(FileData::External {
+                    size: 100000,
+                    redirect: None,
+                    digest: Some(vec![name.as_bytes()[0]; 32]),
+                }),
+            );
+            node.mtime = MTIME as u64;
+            node.xattrs
+                .insert("security.selinux".into(), b"label".to_vec());
+            builder.insert(ROOT, name.as_bytes(), node).unwrap();
+        }
+
+        let image = builder
+            .build_time(MTIME as u64, 0)
+            .derive_uuid()
+            .write(vec![])
+            .unwrap();
+        Sha256::digest(image)
+    };
+
+    assert_eq!(build(false), build(true));
+}
@@ -0,0 +1,71 @@
+use std::process::Command;
+
+use erofs::builder::ImageBuilder;
+use rustix::fs::{AtFlags, CWD, Timespec, Timestamps, utimensat};
+
+/// The times coreutils `stat` prints, without Change which the host sets to now
+fn times(output: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(output)
+        .lines()
+        .filter(|line| line.starts_with("Access: 2") || line.starts_with("Modify: "))
+        .map(String::from)
+        .collect()
+}
+
+fn stat(command: &mut Command, tz: &str) -> Vec<String> {
+    let output = command.env("TZ", tz).output().unwrap();
+    assert!(output.status.success(), "{output:?}");
+
+    times(&output.stdout)
+}
+
+#[test]
+fn times_match_coreutils() {
+    let dir = std::env::temp_dir().join(format!("erofs-stat-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("src")).unwrap();
+
+    let file = dir.join("src/file");
+    std::fs::write(&file, "contents").unwrap();
+
+    // In July, so the zones with DST are on summer time
+    let time = Timespec {
+        tv_sec: 1_720_000_000,
+        tv_nsec: 123_456_789,
+    };
+    let times = Timestamps {
+        last_access: time,
+        last_modification: time,
+    };
+    utimensat(CWD, &file, &times, AtFlags::empty()).unwrap();
+
+    let image = dir.join("image.erofs");
+    ImageBuilder::from_dir(&dir.join("src"))
+        .unwrap()
+        .write(std::fs::File::create(&image).unwrap())
+        .unwrap();
+
+    // Reading the file for the image moved its atime
+    utimensat(CWD, &file, &times, AtFlags::empty()).unwrap();
+
+    for tz in [
+        "UTC",
+        "IST-5:30",
+        "EST5EDT,M3.2.0,M11.1.0",
+        "NZST-12NZDT,M9.5.0,M4.1.0/3",
+    ] {
+        let host = stat(Command::new("stat").arg(&file), tz);
+        let parsed = stat(
+            Command::new(env!("CARGO_BIN_EXE_erofs"))
+                .arg("stat")
+                .arg(&image)
+                .arg("/file"),
+            tz,
+        );
+
+        assert_eq!(host.len(), 2, "{host:?}");
+        assert_eq!(host, parsed, "TZ={tz}");
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
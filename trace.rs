@@ -0,0 +1,88 @@
+// Diagnostics from the parsing code go through `tracing` with the "tracing" feature, and compile
+// to nothing without it so nothing ends up on stdout of whoever embeds the crate
+//
+// Only the structured form of the tracing macros is supported, i.e.
+//  debug!(nid, offset = 10, name = %name, "message")
+//  warning!(nid, "message")
+//  span!("name", nid, offset = 10)
+
+#[cfg(feature = "tracing")]
+macro_rules! debug {
+    ($($args:tt)*) => { ::tracing::debug!($($args)*) };
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! warning {
+    ($($args:tt)*) => { ::tracing::warn!($($args)*) };
+}
+
+/// Enters a span for the rest of the scope, the result has to be kept alive
+#[cfg(feature = "tracing")]
+macro_rules! span {
+    ($name:literal $(, $($fields:tt)*)?) => {
+        ::tracing::debug_span!($name $(, $($fields)*)?).entered()
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! debug {
+    ($($args:tt)*) => {
+        if false {
+            $crate::trace::unused!($($args)*);
+        }
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! warning {
+    ($($args:tt)*) => {
+        if false {
+            $crate::trace::unused!($($args)*);
+        }
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! span {
+    ($name:literal $(, $($fields:tt)*)?) => {{
+        if false {
+            $($crate::trace::unused!($($fields)*);)?
+        }
+
+        $crate::trace::NoSpan
+    }};
+}
+
+/// Stands in for an entered span without the "tracing" feature
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct NoSpan;
+
+/// Marks everything logged as used, so disabling tracing doesn't bring unused variable warnings
+#[cfg(not(feature = "tracing"))]
+macro_rules! unused {
+    () => {};
+    ($msg:literal) => {};
+    ($field:ident = % $value:expr $(, $($rest:tt)*)?) => {
+        let _ = &$value;
+        $($crate::trace::unused!($($rest)*);)?
+    };
+    ($field:ident = ? $value:expr $(, $($rest:tt)*)?) => {
+        let _ = &$value;
+        $($crate::trace::unused!($($rest)*);)?
+    };
+    ($field:ident = $value:expr $(, $($rest:tt)*)?) => {
+        let _ = &$value;
+        $($crate::trace::unused!($($rest)*);)?
+    };
+    ($field:ident $(, $($rest:tt)*)?) => {
+        let _ = &$field;
+        $($crate::trace::unused!($($rest)*);)?
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) use unused;
+
+pub(crate) use debug;
+pub(crate) use span;
+pub(crate) use warning;
@@ -17,3 +17,180 @@ pub fn u16_le(buf: &[u8], msg: &str) -> Result<u16> {
     let thing: &[u8; 2] = buf[..2].try_into().unwrap();
     Ok(u16::from_le_bytes(*thing))
 }
+
+/// Formats seconds since the epoch as `YYYY-MM-DD HH:MM:SS.NNNNNNNNN +HHMM` in local time,
+/// following `TZ` like coreutils does
+pub fn format_timestamp(secs: i64, nsec: i64) -> String {
+    let offset = local_offset(secs);
+    let local = secs + offset;
+
+    let days = local.div_euclid(86400);
+    let time = local.rem_euclid(86400);
+
+    // Howard Hinnant's civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let sign = if offset < 0 { '-' } else { '+' };
+    let offset = offset.abs() / 60;
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}.{nsec:09} {sign}{:02}{:02}",
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        offset / 60,
+        offset % 60
+    )
+}
+
+/// Seconds the local time zone is ahead of UTC at `secs`, 0 when it can't be told
+fn local_offset(secs: i64) -> i64 {
+    let time = secs as libc::time_t;
+
+    // SAFETY: localtime_r only writes to `tm`, which is plain data
+    unsafe {
+        let mut tm = std::mem::zeroed::<libc::tm>();
+
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            0
+        } else {
+            tm.tm_gmtoff
+        }
+    }
+}
+
+/// Standard base64 with padding
+pub fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+pub fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn hex_decode(hex: &str) -> Result<Vec<u8>> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        anyhow::bail!("Invalid hex {hex:?}");
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| anyhow::anyhow!("Invalid hex {hex:?}"))
+        })
+        .collect()
+}
+
+/// CRC32C the way the kernel computes it, without the final inversion. Start with `!0`
+pub fn crc32c(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f63b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    crc
+}
+
+const XXH32_PRIME1: u32 = 0x9e3779b1;
+const XXH32_PRIME2: u32 = 0x85ebca77;
+const XXH32_PRIME3: u32 = 0xc2b2ae3d;
+const XXH32_PRIME4: u32 = 0x27d4eb2f;
+const XXH32_PRIME5: u32 = 0x165667b1;
+
+/// xxHash32, which EROFS uses for the xattr name bloom filter
+pub fn xxh32(data: &[u8], seed: u32) -> u32 {
+    let lane = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+    let round = |acc: u32, input: u32| {
+        acc.wrapping_add(input.wrapping_mul(XXH32_PRIME2))
+            .rotate_left(13)
+            .wrapping_mul(XXH32_PRIME1)
+    };
+
+    let mut stripes = data.chunks_exact(16);
+
+    let mut hash = if data.len() >= 16 {
+        let mut acc = [
+            seed.wrapping_add(XXH32_PRIME1).wrapping_add(XXH32_PRIME2),
+            seed.wrapping_add(XXH32_PRIME2),
+            seed,
+            seed.wrapping_sub(XXH32_PRIME1),
+        ];
+
+        for stripe in &mut stripes {
+            for (i, acc) in acc.iter_mut().enumerate() {
+                *acc = round(*acc, lane(&stripe[i * 4..i * 4 + 4]));
+            }
+        }
+
+        acc[0]
+            .rotate_left(1)
+            .wrapping_add(acc[1].rotate_left(7))
+            .wrapping_add(acc[2].rotate_left(12))
+            .wrapping_add(acc[3].rotate_left(18))
+    } else {
+        seed.wrapping_add(XXH32_PRIME5)
+    };
+
+    hash = hash.wrapping_add(data.len() as u32);
+
+    let mut words = stripes.remainder().chunks_exact(4);
+
+    for word in &mut words {
+        hash = hash
+            .wrapping_add(lane(word).wrapping_mul(XXH32_PRIME3))
+            .rotate_left(17)
+            .wrapping_mul(XXH32_PRIME4);
+    }
+
+    for &byte in words.remainder() {
+        hash = hash
+            .wrapping_add((byte as u32).wrapping_mul(XXH32_PRIME5))
+            .rotate_left(11)
+            .wrapping_mul(XXH32_PRIME1);
+    }
+
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(XXH32_PRIME2);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(XXH32_PRIME3);
+    hash ^= hash >> 16;
+
+    hash
+}
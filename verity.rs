@@ -0,0 +1,401 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Context, Result, bail};
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::composefs::{FS_VERITY_HASH_ALG_SHA256, FS_VERITY_HASH_ALG_SHA512};
+use crate::image::Image;
+
+// fs-verity hashes the data in 4KiB blocks, the hashes of those in the same sized blocks and so
+// on, until one block is left. The digest of a file is the hash of a descriptor with the hash
+// of that block and the file size
+const BLOCK_SIZE: usize = 4096;
+const LOG_BLOCK_SIZE: u8 = 12;
+
+// struct fsverity_descriptor {
+//     u8 version;
+//     u8 hash_algorithm;
+//     u8 log_blocksize;
+//     u8 salt_size;
+//     le32 reserved;
+//     le64 data_size;
+//     u8 root_hash[64];
+//     u8 salt[32];
+//     u8 reserved[144];
+// };
+const DESCRIPTOR_SIZE: usize = 256;
+const MAX_SALT_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    /// The `FS_VERITY_HASH_ALG_*` number
+    pub fn id(self) -> u8 {
+        match self {
+            HashAlgorithm::Sha256 => FS_VERITY_HASH_ALG_SHA256,
+            HashAlgorithm::Sha512 => FS_VERITY_HASH_ALG_SHA512,
+        }
+    }
+
+    /// The algorithm that gives digests as long as `digest`, like in an overlay metacopy
+    pub fn for_digest(digest: &[u8]) -> Result<Self> {
+        match digest.len() {
+            32 => Ok(HashAlgorithm::Sha256),
+            64 => Ok(HashAlgorithm::Sha512),
+            len => bail!("No fs-verity digest is {len} bytes long"),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+        }
+    }
+
+    pub fn digest_size(self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Sha512 => 64,
+        }
+    }
+
+    /// The block size of the hash itself, salts are padded to it
+    fn hash_block_size(self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 => 64,
+            HashAlgorithm::Sha512 => 128,
+        }
+    }
+
+    fn hash(self, parts: &[&[u8]]) -> Vec<u8> {
+        fn hash<D: Digest>(parts: &[&[u8]]) -> Vec<u8> {
+            let mut hasher = D::new();
+            for part in parts {
+                hasher.update(part);
+            }
+            hasher.finalize().to_vec()
+        }
+
+        match self {
+            HashAlgorithm::Sha256 => hash::<Sha256>(parts),
+            HashAlgorithm::Sha512 => hash::<Sha512>(parts),
+        }
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "sha512" => Ok(HashAlgorithm::Sha512),
+            _ => bail!("Unknown fs-verity hash algorithm {name:?}, expected sha256 or sha512"),
+        }
+    }
+}
+
+/// Computes the fs-verity digest of data written to it, with 4KiB blocks. SHA-256 without a
+/// salt by default, which is what composefs uses
+pub struct FsVerityHasher {
+    algorithm: HashAlgorithm,
+    salt: Vec<u8>,
+    /// The salt padded to the hash block size, it goes in front of every block that's hashed
+    padded_salt: Vec<u8>,
+    block: Vec<u8>,
+    size: u64,
+    /// Hashes of the data blocks
+    hashes: Vec<u8>,
+}
+
+impl Default for FsVerityHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FsVerityHasher {
+    pub fn new() -> Self {
+        FsVerityHasher {
+            algorithm: HashAlgorithm::Sha256,
+            salt: vec![],
+            padded_salt: vec![],
+            block: Vec::with_capacity(BLOCK_SIZE),
+            size: 0,
+            hashes: vec![],
+        }
+    }
+
+    /// At most 32 bytes of salt
+    pub fn with_params(algorithm: HashAlgorithm, salt: &[u8]) -> Result<Self> {
+        if salt.len() > MAX_SALT_SIZE {
+            bail!(
+                "fs-verity salt is {} bytes, at most {MAX_SALT_SIZE} are allowed",
+                salt.len()
+            );
+        }
+
+        let mut padded_salt = salt.to_vec();
+        if !salt.is_empty() {
+            padded_salt.resize(salt.len().next_multiple_of(algorithm.hash_block_size()), 0);
+        }
+
+        Ok(FsVerityHasher {
+            algorithm,
+            salt: salt.to_vec(),
+            padded_salt,
+            ..Self::new()
+        })
+    }
+
+    fn hash_block(&self, block: &[u8]) -> Vec<u8> {
+        self.algorithm
+            .hash(&[&self.padded_salt, block, &[0; BLOCK_SIZE][block.len()..]])
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.size += data.len() as u64;
+
+        while !data.is_empty() {
+            let len = data.len().min(BLOCK_SIZE - self.block.len());
+            self.block.extend_from_slice(&data[..len]);
+            data = &data[len..];
+
+            if self.block.len() == BLOCK_SIZE {
+                let hash = self.hash_block(&self.block);
+                self.hashes.extend_from_slice(&hash);
+                self.block.clear();
+            }
+        }
+    }
+
+    pub fn finalize(mut self) -> Vec<u8> {
+        if !self.block.is_empty() {
+            let hash = self.hash_block(&self.block);
+            self.hashes.extend_from_slice(&hash);
+        }
+
+        let digest_size = self.algorithm.digest_size();
+
+        let root_hash = match self.hashes.len() / digest_size {
+            0 => vec![0; digest_size],
+            // A single block is its own root
+            1 => self.hashes.clone(),
+            _ => {
+                let mut level = std::mem::take(&mut self.hashes);
+
+                while level.len() > BLOCK_SIZE {
+                    level = level
+                        .chunks(BLOCK_SIZE)
+                        .flat_map(|block| self.hash_block(block))
+                        .collect();
+                }
+
+                self.hash_block(&level)
+            }
+        };
+
+        let mut descriptor = [0; DESCRIPTOR_SIZE];
+        descriptor[0] = 1;
+        descriptor[1] = self.algorithm.id();
+        descriptor[2] = LOG_BLOCK_SIZE;
+        descriptor[3] = self.salt.len() as u8;
+        descriptor[8..16].copy_from_slice(&self.size.to_le_bytes());
+        descriptor[16..16 + digest_size].copy_from_slice(&root_hash);
+        descriptor[80..80 + self.salt.len()].copy_from_slice(&self.salt);
+
+        self.algorithm.hash(&[&descriptor])
+    }
+}
+
+impl Write for FsVerityHasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The fs-verity digest of `data`, SHA-256 without a salt
+pub fn digest(data: &[u8]) -> Vec<u8> {
+    let mut hasher = FsVerityHasher::new();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// What fs-verity signatures sign, `struct fsverity_formatted_digest`: the magic, the algorithm
+/// and digest size as le16, then the digest
+pub fn formatted_digest(algorithm: HashAlgorithm, digest: &[u8]) -> Vec<u8> {
+    let mut formatted = b"FSVerity".to_vec();
+    formatted.extend_from_slice(&(algorithm.id() as u16).to_le_bytes());
+    formatted.extend_from_slice(&(digest.len() as u16).to_le_bytes());
+    formatted.extend_from_slice(digest);
+    formatted
+}
+
+/// The fs-verity digest of everything `reader` has
+pub fn read_digest<R: Read>(
+    mut reader: R,
+    algorithm: HashAlgorithm,
+    salt: &[u8],
+) -> Result<Vec<u8>> {
+    let mut hasher = FsVerityHasher::with_params(algorithm, salt)?;
+    std::io::copy(&mut reader, &mut hasher)?;
+
+    Ok(hasher.finalize())
+}
+
+/// The fs-verity digest of the host file at `path`, computed here so the file system doesn't
+/// need fs-verity support
+#[fn_error_context::context("Measuring {}", path.display())]
+pub fn file_digest(path: &Path, algorithm: HashAlgorithm, salt: &[u8]) -> Result<Vec<u8>> {
+    let file = File::open(path).context("Opening file")?;
+
+    read_digest(file, algorithm, salt)
+}
+
+impl Image {
+    /// The fs-verity digest of the image file itself, SHA-256 without a salt like composefs
+    /// mounts expect
+    pub fn verity_digest(&self) -> Vec<u8> {
+        digest(&self.data)
+    }
+
+    /// The fs-verity digest of a regular file in the image, from the data the image holds
+    pub fn file_verity_digest(
+        &self,
+        nid: u64,
+        algorithm: HashAlgorithm,
+        salt: &[u8],
+    ) -> Result<Vec<u8>> {
+        if !self.inode(nid)?.is_file() {
+            bail!("Inode {nid} is not a regular file");
+        }
+
+        let mut hasher = FsVerityHasher::with_params(algorithm, salt)?;
+        self.copy_to(nid, &mut hasher)?;
+
+        Ok(hasher.finalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::hex_encode;
+
+    /// The test files are bytes counting up and wrapping at 251, so blocks differ
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn hex_digest(data: &[u8], algorithm: HashAlgorithm, salt: &[u8]) -> String {
+        hex_encode(&read_digest(data, algorithm, salt).unwrap())
+    }
+
+    // The expected digests come from a separate implementation of the format described in the
+    // kernel's fsverity.rst, the empty file's is the well known one `fsverity digest` gives
+
+    #[test]
+    fn empty() {
+        assert_eq!(
+            hex_encode(&digest(b"")),
+            "3d248ca542a24fc62d1c43b916eae5016878e2533c88238480b26128a1f1af95"
+        );
+        assert_eq!(
+            hex_digest(b"", HashAlgorithm::Sha512, &[]),
+            "ccf9e5aea1c2a64efa2f2354a6024b90dffde6bbc017825045dce374474e13d1\
+             0adb9dadcc6ca8e17a3c075fbd31336e8f266ae6fa93a6c3bed66f9e784e5abf"
+        );
+    }
+
+    #[test]
+    fn one_block() {
+        assert_eq!(
+            hex_encode(&digest(&pattern(4096))),
+            "13e9b8848ae484a36acb3f3cac0ceb2f7601e96633d15c92f9bd3dd44e492157"
+        );
+    }
+
+    #[test]
+    fn partial_block() {
+        assert_eq!(
+            hex_encode(&digest(&pattern(5000))),
+            "219c2df1dc52c12b7f666570d48b3f57c2e1ef58aa4734111f36773c8e731829"
+        );
+    }
+
+    #[test]
+    fn multi_level() {
+        // 147 blocks, more hashes than fit in one block
+        assert_eq!(
+            hex_encode(&digest(&pattern(600000))),
+            "06d5be5c1f84f55006b5826aec720b80e5d95f30e170be7e1d6aae61755f1ae4"
+        );
+    }
+
+    #[test]
+    fn salted() {
+        assert_eq!(
+            hex_digest(&pattern(10000), HashAlgorithm::Sha256, b"salt"),
+            "1618d39a7f38cfd53d938a011069ad8c3605544f6974b34f62538fd38796b337"
+        );
+    }
+
+    #[test]
+    fn sha512() {
+        assert_eq!(
+            hex_digest(&pattern(10000), HashAlgorithm::Sha512, &[]),
+            "86645940beab38208dc84afd90e334cc03fe92748c0324bd0cd8a624b313ca34\
+             e04c42385107a5dc87d057ae9080c3556db5599de507409bf388849cbec41fc7"
+        );
+        assert_eq!(
+            hex_digest(&pattern(600000), HashAlgorithm::Sha512, b"salt"),
+            "ba6b4098099791db63b10a10e0f073346f5e4fdde105f4ce10968988e5dd2190\
+             a0c5dd239503e736fe8a1b9e71ebe8e889f8ca5cbc8fc6a7862736e3bc1514e8"
+        );
+    }
+
+    #[test]
+    fn split_updates() {
+        let data = pattern(600000);
+        let mut hasher = FsVerityHasher::new();
+
+        for chunk in data.chunks(1000) {
+            hasher.update(chunk);
+        }
+
+        assert_eq!(hasher.finalize(), digest(&data));
+    }
+
+    #[test]
+    fn host_file() {
+        let path = std::env::temp_dir().join(format!("erofs-verity-{}", std::process::id()));
+        std::fs::write(&path, pattern(5000)).unwrap();
+
+        let digest = file_digest(&path, HashAlgorithm::Sha256, &[]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            hex_encode(&digest),
+            "219c2df1dc52c12b7f666570d48b3f57c2e1ef58aa4734111f36773c8e731829"
+        );
+    }
+
+    #[test]
+    fn salt_too_long() {
+        assert!(FsVerityHasher::with_params(HashAlgorithm::Sha256, &[0; 33]).is_err());
+    }
+}
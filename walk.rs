@@ -0,0 +1,486 @@
+use std::collections::{HashMap, VecDeque};
+
+use anyhow::{Result, anyhow};
+
+use crate::image::Image;
+use crate::metadata::{FileType, Metadata};
+use crate::trace;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkOrder {
+    /// Everything below a directory comes before its next sibling
+    DepthFirst,
+    /// All entries of a directory come before anything below them
+    BreadthFirst,
+}
+
+#[derive(Debug, Clone)]
+pub struct WalkEntry {
+    pub path: String,
+    pub nid: u64,
+    /// 0 for where the walk started
+    pub depth: usize,
+    pub metadata: Metadata,
+    /// For a nid that was already seen at another path, that first path
+    pub hardlink_of: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkControl {
+    Continue,
+    /// Don't descend into this directory
+    Prune,
+    /// End the walk
+    Stop,
+}
+
+pub trait Visitor {
+    /// Called for every entry, for a directory this is before any of its children
+    fn visit(&mut self, entry: &WalkEntry) -> Result<WalkControl>;
+
+    /// Called once the children of a directory were visited. For a depth first walk that's
+    /// everything below it, for a breadth first walk only its direct children. Directories that
+    /// are pruned or at the maximum depth aren't descended into, so this isn't called for them
+    fn post_dir(&mut self, _entry: &WalkEntry) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called for entries that couldn't be read, the walk goes on unless this returns an error
+    fn error(&mut self, err: anyhow::Error) -> Result<()> {
+        Err(err)
+    }
+}
+
+enum Pending {
+    Visit {
+        path: String,
+        nid: u64,
+        depth: usize,
+    },
+    PostDir(WalkEntry),
+}
+
+enum Event {
+    Entry(Result<WalkEntry>),
+    PostDir(WalkEntry),
+}
+
+/// Walks the tree below a directory, see [`Image::walk`]. Every nid is only descended into
+/// once, so directory cycles in a corrupt image end up as errors instead of looping forever
+pub struct Walker<'a> {
+    image: &'a Image,
+    order: WalkOrder,
+    max_depth: Option<usize>,
+    pending: VecDeque<Pending>,
+    /// The directory yielded last, its children are only queued on the next step so it can
+    /// still be pruned
+    to_expand: Option<WalkEntry>,
+    /// First path each nid was seen at
+    seen: HashMap<u64, String>,
+}
+
+impl Image {
+    /// Walks the whole image, depth first, starting at the root
+    pub fn walk(&self) -> Walker<'_> {
+        Walker::new(self, String::from("/"), self.root_nid())
+    }
+
+    /// Walks everything below `path`
+    pub fn walk_from(&self, path: &str) -> Result<Walker<'_>> {
+        Ok(Walker::new(
+            self,
+            path.to_string(),
+            self.resolve(path, true)?,
+        ))
+    }
+}
+
+fn join_path(parent: &str, name: &str) -> String {
+    if parent.ends_with('/') {
+        format!("{parent}{name}")
+    } else {
+        format!("{parent}/{name}")
+    }
+}
+
+impl<'a> Walker<'a> {
+    fn new(image: &'a Image, path: String, nid: u64) -> Self {
+        Walker {
+            image,
+            order: WalkOrder::DepthFirst,
+            max_depth: None,
+            pending: VecDeque::from([Pending::Visit {
+                path,
+                nid,
+                depth: 0,
+            }]),
+            to_expand: None,
+            seen: HashMap::new(),
+        }
+    }
+
+    pub fn order(mut self, order: WalkOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Don't descend into directories deeper than this, 0 only yields the starting point
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Skips the children of the directory that was yielded last
+    pub fn skip_current_dir(&mut self) {
+        self.to_expand = None;
+    }
+
+    /// Drives the walk with a visitor instead of iterating
+    pub fn visit(mut self, visitor: &mut dyn Visitor) -> Result<()> {
+        while let Some(event) = self.next_event() {
+            match event {
+                Event::Entry(Ok(entry)) => match visitor.visit(&entry)? {
+                    WalkControl::Continue => {}
+                    WalkControl::Prune => self.skip_current_dir(),
+                    WalkControl::Stop => break,
+                },
+                Event::Entry(Err(e)) => visitor.error(e)?,
+                Event::PostDir(entry) => visitor.post_dir(&entry)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn push(&mut self, pending: Vec<Pending>) {
+        match self.order {
+            // Reversed so they come off the back in directory order
+            WalkOrder::DepthFirst => self.pending.extend(pending.into_iter().rev()),
+            WalkOrder::BreadthFirst => self.pending.extend(pending),
+        }
+    }
+
+    fn pop(&mut self) -> Option<Pending> {
+        match self.order {
+            WalkOrder::DepthFirst => self.pending.pop_back(),
+            WalkOrder::BreadthFirst => self.pending.pop_front(),
+        }
+    }
+
+    /// Queues the children of the directory yielded last
+    fn expand(&mut self, dir: WalkEntry) -> Result<()> {
+        let dirents = self.image.read_dir(dir.nid)?;
+
+        let mut pending: Vec<_> = dirents
+            .into_iter()
+            .filter(|d| d.name != "." && d.name != "..")
+            .map(|d| Pending::Visit {
+                path: join_path(&dir.path, &d.name),
+                nid: d.dirent.nid,
+                depth: dir.depth + 1,
+            })
+            .collect();
+
+        // Depth first pushes this underneath the children, so either way it comes off after them
+        pending.push(Pending::PostDir(dir));
+
+        self.push(pending);
+
+        Ok(())
+    }
+
+    fn entry(&mut self, path: String, nid: u64, depth: usize) -> Result<WalkEntry> {
+        let metadata = self
+            .image
+            .metadata(nid)
+            .map_err(|e| e.context(path.clone()))?;
+
+        let hardlink_of = self.seen.get(&nid).cloned();
+
+        if let Some(first) = &hardlink_of {
+            if metadata.file_type == FileType::Directory {
+                trace::warning!(nid, path = %path, first = %first, "directory cycle");
+                return Err(anyhow!(
+                    "{path}: directory nid {nid} was already seen at {first}, the image has a cycle"
+                ));
+            }
+        } else {
+            self.seen.insert(nid, path.clone());
+        }
+
+        Ok(WalkEntry {
+            path,
+            nid,
+            depth,
+            metadata,
+            hardlink_of,
+        })
+    }
+
+    fn next_event(&mut self) -> Option<Event> {
+        if let Some(dir) = self.to_expand.take() {
+            let path = dir.path.clone();
+
+            if let Err(e) = self.expand(dir) {
+                return Some(Event::Entry(Err(e.context(path))));
+            }
+        }
+
+        match self.pop()? {
+            Pending::PostDir(entry) => Some(Event::PostDir(entry)),
+
+            Pending::Visit { path, nid, depth } => {
+                let entry = self.entry(path, nid, depth);
+
+                if let Ok(entry) = &entry
+                    && entry.metadata.file_type == FileType::Directory
+                    && self.max_depth.is_none_or(|max| depth < max)
+                {
+                    self.to_expand = Some(entry.clone());
+                }
+
+                Some(Event::Entry(entry))
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Walker<'a> {
+    type Item = Result<WalkEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_event()? {
+                Event::Entry(entry) => return Some(entry),
+                Event::PostDir(..) => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::builder::{Content, FileData, ImageBuilder, Node, ROOT};
+    use crate::inode::DirEnt;
+
+    fn dir() -> Node {
+        Node::new(0o755, Content::Directory(BTreeMap::new()))
+    }
+
+    fn file() -> Node {
+        Node::new(0o644, Content::Regular(FileData::Bytes(b"file".to_vec())))
+    }
+
+    /// /a/x, /a/y/z, /b/w and /c, with /b/w a hardlink of /a/x
+    fn image() -> Image {
+        let mut builder = ImageBuilder::new();
+
+        let a = builder.insert(ROOT, b"a", dir()).unwrap();
+        let x = builder.insert(a, b"x", file()).unwrap();
+        let y = builder.insert(a, b"y", dir()).unwrap();
+        builder.insert(y, b"z", dir()).unwrap();
+        let b = builder.insert(ROOT, b"b", dir()).unwrap();
+        builder.link(b, b"w", x).unwrap();
+        builder.insert(ROOT, b"c", file()).unwrap();
+
+        Image::from_bytes(builder.write(vec![]).unwrap()).unwrap()
+    }
+
+    fn paths(walker: Walker) -> Vec<String> {
+        walker.map(|entry| entry.unwrap().path).collect()
+    }
+
+    /// Records the visits and post_dir calls, pruning the directories in `prune`
+    #[derive(Default)]
+    struct Recorder {
+        events: Vec<String>,
+        prune: Vec<&'static str>,
+    }
+
+    impl Visitor for Recorder {
+        fn visit(&mut self, entry: &WalkEntry) -> Result<WalkControl> {
+            self.events.push(entry.path.clone());
+
+            Ok(match self.prune.contains(&entry.path.as_str()) {
+                true => WalkControl::Prune,
+                false => WalkControl::Continue,
+            })
+        }
+
+        fn post_dir(&mut self, entry: &WalkEntry) -> Result<()> {
+            self.events.push(format!("post {}", entry.path));
+            Ok(())
+        }
+    }
+
+    fn events(walker: Walker, prune: &[&'static str]) -> Vec<String> {
+        let mut recorder = Recorder {
+            prune: prune.to_vec(),
+            ..Default::default()
+        };
+        walker.visit(&mut recorder).unwrap();
+
+        recorder.events
+    }
+
+    #[test]
+    fn order() {
+        let image = image();
+
+        assert_eq!(
+            paths(image.walk()),
+            ["/", "/a", "/a/x", "/a/y", "/a/y/z", "/b", "/b/w", "/c"]
+        );
+        assert_eq!(
+            paths(image.walk().order(WalkOrder::BreadthFirst)),
+            ["/", "/a", "/b", "/c", "/a/x", "/a/y", "/b/w", "/a/y/z"]
+        );
+        assert_eq!(
+            paths(image.walk_from("/a").unwrap()),
+            ["/a", "/a/x", "/a/y", "/a/y/z"]
+        );
+
+        let depths: Vec<_> = image.walk().map(|entry| entry.unwrap().depth).collect();
+        assert_eq!(depths, [0, 1, 2, 2, 3, 1, 2, 1]);
+    }
+
+    #[test]
+    fn max_depth() {
+        let image = image();
+
+        assert_eq!(paths(image.walk().max_depth(0)), ["/"]);
+        assert_eq!(paths(image.walk().max_depth(1)), ["/", "/a", "/b", "/c"]);
+        assert_eq!(
+            events(image.walk().max_depth(1), &[]),
+            ["/", "/a", "/b", "/c", "post /"]
+        );
+        assert_eq!(
+            paths(image.walk().order(WalkOrder::BreadthFirst).max_depth(2)),
+            ["/", "/a", "/b", "/c", "/a/x", "/a/y", "/b/w"]
+        );
+    }
+
+    #[test]
+    fn prune() {
+        let image = image();
+
+        // Without post_dir, since nothing below it is walked
+        assert_eq!(
+            events(image.walk(), &["/a"]),
+            ["/", "/a", "/b", "/b/w", "post /b", "/c", "post /"]
+        );
+
+        // The same when iterating
+        let mut walker = image.walk().order(WalkOrder::BreadthFirst);
+        let mut paths = vec![];
+        while let Some(entry) = walker.next() {
+            let entry = entry.unwrap();
+            if entry.path == "/a" {
+                walker.skip_current_dir();
+            }
+            paths.push(entry.path);
+        }
+        assert_eq!(paths, ["/", "/a", "/b", "/c", "/b/w"]);
+    }
+
+    #[test]
+    fn post_dir() {
+        let image = image();
+
+        // After everything below the directory
+        assert_eq!(
+            events(image.walk(), &[]),
+            [
+                "/",
+                "/a",
+                "/a/x",
+                "/a/y",
+                "/a/y/z",
+                "post /a/y/z",
+                "post /a/y",
+                "post /a",
+                "/b",
+                "/b/w",
+                "post /b",
+                "/c",
+                "post /",
+            ]
+        );
+
+        // After the direct children
+        assert_eq!(
+            events(image.walk().order(WalkOrder::BreadthFirst), &[]),
+            [
+                "/",
+                "/a",
+                "/b",
+                "/c",
+                "post /",
+                "/a/x",
+                "/a/y",
+                "post /a",
+                "/b/w",
+                "post /b",
+                "/a/y/z",
+                "post /a/y",
+                "post /a/y/z",
+            ]
+        );
+    }
+
+    #[test]
+    fn hardlinks() {
+        let image = image();
+        let entries: Vec<_> = image.walk().map(Result::unwrap).collect();
+
+        let x = entries.iter().find(|entry| entry.path == "/a/x").unwrap();
+        let w = entries.iter().find(|entry| entry.path == "/b/w").unwrap();
+        assert_eq!(x.hardlink_of, None);
+        assert_eq!(w.hardlink_of.as_deref(), Some("/a/x"));
+        assert_eq!(w.nid, x.nid);
+
+        assert!(
+            entries
+                .iter()
+                .filter(|entry| entry.path != "/b/w")
+                .all(|entry| entry.hardlink_of.is_none())
+        );
+    }
+
+    #[test]
+    fn cycle() {
+        let image = image();
+        let mut data = image.data.clone();
+
+        // /a/y/z points back at /a
+        let y = image.resolve("/a/y", false).unwrap();
+        let index = image
+            .read_dir(y)
+            .unwrap()
+            .iter()
+            .position(|dirent| dirent.name == "z")
+            .unwrap();
+        let offset = image.extents(y).unwrap()[0].physical as usize + index * size_of::<DirEnt>();
+        let a = image.resolve("/a", false).unwrap();
+        data[offset..offset + 8].copy_from_slice(&a.to_le_bytes());
+
+        let image = Image::from_bytes(data).unwrap();
+        let entries: Vec<_> = image.walk().collect();
+        assert_eq!(entries.len(), 8);
+
+        let errors: Vec<_> = entries
+            .iter()
+            .filter_map(|entry| entry.as_ref().err())
+            .map(|e| format!("{e:#}"))
+            .collect();
+        assert_eq!(
+            errors,
+            [format!(
+                "/a/y/z: directory nid {a} was already seen at /a, the image has a cycle"
+            )]
+        );
+    }
+}